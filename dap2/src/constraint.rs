@@ -7,9 +7,12 @@
 //! dataset, returning a [`crate::dds::DdsResponse`] with [`crate::dds::ConstrainedVariable`]s that can
 //! be used to stream the variables of a data-source.
 //!
+//! The variable list may be followed by [selections](crate::selection) separated by `&`, which
+//! constrain the variables by their values.
+//!
 //! * Strides are not supported.
-//! * Constraints based on variable value is not supported.
 use crate::hyperslab;
+use crate::selection::{self, Selection};
 use percent_encoding::percent_decode_str;
 use std::ops::{Deref, DerefMut};
 
@@ -17,6 +20,7 @@ use std::ops::{Deref, DerefMut};
 #[derive(Debug, Clone)]
 pub struct Constraint {
     variables: Vec<ConstraintVariable>,
+    selections: Vec<Selection>,
 }
 
 impl Deref for Constraint {
@@ -47,20 +51,38 @@ impl Constraint {
         let query = percent_decode_str(query).decode_utf8()?;
         debug!("query: {}", query);
 
-        query
+        let mut clauses = selection::split_unquoted(&query, '&').into_iter();
+        let projection = clauses.next().unwrap_or_default();
+
+        let selections = clauses
+            .map(Selection::parse)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // Only selections, meaning all variables.
+        if projection.is_empty() && !selections.is_empty() {
+            return Ok(Constraint {
+                variables: Vec::new(),
+                selections,
+            });
+        }
+
+        projection
             .split(',')
             .map(|var| {
                 if let Some(s) = var.find('.') {
                     let v1 = &var[..s];
                     let v2 = &var[s + 1..];
 
-                    match v2.find('[') {
-                        Some(i) => {
-                            hyperslab::parse_hyperslab(&v2[i..]).map(|slab| (&v2[..i], Some(slab)))
-                        }
-                        None => Ok((v2, None)),
+                    // The hyperslab of an array of structures may be given on the structure, e.g.
+                    // `obs[0:5].temperature`.
+                    match (v1.find('['), v2.find('[')) {
+                        (Some(i), None) => hyperslab::parse_hyperslab(&v1[i..])
+                            .map(|slab| (&v1[..i], v2, Some(slab))),
+                        (_, Some(i)) => hyperslab::parse_hyperslab(&v2[i..])
+                            .map(|slab| (v1, &v2[..i], Some(slab))),
+                        (None, None) => Ok((v1, v2, None)),
                     }
-                    .map(|(v2, slab)| {
+                    .map(|(v1, v2, slab)| {
                         ConstraintVariable::Structure((v1.to_string(), v2.to_string(), slab))
                     })
                 } else {
@@ -73,13 +95,22 @@ impl Constraint {
                 }
             })
             .collect::<anyhow::Result<_>>()
-            .map(|variables| Constraint { variables })
+            .map(|variables| Constraint {
+                variables,
+                selections,
+            })
+    }
+
+    /// The selections of the constraint, see [crate::selection].
+    pub fn selections(&self) -> &[Selection] {
+        &self.selections
     }
 
     /// An empty constraints, meaning all variables.
     pub fn empty() -> Constraint {
         Constraint {
             variables: Vec::new(),
+            selections: Vec::new(),
         }
     }
 }
@@ -93,6 +124,25 @@ mod tests {
         assert_eq!(Constraint::empty().len(), 0);
     }
 
+    #[test]
+    fn selections() {
+        let c = Constraint::parse("buoys.temperature,SST&buoys.lat%3C60&TIME%3E=%221%22").unwrap();
+
+        assert_eq!(c.len(), 2);
+        assert_eq!(c.selections().len(), 2);
+        assert_eq!(c.selections()[0], Selection::parse("buoys.lat<60").unwrap());
+        assert_eq!(
+            c.selections()[1].values,
+            vec![crate::dds::Value::String("1".into())]
+        );
+
+        let c = Constraint::parse("&TIME>1").unwrap();
+        assert_eq!(c.len(), 0);
+        assert_eq!(c.selections().len(), 1);
+
+        assert!(Constraint::parse("SST&TIME").is_err());
+    }
+
     #[test]
     fn single_variable() {
         let c = Constraint::parse("SST").unwrap();
@@ -134,6 +184,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn single_struct_outer_slab() {
+        let c = Constraint::parse("obs[0:5].temperature").unwrap();
+
+        assert_eq!(c.len(), 1);
+
+        if let ConstraintVariable::Structure((v1, v2, slab)) = &c[0] {
+            assert_eq!(v1, "obs");
+            assert_eq!(v2, "temperature");
+            assert_eq!(*slab.as_ref().unwrap(), vec!(vec![0usize, 5]));
+        } else {
+            panic!("wrong enum");
+        }
+    }
+
     #[test]
     fn single_struct_slab_indexes() {
         let c = Constraint::parse("SST.TIME[5][4]").unwrap();
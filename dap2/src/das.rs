@@ -3,14 +3,21 @@
 //! DAS responses contain additional information about each variable like _fill value_ or history
 //! fields.
 //!
-//! DAS responses are static once constructed from a source.
+//! DAS responses are rendered once constructed from a source, and again if variables are added
+//! or changed.
 use bytes::Bytes;
 use std::fmt::{self, Write};
 
 /// DAS (Data Attribute Structure)
-pub struct Das(Bytes);
+pub struct Das {
+    /// Global attributes, `None` if the dataset has no global attributes.
+    global: Option<Vec<Attribute>>,
+    variables: Vec<(String, Vec<Attribute>)>,
+    /// The rendered DAS.
+    das: Bytes,
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Attribute {
     pub name: String,
     pub value: AttrValue,
@@ -132,31 +139,80 @@ where
     T: ToDas,
 {
     fn from(dataset: T) -> Self {
-        let mut das: String = "Attributes {\n".to_string();
+        let global = dataset
+            .has_global_attributes()
+            .then(|| dataset.global_attributes().collect());
+
+        let variables = dataset
+            .variables()
+            .map(|var| {
+                let attributes = dataset.variable_attributes(&var).collect();
+                (var, attributes)
+            })
+            .collect();
+
+        let mut das = Das {
+            global,
+            variables,
+            das: Bytes::new(),
+        };
+        das.render();
+
+        das
+    }
+}
 
-        if dataset.has_global_attributes() {
-            writeln!(das, "{:4}NC_GLOBAL {{", "").unwrap();
+fn write_attributes(das: &mut String, attributes: &[Attribute]) {
+    for a in attributes
+        .iter()
+        .filter(|a| !matches!(a.value, AttrValue::Unimplemented(_) | AttrValue::Ignored(_)))
+    {
+        writeln!(das, "{:8}{}", "", a).unwrap();
+    }
+}
 
-            for a in dataset
-                .global_attributes()
-                .filter(|a| !matches!(a.value, AttrValue::Unimplemented(_) | AttrValue::Ignored(_)))
-            {
-                writeln!(das, "{:8}{}", "", a).unwrap();
-            }
+impl fmt::Display for Das {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.as_str())
+    }
+}
 
-            writeln!(das, "{:4}}}", "").unwrap();
+impl Das {
+    pub fn bytes(&self) -> Bytes {
+        self.das.clone()
+    }
+
+    /// Add a variable with `attributes` at the end of the DAS.
+    pub fn add_variable(&mut self, variable: &str, attributes: Vec<Attribute>) {
+        self.variables.push((variable.to_string(), attributes));
+        self.render();
+    }
+
+    /// Replace the attributes of `variable`, the variable is added if it is not in the DAS.
+    pub fn set_variable(&mut self, variable: &str, attributes: Vec<Attribute>) {
+        match self.variables.iter_mut().find(|(v, _)| v == variable) {
+            Some((_, a)) => *a = attributes,
+            None => self.variables.push((variable.to_string(), attributes)),
         }
+        self.render();
+    }
 
-        for var in dataset.variables() {
-            writeln!(das, "{:4}{} {{", "", var).unwrap();
+    pub fn as_str(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.das)
+    }
 
-            for a in dataset
-                .variable_attributes(&var)
-                .filter(|a| !matches!(a.value, AttrValue::Unimplemented(_) | AttrValue::Ignored(_)))
-            {
-                writeln!(das, "{:8}{}", "", a).unwrap();
-            }
+    fn render(&mut self) {
+        let mut das: String = "Attributes {\n".to_string();
 
+        if let Some(global) = &self.global {
+            writeln!(das, "{:4}NC_GLOBAL {{", "").unwrap();
+            write_attributes(&mut das, global);
+            writeln!(das, "{:4}}}", "").unwrap();
+        }
+
+        for (var, attributes) in &self.variables {
+            writeln!(das, "{:4}{} {{", "", var).unwrap();
+            write_attributes(&mut das, attributes);
             writeln!(das, "    }}").unwrap();
         }
 
@@ -164,22 +220,65 @@ where
 
         trace!("Generated DAS: {}", das);
 
-        Das(Bytes::from(das))
+        self.das = Bytes::from(das);
     }
 }
 
-impl fmt::Display for Das {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(&self.as_str())
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl Das {
-    pub fn bytes(&self) -> Bytes {
-        self.0.clone()
+    struct Source;
+
+    impl ToDas for Source {
+        fn has_global_attributes(&self) -> bool {
+            false
+        }
+
+        fn global_attributes(&self) -> Box<dyn Iterator<Item = Attribute>> {
+            Box::new(std::iter::empty())
+        }
+
+        fn variables(&self) -> Box<dyn Iterator<Item = String>> {
+            Box::new(vec!["a".to_string(), "b".to_string()].into_iter())
+        }
+
+        fn variable_attributes(&self, variable: &str) -> Box<dyn Iterator<Item = Attribute>> {
+            Box::new(std::iter::once(Attribute {
+                name: "long_name".into(),
+                value: AttrValue::Str(variable.to_uppercase()),
+            }))
+        }
     }
 
-    pub fn as_str(&self) -> std::borrow::Cow<'_, str> {
-        String::from_utf8_lossy(&self.0)
+    #[test]
+    fn set_variables() {
+        let mut das = Das::from(Source);
+
+        let units = Attribute {
+            name: "units".into(),
+            value: AttrValue::Str("m".into()),
+        };
+
+        das.set_variable("a", vec![units.clone()]);
+        das.set_variable("c", vec![]);
+        das.add_variable("d", vec![units]);
+
+        assert_eq!(
+            das.to_string(),
+            r#"Attributes {
+    a {
+        String units "m";
+    }
+    b {
+        String long_name "B";
+    }
+    c {
+    }
+    d {
+        String units "m";
+    }
+}"#
+        );
     }
 }
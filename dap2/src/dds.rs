@@ -7,11 +7,12 @@
 //! response](DdsResponse) with [constrained variables](ConstrainedVariable). These are suitable
 //! for reading and streaming the XDR serialized variables.
 use itertools::{izip, Itertools};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 
 use super::constraint::{Constraint, ConstraintVariable};
 use super::hyperslab;
+use super::selection;
 
 const INDENT: usize = 4;
 
@@ -30,6 +31,10 @@ pub struct Variable {
     dimensions: Vec<String>,
     shape: Vec<usize>,
     position: usize,
+    /// Index coordinate added by [Dds::add_index_coordinates], not read from the source.
+    index: bool,
+    /// Members of a structure or sequence, see [Variable::structure] and [Variable::sequence].
+    members: Vec<(String, VarType)>,
 }
 
 impl Variable {
@@ -45,6 +50,32 @@ impl Variable {
             dimensions,
             shape,
             position: 0,
+            index: false,
+            members: Vec::new(),
+        }
+    }
+
+    /// A structure (or an array of structures) with scalar `members`, e.g. a HDF5 compound
+    /// dataset.
+    pub fn structure(
+        name: String,
+        dimensions: Vec<String>,
+        shape: Vec<usize>,
+        members: Vec<(String, VarType)>,
+    ) -> Variable {
+        Variable {
+            vartype: VarType::structure(&members),
+            members,
+            ..Variable::new(name, VarType::Unimplemented, dimensions, shape)
+        }
+    }
+
+    /// A sequence (table) with scalar `members` as columns. The number of rows is not known in
+    /// advance, the rows are streamed by the source (see [crate::DodsXdr::sequence_rows]).
+    pub fn sequence(name: String, members: Vec<(String, VarType)>) -> Variable {
+        Variable {
+            members,
+            ..Variable::new(name, VarType::Sequence, Vec::new(), Vec::new())
         }
     }
 }
@@ -61,19 +92,52 @@ pub enum VarType {
     Int64,
     Byte,
     String(usize),
+    /// A structure with members of this XDR encoded size, see [Variable::structure].
+    Structure(usize),
+    /// A sequence, see [Variable::sequence].
+    Sequence,
     Unimplemented,
 }
 
+/// A value in a row of a sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    String(String),
+}
+
+/// A row of a sequence, with a value for each (constrained) member.
+pub type Row = Vec<Value>;
+
 impl VarType {
+    /// The type of a structure with `members`.
+    pub fn structure(members: &[(String, VarType)]) -> VarType {
+        VarType::Structure(members.iter().map(|(_, t)| t.xdr_member_size()).sum())
+    }
+
+    /// The type of this structure or sequence with only `members`.
+    fn with_members(self, members: &[(String, VarType)]) -> VarType {
+        match self {
+            VarType::Sequence => VarType::Sequence,
+            _ => VarType::structure(members),
+        }
+    }
+
+    /// XDR encoded size as a scalar member of a structure, values are padded to 4 bytes.
+    pub fn xdr_member_size(&self) -> usize {
+        self.xdr_size().max(4)
+    }
+
     pub fn size(&self) -> usize {
         use VarType::*;
 
         match self {
             Byte => 1,
-            String(n) => *n,
+            String(n) | Structure(n) => *n,
             UInt16 | Int16 => 2,
             Float32 | UInt32 | Int32 => 4,
             Float64 | UInt64 | Int64 => 8,
+            Sequence => 0, // Not known until the rows are streamed.
             Unimplemented => panic!("Tried to get size of unimplemented variable"),
         }
     }
@@ -83,10 +147,11 @@ impl VarType {
 
         match self {
             Byte => 1, // Should this be 4?
-            String(n) => *n,
+            String(n) | Structure(n) => *n,
             UInt16 | Int16 => 4, // Upcast from 2 to 4.
             Float32 | UInt32 | Int32 => 4,
             Float64 | UInt64 | Int64 => 8,
+            Sequence => 0, // Not known until the rows are streamed.
             Unimplemented => panic!("Tried to get size of unimplemented variable"),
         }
     }
@@ -105,6 +170,8 @@ impl fmt::Display for VarType {
             VarType::Int64 => "Int64",
             VarType::Byte => "Byte",
             VarType::String(_) => "String",
+            VarType::Structure(_) => "Structure",
+            VarType::Sequence => "Sequence",
             VarType::Unimplemented => panic!("Tried to display unimplemented type"),
         })
     }
@@ -222,6 +289,265 @@ impl Dds {
             .unwrap_or_else(|| Ok(vec![0; var.shape.len()]))
     }
 
+    /// Add an `Int32` coordinate variable with the indices along the dimension for every
+    /// dimension that does not have a coordinate variable, so that the variables with these
+    /// dimensions are served as grids. The values are generated when the variables are streamed
+    /// (see [crate::dods]), not read from the source. Returns the names of the added variables.
+    pub fn add_index_coordinates(&mut self) -> Vec<String> {
+        let missing = self
+            .variables
+            .values()
+            .flat_map(|var| var.dimensions.iter().zip(&var.shape))
+            .filter(|(dim, _)| !self.variables.contains_key(*dim))
+            .map(|(dim, len)| (dim.clone(), *len))
+            .collect::<BTreeMap<String, usize>>();
+
+        for (dim, len) in &missing {
+            let mut var = Variable::new(dim.clone(), VarType::Int32, vec![dim.clone()], vec![*len]);
+            var.index = true;
+            self.variables.insert(dim.clone(), var);
+        }
+
+        // Constrained variables are sorted by position, which must match the order of `all`.
+        for (i, var) in self.variables.values_mut().enumerate() {
+            var.position = i;
+        }
+
+        missing.into_keys().collect()
+    }
+
+    /// Add `var` to the DDS, e.g. a sequence that the source serves from variables that are
+    /// already in the DDS.
+    pub fn add_variable(&mut self, var: Variable) {
+        self.variables.insert(var.name.clone(), var);
+
+        // Constrained variables are sorted by position, which must match the order of `all`.
+        for (i, var) in self.variables.values_mut().enumerate() {
+            var.position = i;
+        }
+    }
+
+    /// Change the type of `variable`, e.g. when the source serves it converted to another type.
+    pub fn set_vartype(&mut self, variable: &str, vartype: VarType) {
+        if let Some(var) = self.variables.get_mut(variable) {
+            var.vartype = vartype;
+        }
+    }
+
+    /// Change the dimension names of `variable`, e.g. when the source names the dimensions
+    /// differently depending on how it is served.
+    pub fn set_dimensions(&mut self, variable: &str, dimensions: Vec<String>) {
+        if let Some(var) = self.variables.get_mut(variable) {
+            var.dimensions = dimensions;
+        }
+    }
+
+    /// Whether the variable is an index coordinate added by [Dds::add_index_coordinates].
+    pub fn is_index_coordinate(&self, variable: &str) -> bool {
+        self.variables.get(variable).is_some_and(|var| var.index)
+    }
+
+    /// The members of a structure or sequence, empty for other variables.
+    pub fn members(&self, variable: &str) -> &[(String, VarType)] {
+        self.variables
+            .get(variable)
+            .map_or(&[], |var| var.members.as_slice())
+    }
+
+    /// Whether `variable` names a member of a sequence, either qualified (`sequence.member`) or
+    /// not. An unqualified name of both a member and a variable is ambiguous, and an error.
+    pub fn is_sequence_member(&self, variable: &str) -> anyhow::Result<bool> {
+        let member = self
+            .variables
+            .values()
+            .filter(|var| matches!(var.vartype, VarType::Sequence))
+            .any(|var| {
+                var.members
+                    .iter()
+                    .any(|(m, _)| selection::is_member(variable, &var.name, m))
+            });
+
+        ensure!(
+            !(member && self.variables.contains_key(variable)),
+            "{} is both a variable and a member of a sequence, qualify the member with the sequence",
+            variable
+        );
+
+        Ok(member)
+    }
+
+    /// Constrain the variables of `constraint` with the dimensions in `ranges` to the (inclusive)
+    /// range of indices along those dimensions. Used for selections on 1-D variables (see
+    /// [crate::selection]).
+    pub fn select(
+        &self,
+        constraint: &mut Constraint,
+        ranges: &HashMap<String, (usize, usize)>,
+    ) -> anyhow::Result<()> {
+        use ConstraintVariable::*;
+
+        if ranges.is_empty() {
+            return Ok(());
+        }
+
+        // Only selections, meaning all variables.
+        if constraint.is_empty() {
+            constraint.extend(
+                self.variables
+                    .values()
+                    .sorted_by_key(|var| var.position)
+                    .map(|var| Variable((var.name.clone(), None))),
+            );
+        }
+
+        for c in constraint.iter_mut() {
+            let (var, slab) = match c {
+                Variable((var, slab)) => (self.variables.get(var.as_str()), slab),
+                Structure((v1, v2, slab)) => (
+                    self.variables.get(v1.as_str()).and_then(|var1| {
+                        if var1.members.is_empty() {
+                            self.variables.get(v2.as_str())
+                        } else {
+                            Some(var1)
+                        }
+                    }),
+                    slab,
+                ),
+            };
+
+            // Missing variables are reported when the DDS response is made.
+            let Some(var) = var else {
+                continue;
+            };
+
+            if !var.dimensions.iter().any(|d| ranges.contains_key(d)) {
+                continue;
+            }
+
+            *slab = Some(
+                izip!(&var.dimensions, &var.shape, 0..)
+                    .map(|(dim, n, i)| {
+                        let (start, end) = match slab.as_ref().and_then(|s| s.get(i)) {
+                            None => (0, n.saturating_sub(1)),
+                            Some(s) if s.len() == 1 => (s[0], s[0]),
+                            Some(s) if s.len() == 2 => (s[0], s[1]),
+                            Some(_) => {
+                                return Err(anyhow!("hyperslabs with strides not supported"))
+                            }
+                        };
+
+                        match ranges.get(dim) {
+                            Some((lo, hi)) => {
+                                let (start, end) = (start.max(*lo), end.min(*hi));
+                                ensure!(start <= end, "no values of {} match selection", dim);
+                                Ok(vec![start, end])
+                            }
+                            None => Ok(vec![start, end]),
+                        }
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The structure or sequence `var` constrained to `member`.
+    fn member(
+        &self,
+        var: &Variable,
+        member: &str,
+        slab: &Option<Vec<Vec<usize>>>,
+    ) -> anyhow::Result<ConstrainedVariable> {
+        ensure!(
+            slab.as_ref().is_none_or(|s| s.iter().all(|i| i.len() < 3)),
+            "hyperslabs with strides not supported"
+        );
+
+        let members: Vec<_> = var
+            .members
+            .iter()
+            .filter(|(name, _)| name == member)
+            .cloned()
+            .collect();
+        ensure!(
+            !members.is_empty(),
+            "Variable not found: {}.{}",
+            var.name,
+            member
+        );
+
+        let indices = self.extend_indices(var, slab)?;
+        let counts = self.extend_counts(var, &indices, slab)?;
+
+        Ok(ConstrainedVariable::Variable(DdsVariableDetails {
+            name: var.name.clone(),
+            vartype: var.vartype.with_members(&members),
+            dimensions: var
+                .dimensions
+                .iter()
+                .cloned()
+                .zip(counts.iter().copied())
+                .collect(),
+            size: counts.iter().product(),
+            indices,
+            counts,
+            members,
+        }))
+    }
+
+    /// Merge several member projections of the same structure (or sequence) into a single
+    /// variable, with the members in the order of the structure.
+    fn merge_members(&self, variables: &mut Vec<ConstrainedVariable>) -> anyhow::Result<()> {
+        let mut merged: Vec<ConstrainedVariable> = Vec::with_capacity(variables.len());
+
+        for c in variables.drain(..) {
+            let prev = merged.iter_mut().find_map(|m| match (m, &c) {
+                (ConstrainedVariable::Variable(m), ConstrainedVariable::Variable(v))
+                    if !m.members.is_empty() && !v.members.is_empty() && m.name == v.name =>
+                {
+                    Some(m)
+                }
+                _ => None,
+            });
+
+            match (prev, c) {
+                (Some(m), ConstrainedVariable::Variable(v)) => {
+                    ensure!(
+                        m.indices == v.indices && m.counts == v.counts,
+                        "different hyperslabs of the members of {} not supported",
+                        v.name
+                    );
+
+                    let var = &self.variables[v.name.as_str()];
+                    m.members = var
+                        .members
+                        .iter()
+                        .filter(|(s, _)| m.members.iter().chain(&v.members).any(|(n, _)| n == s))
+                        .cloned()
+                        .collect();
+                    m.vartype = m.vartype.with_members(&m.members);
+                }
+                (_, c) => merged.push(c),
+            }
+        }
+
+        *variables = merged;
+        Ok(())
+    }
+
+    /// A variable is a grid if it has more than one dimension and all dimensions have 1-D
+    /// coordinate variables which can be used as maps. Structures are never grids.
+    fn is_grid(&self, var: &Variable) -> bool {
+        var.members.is_empty()
+            && var.dimensions.len() > 1
+            && var.dimensions.iter().all(|d| {
+                self.variables
+                    .get(d)
+                    .is_some_and(|d| d.dimensions.len() == 1)
+            })
+    }
+
     /// Return a DDS response with all the variables.
     pub fn all(&self) -> DdsResponse {
         DdsResponse {
@@ -232,12 +558,7 @@ impl Dds {
                 .map(|var| {
                     // If not all dimensions have corresponding variables, return as variable and
                     // not a gridded variable.
-                    if var.dimensions.len() > 1
-                        && var
-                            .dimensions
-                            .iter()
-                            .all(|d| self.variables.contains_key(d))
-                    {
+                    if self.is_grid(var) {
                         ConstrainedVariable::Grid {
                             variable: DdsVariableDetails {
                                 name: var.name.clone(),
@@ -251,6 +572,7 @@ impl Dds {
                                 size: var.shape.iter().product(),
                                 indices: vec![0; var.shape.len()],
                                 counts: var.shape.clone(),
+                                members: Vec::new(),
                             },
                             dimensions: var
                                 .dimensions
@@ -268,6 +590,7 @@ impl Dds {
                                         size: dim.shape.iter().product(),
                                         indices: vec![0; dim.shape.len()],
                                         counts: dim.shape.clone(),
+                                        members: Vec::new(),
                                     })
                                 })
                                 .collect(),
@@ -285,6 +608,7 @@ impl Dds {
                             size: var.shape.iter().product(),
                             indices: vec![0; var.shape.len()],
                             counts: var.shape.clone(),
+                            members: var.members.clone(),
                         })
                     }
                 })
@@ -316,12 +640,7 @@ impl Dds {
                                     let indices = self.extend_indices(var, slab)?;
                                     let counts = self.extend_counts(var, &indices, slab)?;
 
-                                    if var.dimensions.len() > 1
-                                        && var
-                                            .dimensions
-                                            .iter()
-                                            .all(|d| self.variables.contains_key(d))
-                                    {
+                                    if self.is_grid(var) {
                                         Ok(ConstrainedVariable::Grid {
                                             variable: DdsVariableDetails {
                                                 name: var.name.clone(),
@@ -335,6 +654,7 @@ impl Dds {
                                                 size: counts.iter().product(),
                                                 indices: indices.clone(),
                                                 counts: counts.clone(),
+                                                members: Vec::new(),
                                             },
 
                                             // XXX: More deeply nested dimensions are not
@@ -353,6 +673,7 @@ impl Dds {
                                                             size: *c,
                                                             indices: vec![*i],
                                                             counts: vec![*c],
+                                                            members: Vec::new(),
                                                         })
                                                         .ok_or_else(|| {
                                                             anyhow!(
@@ -376,12 +697,22 @@ impl Dds {
                                             size: counts.iter().product(),
                                             indices,
                                             counts,
+                                            members: var.members.clone(),
                                         }))
                                     }
                                 })
                                 .ok_or_else(|| anyhow!("Variable not found: {}", var))?
                         }
 
+                        Structure((v1, v2, slab))
+                            if self
+                                .variables
+                                .get(v1.as_str())
+                                .is_some_and(|var1| !var1.members.is_empty()) =>
+                        {
+                            self.member(&self.variables[v1.as_str()], v2, slab)
+                        }
+
                         Structure((v1, v2, slab)) => self
                             .variables
                             .get(v1.as_str())
@@ -413,6 +744,7 @@ impl Dds {
                                         size: counts.iter().product(),
                                         indices,
                                         counts,
+                                        members: Vec::new(),
                                     },
                                 })
                             }),
@@ -420,6 +752,8 @@ impl Dds {
                 })
                 .collect::<Result<Vec<ConstrainedVariable>, anyhow::Error>>()?;
 
+            self.merge_members(&mut variables)?;
+
             // Netcdf clients require the response to be sorted the same way the initial free DDS
             // query of all variables are.
             variables.sort_by_key(|c| {
@@ -453,6 +787,9 @@ pub struct DdsVariableDetails {
     /// Slice in the variable
     pub indices: Vec<usize>,
     pub counts: Vec<usize>,
+
+    /// The (constrained) members of a structure, empty for other variables.
+    pub members: Vec<(String, VarType)>,
 }
 
 impl DdsVariableDetails {
@@ -460,6 +797,14 @@ impl DdsVariableDetails {
         self.dimensions.is_empty()
     }
 
+    pub fn is_structure(&self) -> bool {
+        !self.members.is_empty() && !self.is_sequence()
+    }
+
+    pub fn is_sequence(&self) -> bool {
+        matches!(self.vartype, VarType::Sequence)
+    }
+
     /// Number of elements in array.
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
@@ -473,7 +818,13 @@ impl DdsVariableDetails {
 
     /// Size of variable serialized with XDR and with XDR header, in bytes.
     pub fn dods_size(&self) -> usize {
-        self.size * self.vartype.xdr_size() + if self.is_scalar() { 0 } else { 8 }
+        // Arrays of structures only have a single length in the header.
+        self.size * self.vartype.xdr_size()
+            + match (self.is_scalar(), self.is_structure()) {
+                (true, _) => 0,
+                (false, true) => 4,
+                (false, false) => 8,
+            }
     }
 }
 
@@ -525,6 +876,12 @@ impl ConstrainedVariable {
         }
     }
 
+    /// Whether the variable is a sequence, the size of which is not known before it is
+    /// streamed.
+    pub fn is_sequence(&self) -> bool {
+        matches!(self, ConstrainedVariable::Variable(v) if v.is_sequence())
+    }
+
     /// Outer variable name
     pub fn name(&self) -> &str {
         use ConstrainedVariable::*;
@@ -545,7 +902,16 @@ impl ConstrainedVariable {
 
 impl fmt::Display for DdsVariableDetails {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", self.vartype, self.name)?;
+        if !self.members.is_empty() {
+            // Nested in a `ConstrainedVariable::Variable`.
+            writeln!(f, "{} {{", self.vartype)?;
+            for (name, vartype) in &self.members {
+                writeln!(f, "{}{} {};", " ".repeat(2 * INDENT), vartype, name)?;
+            }
+            write!(f, "{}}} {}", " ".repeat(INDENT), self.name)?;
+        } else {
+            write!(f, "{} {}", self.vartype, self.name)?;
+        }
 
         for (d, sz) in &self.dimensions {
             write!(f, "[{} = {}]", d, sz)?;
@@ -610,10 +976,15 @@ impl DdsResponse {
         self.variables.iter().map(|v| v.size()).sum()
     }
 
-    /// Total XDR size of variables in bytes.
+    /// Total XDR size of variables in bytes. The size of sequences is not known, see
+    /// [DdsResponse::has_sequences].
     pub fn dods_size(&self) -> usize {
         self.variables.iter().map(|v| v.dods_size()).sum()
     }
+
+    pub fn has_sequences(&self) -> bool {
+        self.variables.iter().any(|v| v.is_sequence())
+    }
 }
 
 impl fmt::Display for DdsResponse {
@@ -626,3 +997,193 @@ impl fmt::Display for DdsResponse {
         write!(f, "}} {};", self.file_name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dods::xdr::xdr_index;
+
+    struct Source;
+
+    impl ToDds for Source {
+        fn variables(&self) -> Vec<Variable> {
+            vec![
+                Variable::new("x".into(), VarType::Float32, vec!["x".into()], vec![3]),
+                Variable::new(
+                    "t".into(),
+                    VarType::Float32,
+                    vec!["x".into(), "y".into()],
+                    vec![3, 2],
+                ),
+            ]
+        }
+
+        fn file_name(&self) -> String {
+            "source".into()
+        }
+    }
+
+    #[test]
+    fn index_coordinates() {
+        let mut dds = Dds::from(Source);
+        assert!(matches!(
+            dds.all().variables[0],
+            ConstrainedVariable::Variable(_)
+        ));
+
+        assert_eq!(dds.add_index_coordinates(), vec!["y".to_string()]);
+        assert!(dds.is_index_coordinate("y"));
+        assert!(!dds.is_index_coordinate("x"));
+
+        assert_eq!(
+            dds.all().to_string(),
+            r#"Dataset {
+    Grid {
+     ARRAY:
+        Float32 t[x = 3][y = 2];
+     MAPS:
+        Float32 x[x = 3];
+        Int32 y[y = 2];
+    } t;
+    Float32 x[x = 3];
+    Int32 y[y = 2];
+} source;"#
+        );
+
+        let c = Constraint::parse("y[1:1],x").unwrap();
+        let r = dds.dds(&c).unwrap();
+        assert_eq!(r.variables[0].name(), "x");
+
+        match &r.variables[1] {
+            ConstrainedVariable::Variable(y) => {
+                assert_eq!(&xdr_index(y)[..], [0u8, 0, 0, 1]);
+            }
+            _ => panic!("expected variable"),
+        }
+    }
+
+    struct Observations;
+
+    impl ToDds for Observations {
+        fn variables(&self) -> Vec<Variable> {
+            vec![Variable::structure(
+                "obs".into(),
+                vec!["obs".into()],
+                vec![10],
+                vec![
+                    ("station".into(), VarType::Int16),
+                    ("temperature".into(), VarType::Float32),
+                    ("pressure".into(), VarType::Float64),
+                ],
+            )]
+        }
+
+        fn file_name(&self) -> String {
+            "observations".into()
+        }
+    }
+
+    #[test]
+    fn structure() {
+        let dds = Dds::from(Observations);
+
+        let all = dds.all();
+        assert_eq!(
+            all.to_string(),
+            r#"Dataset {
+    Structure {
+        Int16 station;
+        Float32 temperature;
+        Float64 pressure;
+    } obs[obs = 10];
+} observations;"#
+        );
+        assert_eq!(all.dods_size(), 4 + 10 * (4 + 4 + 8));
+
+        let c = crate::Constraint::parse("obs[0:4].pressure,obs[0:4].station").unwrap();
+        let r = dds.dds(&c).unwrap();
+        assert_eq!(
+            r.to_string(),
+            r#"Dataset {
+    Structure {
+        Int16 station;
+        Float64 pressure;
+    } obs[obs = 5];
+} observations;"#
+        );
+        assert_eq!(r.dods_size(), 4 + 5 * (4 + 8));
+
+        let c = crate::Constraint::parse("obs.humidity").unwrap();
+        assert!(dds.dds(&c).is_err());
+
+        let c = crate::Constraint::parse("obs[0:4].pressure,obs.station").unwrap();
+        assert!(dds.dds(&c).is_err());
+    }
+
+    struct Buoys;
+
+    impl ToDds for Buoys {
+        fn variables(&self) -> Vec<Variable> {
+            vec![Variable::sequence(
+                "buoys".into(),
+                vec![
+                    ("station".into(), VarType::String(0)),
+                    ("time".into(), VarType::Float64),
+                    ("temperature".into(), VarType::Float32),
+                ],
+            )]
+        }
+
+        fn file_name(&self) -> String {
+            "buoys".into()
+        }
+    }
+
+    #[test]
+    fn sequence() {
+        let dds = Dds::from(Buoys);
+
+        let all = dds.all();
+        assert!(all.has_sequences());
+        assert_eq!(
+            all.to_string(),
+            r#"Dataset {
+    Sequence {
+        String station;
+        Float64 time;
+        Float32 temperature;
+    } buoys;
+} buoys;"#
+        );
+
+        let c = crate::Constraint::parse("buoys.temperature,buoys.station").unwrap();
+        let r = dds.dds(&c).unwrap();
+        assert_eq!(
+            r.to_string(),
+            r#"Dataset {
+    Sequence {
+        String station;
+        Float32 temperature;
+    } buoys;
+} buoys;"#
+        );
+    }
+
+    #[test]
+    fn sequence_member_names() {
+        let mut dds = Dds::from(Buoys);
+
+        assert!(dds.is_sequence_member("time").unwrap());
+        assert!(dds.is_sequence_member("buoys.time").unwrap());
+        assert!(!dds.is_sequence_member("depth").unwrap());
+
+        dds.add_variable(Variable::new(
+            "time".into(),
+            VarType::Float64,
+            vec!["time".into()],
+            vec![2],
+        ));
+        assert!(dds.is_sequence_member("time").is_err());
+        assert!(dds.is_sequence_member("buoys.time").unwrap());
+    }
+}
@@ -14,6 +14,13 @@
 //! Arrays are prepended with their XDR encoded length as `u32` _twice_. While scalars do not. A
 //! Structure or Grid is sent as each member sequentially.
 //!
+//! ### Sequences
+//!
+//! Each row of a Sequence is prepended by a start-of-instance marker (`0x5A000000`) and the
+//! members are sent sequentially. The Sequence is ended by an end-of-sequence marker
+//! (`0xA5000000`). The number of rows is not known before the rows are streamed, so the content
+//! length of responses with Sequences is unknown.
+//!
 //!
 //! ### XDR types
 //!
@@ -36,7 +43,8 @@
 //! elements. Then each string is prepended with the string length of that element, then the string
 //! is sent null-terminated.
 use bytes::Bytes;
-use futures::{pin_mut, Stream, StreamExt};
+use futures::{pin_mut, Stream, StreamExt, TryStreamExt};
+use std::collections::HashMap;
 use std::pin::Pin;
 
 use async_stream::stream;
@@ -45,34 +53,101 @@ use async_trait::async_trait;
 pub mod xdr;
 use xdr::xdr_length;
 
-use crate::{dds::ConstrainedVariable, Constraint};
+use crate::dds::{ConstrainedVariable, DdsResponse, Value};
+use crate::selection::RowFilter;
+use crate::Constraint;
 
 /// A `DODS` response streaming the [DDS](crate::dds::Dds) header and the (possibly) constrained
 /// variable data.
 #[async_trait]
 pub trait Dods: crate::Dap2 + Send + Sync + Clone + 'static {
+    /// The DDS response of [crate::Constraint]. The [selections](crate::selection) on 1-D
+    /// variables are resolved to ranges of indices along their dimension, which requires reading
+    /// the values of these variables. Selections on the members of sequences are applied to the
+    /// rows when they are streamed.
+    async fn constrained_dds(&self, constraint: &Constraint) -> anyhow::Result<DdsResponse> {
+        let dds = self.dds().await;
+        let mut ranges: HashMap<String, (usize, usize)> = HashMap::new();
+
+        for s in constraint.selections() {
+            if dds.is_sequence_member(&s.variable)? {
+                continue;
+            }
+
+            let var = match dds.dds(&Constraint::parse(&s.variable)?)?.variables.pop() {
+                Some(ConstrainedVariable::Variable(v)) if v.dimensions.len() == 1 => v,
+                _ => return Err(anyhow!("selection on {} not supported", s.variable)),
+            };
+
+            let values = if dds.is_index_coordinate(&var.name) {
+                xdr::xdr_index(&var)
+            } else {
+                let bytes: Vec<Bytes> = self.variable_xdr(&var).await?.try_collect().await?;
+                Bytes::from(bytes.concat())
+            };
+
+            let matching: Vec<usize> = xdr::xdr_decode_f64(var.vartype, &values)?
+                .into_iter()
+                .enumerate()
+                .filter(|(_, v)| s.matches(&Value::Number(*v)))
+                .map(|(i, _)| i)
+                .collect();
+
+            let (lo, hi) = match (matching.first(), matching.last()) {
+                (Some(lo), Some(hi)) => (*lo, *hi),
+                _ => return Err(anyhow!("no values of {} match selection", var.name)),
+            };
+
+            // Only a range of indices can be constrained, e.g. not `!=` or a selection on a map
+            // that is not monotonic.
+            ensure!(
+                hi - lo + 1 == matching.len(),
+                "selection on {} does not match a contiguous range of values",
+                var.name
+            );
+
+            let dim = &var.dimensions[0].0;
+            let (lo, hi) = match ranges.get(dim) {
+                Some((l, h)) => (lo.max(*l), hi.min(*h)),
+                None => (lo, hi),
+            };
+            ensure!(lo <= hi, "no values of {} match selection", dim);
+
+            ranges.insert(dim.clone(), (lo, hi));
+        }
+
+        let mut constraint = constraint.clone();
+        dds.select(&mut constraint, &ranges)?;
+        dds.dds(&constraint)
+    }
+
     /// A streamed DODS response based on [crate::Constraint] for a data source
     /// implementing [crate::Dap2].
     ///
-    /// Returns a tuple with the content length (in bytes) and a stream of [Bytes].
+    /// Returns a tuple with the content length (in bytes, `None` if it is not known because the
+    /// response contains sequences) and a stream of [Bytes].
     async fn dods(
         &self,
         constraint: Constraint,
     ) -> Result<
         (
-            u64,
+            Option<u64>,
             Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>,
         ),
         anyhow::Error,
     > {
-        let dds = self.dds().await.dds(&constraint)?;
+        let dds = self.constrained_dds(&constraint).await?;
+        let selections = constraint.selections().to_vec();
         let dds_bytes = Bytes::from(dds.to_string());
-        let content_length = (dds.dods_size() + dds_bytes.len() + 8) as u64;
-        debug!(
-            "dods length: {} b / {} mb",
-            content_length,
-            content_length / 1024 / 1024
-        );
+        let content_length =
+            (!dds.has_sequences()).then(|| (dds.dods_size() + dds_bytes.len() + 8) as u64);
+        if let Some(content_length) = content_length {
+            debug!(
+                "dods length: {} b / {} mb",
+                content_length,
+                content_length / 1024 / 1024
+            );
+        }
 
         let slf = self.clone();
 
@@ -85,10 +160,36 @@ pub trait Dods: crate::Dap2 + Send + Sync + Clone + 'static {
                     ConstrainedVariable::Variable(v) |
                         ConstrainedVariable::Structure { variable: _, member: v }
                     => {
-                        if !v.is_scalar() {
+                        if v.is_sequence() {
+                            let filter = RowFilter::new(slf.dds().await.members(&v.name), &v, &selections);
+                            let rows = slf.sequence_rows(filter.source()).await?;
+
+                            pin_mut!(rows);
+
+                            while let Some(row) = rows.next().await {
+                                match row.map(|r| filter.apply(r)) {
+                                    Ok(Some(r)) => yield xdr::xdr_row(&v.members, &r),
+                                    Ok(None) => (),
+                                    Err(e) => yield Err(e),
+                                }
+                            }
+
+                            yield Ok(Bytes::from_static(&xdr::END_OF_SEQUENCE));
+                            continue;
+                        }
+
+                        if v.is_structure() && !v.is_scalar() {
+                            // Arrays of structures are only prefixed by a single length.
+                            yield Ok(Bytes::copy_from_slice(&(v.len() as u32).to_be_bytes()));
+                        } else if !v.is_scalar() {
                             yield Ok(Bytes::from(Vec::from(xdr_length(v.len() as u32))));
                         }
 
+                        if slf.dds().await.is_index_coordinate(&v.name) {
+                            yield Ok(xdr::xdr_index(&v));
+                            continue;
+                        }
+
                         let reader = slf.variable_xdr(&v).await?;
 
                         pin_mut!(reader);
@@ -106,6 +207,11 @@ pub trait Dods: crate::Dap2 + Send + Sync + Clone + 'static {
                                 yield Ok(Bytes::from(Vec::from(xdr_length(variable.len() as u32))));
                             }
 
+                            if slf.dds().await.is_index_coordinate(&variable.name) {
+                                yield Ok(xdr::xdr_index(&variable));
+                                continue;
+                            }
+
                             let reader = slf.variable_xdr(&variable).await?;
 
                             pin_mut!(reader);
@@ -122,3 +228,304 @@ pub trait Dods: crate::Dap2 + Send + Sync + Clone + 'static {
 }
 
 impl<T: crate::Dap2 + Send + Sync + Clone + 'static> Dods for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::das::{Attribute, ToDas};
+    use crate::dds::{DdsVariableDetails, Row, ToDds, Value, VarType, Variable};
+    use crate::{Das, Dds, DodsXdr};
+    use std::sync::Arc;
+
+    struct Buoys {
+        das: Das,
+        dds: Dds,
+    }
+
+    struct Source;
+
+    impl ToDas for Source {
+        fn has_global_attributes(&self) -> bool {
+            false
+        }
+
+        fn global_attributes(&self) -> Box<dyn Iterator<Item = Attribute>> {
+            Box::new(std::iter::empty())
+        }
+
+        fn variables(&self) -> Box<dyn Iterator<Item = String>> {
+            Box::new(std::iter::once("buoys".to_string()))
+        }
+
+        fn variable_attributes(&self, _variable: &str) -> Box<dyn Iterator<Item = Attribute>> {
+            Box::new(std::iter::empty())
+        }
+    }
+
+    impl ToDds for Source {
+        fn variables(&self) -> Vec<Variable> {
+            vec![Variable::sequence(
+                "buoys".into(),
+                vec![
+                    ("station".into(), VarType::String(0)),
+                    ("temperature".into(), VarType::Float32),
+                ],
+            )]
+        }
+
+        fn file_name(&self) -> String {
+            "buoys".into()
+        }
+    }
+
+    #[async_trait]
+    impl DodsXdr for Buoys {
+        async fn variable_xdr(
+            &self,
+            variable: &DdsVariableDetails,
+        ) -> Result<
+            Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>,
+            anyhow::Error,
+        > {
+            Err(anyhow!("{} is not an array", variable.name))
+        }
+
+        async fn sequence_rows(
+            &self,
+            variable: &DdsVariableDetails,
+        ) -> Result<
+            Pin<Box<dyn Stream<Item = Result<Row, anyhow::Error>> + Send + 'static>>,
+            anyhow::Error,
+        > {
+            let rows = [("a", 1.5), ("b", 2.5)].map(|(station, temperature)| {
+                variable
+                    .members
+                    .iter()
+                    .map(|(m, _)| match m.as_str() {
+                        "station" => Value::String(station.into()),
+                        _ => Value::Number(temperature),
+                    })
+                    .collect::<Row>()
+            });
+
+            Ok(futures::stream::iter(rows.map(Ok)).boxed())
+        }
+    }
+
+    #[async_trait]
+    impl crate::Dap2 for Buoys {
+        async fn das(&self) -> &Das {
+            &self.das
+        }
+
+        async fn dds(&self) -> &Dds {
+            &self.dds
+        }
+
+        async fn raw(
+            &self,
+        ) -> Result<
+            (
+                u64,
+                Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static>>,
+            ),
+            std::io::Error,
+        > {
+            Err(std::io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    #[test]
+    fn sequence() {
+        let buoys = Arc::new(Buoys {
+            das: Das::from(Source),
+            dds: Dds::from(Source),
+        });
+
+        let c = Constraint::parse("buoys.temperature").unwrap();
+        let (content_length, body) = futures::executor::block_on(buoys.dods(c)).unwrap();
+        assert!(content_length.is_none());
+
+        let body: Vec<Bytes> =
+            futures::executor::block_on(futures::TryStreamExt::try_collect(body)).unwrap();
+        let body = body.concat();
+
+        let dds = r#"Dataset {
+    Sequence {
+        Float32 temperature;
+    } buoys;
+} buoys;
+
+Data:
+"#;
+        assert_eq!(&body[..dds.len()], dds.as_bytes());
+
+        let mut data = Vec::new();
+        for t in [1.5_f32, 2.5] {
+            data.extend_from_slice(&xdr::START_OF_INSTANCE);
+            data.extend_from_slice(&t.to_be_bytes());
+        }
+        data.extend_from_slice(&xdr::END_OF_SEQUENCE);
+
+        assert_eq!(&body[dds.len()..], &data[..]);
+    }
+
+    #[test]
+    fn sequence_selection() {
+        let buoys = Arc::new(Buoys {
+            das: Das::from(Source),
+            dds: Dds::from(Source),
+        });
+
+        let c = Constraint::parse("buoys.temperature&buoys.station=\"b\"").unwrap();
+        let (_, body) = futures::executor::block_on(buoys.dods(c)).unwrap();
+        let body: Vec<Bytes> =
+            futures::executor::block_on(futures::TryStreamExt::try_collect(body)).unwrap();
+        let body = body.concat();
+
+        let mut data = xdr::START_OF_INSTANCE.to_vec();
+        data.extend_from_slice(&2.5_f32.to_be_bytes());
+        data.extend_from_slice(&xdr::END_OF_SEQUENCE);
+
+        assert!(body.ends_with(&data));
+        assert_eq!(
+            body.windows(4)
+                .filter(|w| *w == xdr::START_OF_INSTANCE)
+                .count(),
+            1
+        );
+    }
+
+    /// A grid `t[x][y]` with the maps `x = [10, 20, 30]` and `y = [0, 1]`.
+    #[derive(Clone)]
+    struct Grid {
+        das: Arc<Das>,
+        dds: Arc<Dds>,
+    }
+
+    struct GridSource;
+
+    impl ToDas for GridSource {
+        fn has_global_attributes(&self) -> bool {
+            false
+        }
+
+        fn global_attributes(&self) -> Box<dyn Iterator<Item = Attribute>> {
+            Box::new(std::iter::empty())
+        }
+
+        fn variables(&self) -> Box<dyn Iterator<Item = String>> {
+            Box::new(["x", "y", "t"].into_iter().map(String::from))
+        }
+
+        fn variable_attributes(&self, _variable: &str) -> Box<dyn Iterator<Item = Attribute>> {
+            Box::new(std::iter::empty())
+        }
+    }
+
+    impl ToDds for GridSource {
+        fn variables(&self) -> Vec<Variable> {
+            vec![
+                Variable::new("x".into(), VarType::Float32, vec!["x".into()], vec![3]),
+                Variable::new("y".into(), VarType::Float32, vec!["y".into()], vec![2]),
+                Variable::new(
+                    "t".into(),
+                    VarType::Float32,
+                    vec!["x".into(), "y".into()],
+                    vec![3, 2],
+                ),
+            ]
+        }
+
+        fn file_name(&self) -> String {
+            "grid".into()
+        }
+    }
+
+    #[async_trait]
+    impl DodsXdr for Grid {
+        async fn variable_xdr(
+            &self,
+            variable: &DdsVariableDetails,
+        ) -> Result<
+            Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>,
+            anyhow::Error,
+        > {
+            let values: Vec<f64> = match variable.name.as_str() {
+                "x" => vec![10., 20., 30.],
+                "y" => vec![0., 1.],
+                _ => return Err(anyhow!("not a map: {}", variable.name)),
+            };
+            let (i, n) = (variable.indices[0], variable.counts[0]);
+            let b = xdr::xdr_encode_f64(VarType::Float32, &values[i..i + n])?;
+
+            Ok(futures::stream::once(async { Ok(Bytes::from(b)) }).boxed())
+        }
+    }
+
+    #[async_trait]
+    impl crate::Dap2 for Grid {
+        async fn das(&self) -> &Das {
+            &self.das
+        }
+
+        async fn dds(&self) -> &Dds {
+            &self.dds
+        }
+
+        async fn raw(
+            &self,
+        ) -> Result<
+            (
+                u64,
+                Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static>>,
+            ),
+            std::io::Error,
+        > {
+            Err(std::io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    #[test]
+    fn grid_selection() {
+        let grid = Grid {
+            das: Arc::new(Das::from(GridSource)),
+            dds: Arc::new(Dds::from(GridSource)),
+        };
+        let dds = |c: &str| {
+            futures::executor::block_on(grid.constrained_dds(&Constraint::parse(c).unwrap()))
+                .map(|r| r.to_string())
+        };
+
+        assert_eq!(
+            dds("t&x>=20").unwrap(),
+            r#"Dataset {
+    Grid {
+     ARRAY:
+        Float32 t[x = 2][y = 2];
+     MAPS:
+        Float32 x[x = 2];
+        Float32 y[y = 2];
+    } t;
+} grid;"#
+        );
+
+        // The range of indices is intersected with the hyperslab.
+        assert!(dds("t[0:1][1]&x>=20")
+            .unwrap()
+            .contains("Float32 t[x = 1][y = 1];"));
+
+        // Only selections, meaning all variables.
+        let all = dds("&x<=20&y=1").unwrap();
+        assert!(all.contains("Float32 t[x = 2][y = 1];"));
+        assert!(all.starts_with("Dataset {\n    Float32 x[x = 2];\n    Float32 y[y = 1];\n"));
+
+        // The matching values must be a contiguous range.
+        assert!(dds("t&x!=20").is_err());
+        assert!(dds("t&x={10,30}").is_err());
+        assert!(dds("t&x={10,20}").is_ok());
+
+        assert!(dds("t&x>30").is_err());
+        assert!(dds("t&t>1").is_err());
+    }
+}
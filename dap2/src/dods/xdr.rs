@@ -1,7 +1,13 @@
 use bytes::Bytes;
 use std::mem;
 
-use crate::dds::{DdsVariableDetails, VarType};
+use crate::dds::{DdsVariableDetails, Value, VarType};
+
+/// Marks the start of each row (instance) of a sequence.
+pub const START_OF_INSTANCE: [u8; 4] = [0x5a, 0, 0, 0];
+
+/// Marks the end of a sequence.
+pub const END_OF_SEQUENCE: [u8; 4] = [0xa5, 0, 0, 0];
 
 /// XDR encoded length.
 pub fn xdr_length(len: u32) -> [u8; 8] {
@@ -61,6 +67,99 @@ pub fn xdr_serialize(v: &DdsVariableDetails, b: Bytes) -> Bytes {
     }
 }
 
+/// Decode XDR encoded values of `vartype` to `f64`.
+pub fn xdr_decode_f64(vartype: VarType, b: &[u8]) -> anyhow::Result<Vec<f64>> {
+    use VarType::*;
+
+    let chunks = b.chunks_exact(vartype.xdr_size());
+    ensure!(
+        chunks.remainder().is_empty(),
+        "length of bytes not a multiple of data type size"
+    );
+
+    chunks
+        .map(|c| {
+            Ok(match vartype {
+                Byte => c[0] as f64,
+                UInt16 | UInt32 => u32::from_be_bytes(c.try_into()?) as f64,
+                Int16 | Int32 => i32::from_be_bytes(c.try_into()?) as f64,
+                Float32 => f32::from_be_bytes(c.try_into()?) as f64,
+                Float64 => f64::from_be_bytes(c.try_into()?),
+                UInt64 => u64::from_be_bytes(c.try_into()?) as f64,
+                Int64 => i64::from_be_bytes(c.try_into()?) as f64,
+                String(_) | Structure(_) | Sequence | Unimplemented => {
+                    return Err(anyhow!("cannot decode {:?} as number", vartype))
+                }
+            })
+        })
+        .collect()
+}
+
+/// Encode `f64` values as XDR encoded values of `vartype`. Values are rounded to the nearest
+/// integer for integer types.
+pub fn xdr_encode_f64(vartype: VarType, values: &[f64]) -> anyhow::Result<Vec<u8>> {
+    use VarType::*;
+
+    let mut b = Vec::with_capacity(values.len() * vartype.xdr_size());
+
+    for v in values {
+        match vartype {
+            Byte => b.push(v.round() as u8),
+            UInt16 | UInt32 => b.extend_from_slice(&(v.round() as u32).to_be_bytes()),
+            Int16 | Int32 => b.extend_from_slice(&(v.round() as i32).to_be_bytes()),
+            Float32 => b.extend_from_slice(&(*v as f32).to_be_bytes()),
+            Float64 => b.extend_from_slice(&v.to_be_bytes()),
+            UInt64 => b.extend_from_slice(&(v.round() as u64).to_be_bytes()),
+            Int64 => b.extend_from_slice(&(v.round() as i64).to_be_bytes()),
+            String(_) | Structure(_) | Sequence | Unimplemented => {
+                return Err(anyhow!("cannot encode number as {:?}", vartype))
+            }
+        }
+    }
+
+    Ok(b)
+}
+
+/// XDR encoded row of a sequence with `members`, starting with [START_OF_INSTANCE]. Each value
+/// is padded to 4 bytes, strings are prefixed by their length.
+pub fn xdr_row(members: &[(String, VarType)], row: &[Value]) -> anyhow::Result<Bytes> {
+    ensure!(
+        members.len() == row.len(),
+        "row has {} values, expected {}",
+        row.len(),
+        members.len()
+    );
+
+    let mut b = START_OF_INSTANCE.to_vec();
+
+    for ((name, vartype), value) in members.iter().zip(row) {
+        match (vartype, value) {
+            (VarType::String(_), Value::String(s)) => {
+                b.extend_from_slice(&(s.len() as u32).to_be_bytes());
+                b.extend_from_slice(s.as_bytes());
+                b.resize(b.len() + (4 - s.len() % 4) % 4, 0);
+            }
+            (VarType::Byte, Value::Number(v)) => {
+                b.extend_from_slice(&(v.round() as u8 as u32).to_be_bytes())
+            }
+            (_, Value::Number(v)) => b.extend(xdr_encode_f64(*vartype, &[*v])?),
+            _ => return Err(anyhow!("{:?} is not a value of {}", value, name)),
+        }
+    }
+
+    Ok(Bytes::from(b))
+}
+
+/// XDR encoded values of an index coordinate (see [crate::dds::Dds::add_index_coordinates]): the
+/// constrained indices along the dimension.
+pub fn xdr_index(v: &DdsVariableDetails) -> Bytes {
+    let start = v.indices[0] as i32;
+
+    (start..start + v.counts[0] as i32)
+        .flat_map(|i| i.to_be_bytes())
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -72,4 +171,54 @@ mod test {
 
         assert_eq!(b, [0u8, 0, 0, 2, 0, 0, 0, 2]);
     }
+
+    #[test]
+    fn row() {
+        let members = vec![
+            ("station".to_string(), VarType::String(0)),
+            ("flag".to_string(), VarType::Byte),
+            ("temperature".to_string(), VarType::Float32),
+        ];
+
+        let b = xdr_row(
+            &members,
+            &[
+                Value::String("buoy1".into()),
+                Value::Number(3.),
+                Value::Number(1.5),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(b[..4], START_OF_INSTANCE);
+        assert_eq!(b[4..8], 5_u32.to_be_bytes());
+        assert_eq!(&b[8..16], b"buoy1\0\0\0");
+        assert_eq!(b[16..20], 3_u32.to_be_bytes());
+        assert_eq!(b[20..], 1.5_f32.to_be_bytes());
+
+        assert!(xdr_row(&members, &[Value::Number(1.)]).is_err());
+    }
+
+    #[test]
+    fn decode_encode_f64() {
+        let v = vec![1., -2., 31.];
+
+        for t in [
+            VarType::Int16,
+            VarType::Int32,
+            VarType::Float32,
+            VarType::Float64,
+            VarType::Int64,
+        ] {
+            let b = xdr_encode_f64(t, &v).unwrap();
+            assert_eq!(b.len(), 3 * t.xdr_size());
+            assert_eq!(xdr_decode_f64(t, &b).unwrap(), v);
+        }
+
+        assert_eq!(
+            xdr_encode_f64(VarType::Int32, &[31.]).unwrap(),
+            [0u8, 0, 0, 31]
+        );
+        assert!(xdr_decode_f64(VarType::Int32, &[0, 0, 31]).is_err());
+    }
 }
@@ -30,6 +30,7 @@ pub mod das;
 pub mod dds;
 pub mod dods;
 pub mod hyperslab;
+pub mod selection;
 
 pub use constraint::Constraint;
 pub use das::Das;
@@ -86,6 +87,19 @@ pub trait DodsXdr {
         Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>,
         anyhow::Error,
     >;
+
+    /// Stream the rows of a sequence (see [dds::Variable::sequence]), with a value for each of
+    /// the (constrained) members of the variable. Sources that serve sequences must implement
+    /// this.
+    async fn sequence_rows(
+        &self,
+        variable: &dds::DdsVariableDetails,
+    ) -> Result<
+        Pin<Box<dyn Stream<Item = Result<dds::Row, anyhow::Error>> + Send + 'static>>,
+        anyhow::Error,
+    > {
+        Err(anyhow!("{} is not a sequence", variable.name))
+    }
 }
 
 // This implementation assumes that the bytes are provided in native format.
@@ -118,6 +132,16 @@ impl<T: Send + Sync + DodsXdr> DodsXdr for std::sync::Arc<T> {
     > {
         T::variable_xdr(self, variable).await
     }
+
+    async fn sequence_rows(
+        &self,
+        variable: &dds::DdsVariableDetails,
+    ) -> Result<
+        Pin<Box<dyn Stream<Item = Result<dds::Row, anyhow::Error>> + Send + 'static>>,
+        anyhow::Error,
+    > {
+        T::sequence_rows(self, variable).await
+    }
 }
 
 #[async_trait]
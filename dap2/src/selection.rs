@@ -0,0 +1,317 @@
+//! # Selections
+//!
+//! The selection part of a constraint expression follows the projection and consists of clauses
+//! separated by `&`, e.g.: `?buoys.temperature&buoys.time>=100&buoys.lat<60`. Each clause
+//! compares a variable to a value, or a list of values (`{1,2,3}`), with one of the relational
+//! operators: `=`, `!=`, `<`, `<=`, `>`, `>=` and `=~` (regular expression). With a list of
+//! values the clause is true if it is true for any of the values.
+//!
+//! * Selections on the members of a Sequence filter the rows of the sequence (see [RowFilter]).
+//!   Members may be named without the sequence, unless there is a variable with the same name.
+//! * Selections on 1-D variables (e.g. the maps of Grids) constrain the variables with that
+//!   dimension to the range of indices where the selection is true.
+//!
+//! Strings are quoted (`"..."`), unquoted values that are not numbers are also parsed as strings.
+use regex::Regex;
+
+use crate::dds::{DdsVariableDetails, Row, Value, VarType};
+
+/// Relational operator of a selection clause.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    /// Regular expression match of strings.
+    Regex,
+}
+
+/// A selection clause: `variable <operator> values`.
+#[derive(Debug, Clone)]
+pub struct Selection {
+    pub variable: String,
+    pub operator: Operator,
+    pub values: Vec<Value>,
+
+    /// Compiled (and anchored) expressions of [Operator::Regex].
+    regexes: Vec<Regex>,
+}
+
+impl Selection {
+    /// Parse a single selection clause, e.g. `time>=100`.
+    pub fn parse(clause: &str) -> anyhow::Result<Selection> {
+        use Operator::*;
+
+        let i = clause
+            .find(['=', '!', '<', '>'])
+            .ok_or_else(|| anyhow!("no relational operator in selection: {}", clause))?;
+
+        let variable = clause[..i].trim();
+        ensure!(!variable.is_empty(), "no variable in selection: {}", clause);
+
+        let rest = &clause[i..];
+        let (operator, n) = match rest.as_bytes() {
+            [b'!', b'=', ..] => (NotEqual, 2),
+            [b'<', b'=', ..] => (LessEqual, 2),
+            [b'>', b'=', ..] => (GreaterEqual, 2),
+            [b'=', b'~', ..] => (Regex, 2),
+            [b'=', ..] => (Equal, 1),
+            [b'<', ..] => (Less, 1),
+            [b'>', ..] => (Greater, 1),
+            _ => {
+                return Err(anyhow!(
+                    "unknown relational operator in selection: {}",
+                    clause
+                ))
+            }
+        };
+
+        let values = rest[n..].trim();
+        let values = match values.strip_prefix('{').and_then(|v| v.strip_suffix('}')) {
+            Some(list) => split_unquoted(list, ',')
+                .into_iter()
+                .map(parse_value)
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            None => vec![parse_value(values)?],
+        };
+
+        let regexes = if operator == Regex {
+            values
+                .iter()
+                .map(|v| match v {
+                    Value::String(s) => Ok(regex::Regex::new(&format!("^(?:{})$", s))?),
+                    Value::Number(_) => Err(anyhow!("=~ requires a string: {}", clause)),
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Selection {
+            variable: variable.to_string(),
+            operator,
+            values,
+            regexes,
+        })
+    }
+
+    /// Whether `value` satisfies the selection for any of the values of the selection. Numbers
+    /// are only compared to numbers and strings to strings.
+    pub fn matches(&self, value: &Value) -> bool {
+        use std::cmp::Ordering;
+
+        if self.operator == Operator::Regex {
+            return match value {
+                Value::String(s) => self.regexes.iter().any(|r| r.is_match(s)),
+                Value::Number(_) => false,
+            };
+        }
+
+        self.values.iter().any(|v| {
+            let ordering = match (value, v) {
+                (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+                (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+                _ => None,
+            };
+
+            match (self.operator, ordering) {
+                (Operator::Equal, Some(o)) => o == Ordering::Equal,
+                (Operator::NotEqual, Some(o)) => o != Ordering::Equal,
+                (Operator::Less, Some(o)) => o == Ordering::Less,
+                (Operator::LessEqual, Some(o)) => o != Ordering::Greater,
+                (Operator::Greater, Some(o)) => o == Ordering::Greater,
+                (Operator::GreaterEqual, Some(o)) => o != Ordering::Less,
+                _ => false,
+            }
+        })
+    }
+
+    /// Whether the selection is on `member` of the sequence `sequence`, either qualified
+    /// (`sequence.member`) or not.
+    pub fn is_member(&self, sequence: &str, member: &str) -> bool {
+        is_member(&self.variable, sequence, member)
+    }
+}
+
+impl PartialEq for Selection {
+    fn eq(&self, other: &Selection) -> bool {
+        self.variable == other.variable
+            && self.operator == other.operator
+            && self.values == other.values
+    }
+}
+
+pub(crate) fn is_member(variable: &str, sequence: &str, member: &str) -> bool {
+    variable == member
+        || variable
+            .strip_prefix(sequence)
+            .and_then(|m| m.strip_prefix('.'))
+            .is_some_and(|m| m == member)
+}
+
+fn parse_value(value: &str) -> anyhow::Result<Value> {
+    let value = value.trim();
+    ensure!(!value.is_empty(), "missing value in selection");
+
+    Ok(
+        match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(s) => Value::String(s.to_string()),
+            None => match value.parse::<f64>() {
+                Ok(v) => Value::Number(v),
+                Err(_) => Value::String(value.to_string()),
+            },
+        },
+    )
+}
+
+/// Split `s` on `sep`, except inside quoted strings.
+pub(crate) fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+
+    for (i, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+
+    parts
+}
+
+/// Filters the rows of a sequence with the selections on its members. The rows are read from the
+/// source with the members in the selections as well (see [RowFilter::source]), and these are
+/// removed again from the rows that are sent.
+pub struct RowFilter {
+    source: DdsVariableDetails,
+
+    /// Selections and the column of their member in the source rows.
+    selections: Vec<(usize, Selection)>,
+
+    /// Columns of the constrained members in the source rows.
+    projection: Vec<usize>,
+}
+
+impl RowFilter {
+    /// Row filter for the constrained sequence `variable`, with the members `members` of the
+    /// sequence.
+    pub fn new(
+        members: &[(String, VarType)],
+        variable: &DdsVariableDetails,
+        selections: &[Selection],
+    ) -> RowFilter {
+        let selected = |m: &str| selections.iter().any(|s| s.is_member(&variable.name, m));
+
+        let mut source = variable.clone();
+        source.members = members
+            .iter()
+            .filter(|(m, _)| variable.members.iter().any(|(v, _)| v == m) || selected(m))
+            .cloned()
+            .collect();
+
+        let column = |m: &str| source.members.iter().position(|(s, _)| s == m);
+
+        let selections = selections
+            .iter()
+            .filter_map(|s| {
+                source
+                    .members
+                    .iter()
+                    .find(|(m, _)| s.is_member(&variable.name, m))
+                    .and_then(|(m, _)| column(m))
+                    .map(|c| (c, s.clone()))
+            })
+            .collect();
+
+        let projection = variable
+            .members
+            .iter()
+            .filter_map(|(m, _)| column(m))
+            .collect();
+
+        RowFilter {
+            source,
+            selections,
+            projection,
+        }
+    }
+
+    /// The variable to read the rows from: the sequence with the constrained members and the
+    /// members used in the selections.
+    pub fn source(&self) -> &DdsVariableDetails {
+        &self.source
+    }
+
+    /// The constrained members of `row` (read from [RowFilter::source]), or `None` if the row
+    /// does not match the selections.
+    pub fn apply(&self, row: Row) -> Option<Row> {
+        if self.selections.iter().all(|(c, s)| s.matches(&row[*c])) {
+            Some(self.projection.iter().map(|c| row[*c].clone()).collect())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_operators() {
+        for (clause, variable, operator) in [
+            ("time=1", "time", Operator::Equal),
+            ("time!=1", "time", Operator::NotEqual),
+            ("lat<1", "lat", Operator::Less),
+            ("lat<=1", "lat", Operator::LessEqual),
+            ("buoys.lat>1", "buoys.lat", Operator::Greater),
+            ("lat>=1", "lat", Operator::GreaterEqual),
+            ("station=~\"b.*\"", "station", Operator::Regex),
+        ] {
+            let s = Selection::parse(clause).unwrap();
+            assert_eq!(s.variable, variable);
+            assert_eq!(s.operator, operator, "{}", clause);
+        }
+
+        assert!(Selection::parse("time").is_err());
+        assert!(Selection::parse(">1").is_err());
+        assert!(Selection::parse("time!1").is_err());
+        assert!(Selection::parse("time=~1").is_err());
+    }
+
+    #[test]
+    fn parse_values() {
+        let s = Selection::parse("station={1, \"a,b\", c}").unwrap();
+        assert_eq!(
+            s.values,
+            vec![
+                Value::Number(1.),
+                Value::String("a,b".into()),
+                Value::String("c".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn matches() {
+        let s = Selection::parse("lat>=60").unwrap();
+        assert!(s.matches(&Value::Number(60.)));
+        assert!(!s.matches(&Value::Number(59.)));
+        assert!(!s.matches(&Value::String("60".into())));
+
+        let s = Selection::parse("lat={1,3}").unwrap();
+        assert!(s.matches(&Value::Number(3.)));
+        assert!(!s.matches(&Value::Number(2.)));
+
+        let s = Selection::parse("station=~\"buoy[0-9]\"").unwrap();
+        assert!(s.matches(&Value::String("buoy1".into())));
+        assert!(!s.matches(&Value::String("buoy12".into())));
+    }
+}
@@ -74,6 +74,7 @@ fn elements<T: Catalog + Clone>(
 
                     let (folders, mut paths) = catalog
                         .paths()
+                        .into_iter()
                         .filter_map(|p| {
                             if p.starts_with(&tail) {
                                 Some(String::from(&p[tail.len()..]))
@@ -18,7 +18,8 @@ pub async fn index<T: Catalog + Clone>(
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let mut ctx = tera::Context::new();
     ctx.insert("root", &root);
-    ctx.insert("ndatasets", &catalog.paths().count());
+    ctx.insert("ndatasets", &catalog.paths().len());
+    ctx.insert("indexing", &catalog.indexing());
     ctx.insert("title", "");
 
     tera.render("index.html", &ctx)
@@ -38,7 +39,7 @@ pub async fn index<T: Catalog + Clone>(
 pub async fn index_json<T: Catalog + Clone>(
     catalog: T,
 ) -> Result<impl warp::Reply, std::convert::Infallible> {
-    Ok(warp::reply::json(&catalog.paths().collect::<Vec<&str>>()))
+    Ok(warp::reply::json(&catalog.paths()))
 }
 
 pub async fn folder(
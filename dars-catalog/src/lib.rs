@@ -1,5 +1,6 @@
 use lazy_static::lazy_static;
 use rust_embed::RustEmbed;
+use serde::Serialize;
 use std::sync::Arc;
 use tera::Tera;
 use warp::Filter;
@@ -33,14 +34,31 @@ pub fn catalog<T: Catalog + Clone>(
 }
 
 pub trait Catalog: Send + Sync {
-    /// List of all paths to data sources.
-    fn paths<'a>(&'a self) -> Box<dyn Iterator<Item = &str> + 'a>;
+    /// List of all paths to data sources. Data sources may be added or removed while the
+    /// catalog is served.
+    fn paths(&self) -> Vec<String>;
+
+    /// Progress of indexing the data sources, if they are still being indexed.
+    fn indexing(&self) -> Option<Indexing> {
+        None
+    }
+}
+
+/// Number of data sources that are indexed, out of the total.
+#[derive(Debug, Clone, Serialize)]
+pub struct Indexing {
+    pub done: usize,
+    pub total: usize,
 }
 
 impl<T: Catalog> Catalog for Arc<T> {
-    fn paths<'a>(&'a self) -> Box<dyn Iterator<Item = &str> + 'a> {
+    fn paths(&self) -> Vec<String> {
         T::paths(self)
     }
+
+    fn indexing(&self) -> Option<Indexing> {
+        T::indexing(self)
+    }
 }
 
 #[cfg(test)]
@@ -73,8 +91,8 @@ pub(crate) mod tests {
     }
 
     impl Catalog for Arc<TestCatalog> {
-        fn paths<'a>(&'a self) -> Box<dyn Iterator<Item = &str> + 'a> {
-            Box::new(self.paths.iter().map(|s| s.as_str()))
+        fn paths(&self) -> Vec<String> {
+            self.paths.clone()
         }
     }
 
@@ -83,6 +101,31 @@ pub(crate) mod tests {
         catalog("http://localhost:8001".into(), TestCatalog::test()).unwrap();
     }
 
+    #[test]
+    fn index_shows_indexing() {
+        struct Indexing;
+
+        impl Catalog for Indexing {
+            fn paths(&self) -> Vec<String> {
+                vec!["coads1.nc".into()]
+            }
+
+            fn indexing(&self) -> Option<super::Indexing> {
+                Some(super::Indexing { done: 1, total: 3 })
+            }
+        }
+
+        let f = catalog("http://localhost:8001".into(), Arc::new(Indexing)).unwrap();
+        let res = block_on(warp::test::request().method("GET").path("/").reply(&f));
+        let body = String::from_utf8_lossy(res.body());
+
+        assert!(body.contains("Indexing: <strong>1</strong> of <strong>3</strong>"));
+
+        let f = catalog("http://localhost:8001".into(), TestCatalog::test()).unwrap();
+        let res = block_on(warp::test::request().method("GET").path("/").reply(&f));
+        assert!(!String::from_utf8_lossy(res.body()).contains("Indexing"));
+    }
+
     #[test]
     fn does_not_match_data_source() {
         let f = catalog("http://localhost:8001".into(), TestCatalog::test()).unwrap();
@@ -1,5 +1,4 @@
 use futures::executor::{block_on, block_on_stream};
-use std::collections::HashMap;
 use std::sync::Arc;
 
 use divan::Bencher;
@@ -10,45 +9,35 @@ use dars::data::State;
 use dars::data::{DatasetType, Datasets};
 use dars::hdf5::Hdf5Dataset;
 
-fn test_db() -> sled::Db {
-    sled::Config::default()
-        .temporary(true)
-        .print_profile_on_drop(true)
-        .open()
-        .unwrap()
+fn test_db() -> dars::db::Db {
+    dars::db::Db::temporary()
 }
 
 fn temporary() -> Datasets {
-    Datasets {
-        datasets: HashMap::default(),
-        url: None,
-        db: test_db(),
-    }
+    Datasets::new(None, test_db())
 }
 
 fn test_state() -> State {
-    let mut data = temporary();
-    data.datasets.insert(
+    let data = temporary();
+    let coads = Hdf5Dataset::open(
+        "../data/coads_climatology.nc4",
+        "nested/coads_climatology.nc4".into(),
+        &data.db,
+    )
+    .unwrap();
+    data.insert(
         "coads_climatology.nc4".to_string(),
-        Arc::new(DatasetType::HDF5(
-            Hdf5Dataset::open(
-                "../data/coads_climatology.nc4",
-                "nested/coads_climatology.nc4".into(),
-                &data.db,
-            )
-            .unwrap(),
-        )),
+        DatasetType::HDF5(coads),
     );
-    data.datasets.insert(
+    let coads = Hdf5Dataset::open(
+        "../data/coads_climatology.nc4",
+        "nested/coads_climatology.nc4".into(),
+        &data.db,
+    )
+    .unwrap();
+    data.insert(
         "nested/coads_climatology.nc4".to_string(),
-        Arc::new(DatasetType::HDF5(
-            Hdf5Dataset::open(
-                "../data/coads_climatology.nc4",
-                "nested/coads_climatology.nc4".into(),
-                &data.db,
-            )
-            .unwrap(),
-        )),
+        DatasetType::HDF5(coads),
     );
     Arc::new(data)
 }
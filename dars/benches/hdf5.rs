@@ -7,12 +7,8 @@ use dap2::dds::ConstrainedVariable;
 use dap2::DodsXdr;
 use dars::hdf5::Hdf5Dataset;
 
-fn test_db() -> sled::Db {
-    sled::Config::default()
-        .temporary(true)
-        .print_profile_on_drop(true)
-        .open()
-        .unwrap()
+fn test_db() -> dars::db::Db {
+    dars::db::Db::temporary()
 }
 
 #[divan::bench]
@@ -0,0 +1,44 @@
+use futures::StreamExt;
+
+use divan::Bencher;
+
+use dap2::constraint::Constraint;
+use dap2::dds::ConstrainedVariable;
+use dap2::{Dap2, DodsXdr};
+use dars::ncml::NcmlDataset;
+
+fn test_db() -> dars::db::Db {
+    dars::db::Db::temporary()
+}
+
+#[divan::bench(args = [0, 1, 4])]
+fn agg_existing_stream_t(b: Bencher, prefetch: usize) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let db = test_db();
+    let nd = NcmlDataset::open("../data/ncml/aggExisting.ncml", "agg".into(), db)
+        .unwrap()
+        .with_prefetch(prefetch);
+
+    let c = Constraint::parse("T.T").unwrap();
+    let dds = rt.block_on(nd.dds()).dds(&c).unwrap();
+
+    assert_eq!(dds.variables.len(), 1);
+    if let ConstrainedVariable::Structure {
+        variable: _,
+        member,
+    } = &dds.variables[0]
+    {
+        b.bench_local(|| {
+            rt.block_on(async {
+                let reader = nd.variable_xdr(member).await.unwrap();
+                reader.for_each(|b| async move { drop(b.unwrap()) }).await;
+            })
+        });
+    } else {
+        panic!("wrong constrained variable");
+    }
+}
+
+fn main() {
+    divan::main();
+}
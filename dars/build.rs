@@ -0,0 +1,28 @@
+//! Sets `HIDEFIX_VERSION` to the version of hidefix in `Cargo.lock`. The stored indexes are
+//! serialized hidefix indexes, so their format follows the version of hidefix (see `db::FORMAT`).
+use std::path::PathBuf;
+
+fn main() {
+    let manifest = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+
+    // The lock file of the workspace, or of the packaged crate.
+    let lock = [manifest.join("../Cargo.lock"), manifest.join("Cargo.lock")]
+        .into_iter()
+        .find(|p| p.exists())
+        .expect("could not find Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lock.display());
+
+    let lock = std::fs::read_to_string(&lock).unwrap();
+    let version = lock
+        .split("[[package]]")
+        .find_map(|package| {
+            let mut lines = package.lines().map(str::trim);
+            lines.find(|l| *l == "name = \"hidefix\"")?;
+            lines
+                .find_map(|l| l.strip_prefix("version = "))
+                .map(|v| v.trim_matches('"').to_string())
+        })
+        .expect("hidefix is not in Cargo.lock");
+
+    println!("cargo:rustc-env=HIDEFIX_VERSION={}", version);
+}
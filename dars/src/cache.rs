@@ -0,0 +1,191 @@
+//! Size-bounded LRU cache with hit metrics, used for the in-memory caches of indexes (see
+//! [crate::index]) and decompressed chunks (see [crate::chunks]).
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use lru::LruCache;
+use serde::Serialize;
+
+struct Entries<K: Hash + Eq, V> {
+    lru: LruCache<K, (V, usize)>,
+    /// Total size of the entries in bytes.
+    size: usize,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq, V> Entries<K, V> {
+    fn evict(&mut self) -> u64 {
+        let mut evicted = 0;
+
+        while self.size > self.capacity {
+            match self.lru.pop_lru() {
+                Some((_, (_, sz))) => {
+                    self.size -= sz;
+                    evicted += 1;
+                }
+                None => break,
+            }
+        }
+
+        evicted
+    }
+}
+
+/// LRU cache holding up to `capacity` bytes. The size of each entry is given when it is
+/// inserted.
+pub struct SizedLru<K: Hash + Eq, V: Clone> {
+    entries: Mutex<Entries<K, V>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// Cache metrics.
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub hit_rate: f64,
+    pub entries: usize,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+impl<K: Hash + Eq, V: Clone> SizedLru<K, V> {
+    pub fn new(capacity: usize) -> SizedLru<K, V> {
+        SizedLru {
+            entries: Mutex::new(Entries {
+                lru: LruCache::unbounded(),
+                size: 0,
+                capacity,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.lock().unwrap().capacity
+    }
+
+    /// Change the size of the cache, evicting entries if it shrinks.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.capacity = capacity;
+
+        let evicted = entries.evict();
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    /// Look up an entry, counting it as a hit or a miss.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let value = self
+            .entries
+            .lock()
+            .unwrap()
+            .lru
+            .get(key)
+            .map(|(v, _)| v.clone());
+
+        match value {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        value
+    }
+
+    /// Insert an entry of `size` bytes, evicting the least recently used entries to make room
+    /// for it. Entries larger than the cache are not inserted.
+    pub fn insert(&self, key: K, value: V, size: usize) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if size > entries.capacity {
+            return;
+        }
+
+        if let Some((_, (_, sz))) = entries.lru.push(key, (value, size)) {
+            entries.size -= sz;
+        }
+        entries.size += size;
+
+        let evicted = entries.evict();
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    /// Remove the entries for which `f` returns `false`.
+    pub fn retain<F>(&self, f: F)
+    where
+        F: Fn(&K) -> bool,
+        K: Clone,
+    {
+        let mut entries = self.entries.lock().unwrap();
+
+        let keys = entries
+            .lru
+            .iter()
+            .filter(|(k, _)| !f(k))
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+
+        for k in keys {
+            if let Some((_, sz)) = entries.lru.pop(&k) {
+                entries.size -= sz;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+
+        CacheStats {
+            hits,
+            misses,
+            evictions: self.evictions.load(Ordering::Relaxed),
+            hit_rate: if hits + misses > 0 {
+                hits as f64 / (hits + misses) as f64
+            } else {
+                0.
+            },
+            entries: entries.lru.len(),
+            size: entries.size,
+            capacity: entries.capacity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_by_size() {
+        let cache = SizedLru::new(10);
+
+        cache.insert("a", 1, 4);
+        cache.insert("b", 2, 4);
+        assert_eq!(cache.get(&"a"), Some(1));
+
+        // "b" is the least recently used.
+        cache.insert("c", 3, 4);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"c"), Some(3));
+
+        cache.insert("d", 4, 11);
+        assert_eq!(cache.get(&"d"), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 2, 1));
+        assert_eq!((stats.entries, stats.size), (2, 8));
+
+        cache.retain(|k| *k != "a");
+        cache.set_capacity(0);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.size, stats.evictions), (0, 0, 2));
+    }
+}
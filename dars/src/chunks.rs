@@ -0,0 +1,380 @@
+//! In-memory cache of decompressed chunks.
+//!
+//! Popular datasets are often requested over and over for the same slab (e.g. the latest time
+//! step), and every request would otherwise read and decompress the same chunks again. When the
+//! cache is enabled the chunked variables are read one chunk at the time, and the decompressed
+//! (and XDR encoded) chunks are kept in memory. The cache is shared by all datasets and NcML
+//! members, and keyed by file, variable and chunk offset. Like the index cache (see
+//! [crate::index]) the modification time of the file is part of the key.
+//!
+//! The cache is disabled by default, its size is set with `cache_size` (in MiB) in the
+//! `[chunks]` section of `dars.toml`.
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use std::time::SystemTime;
+
+use async_stream::stream;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::SizedLru;
+use crate::index::CachedIndex;
+use dap2::dds::VarType;
+
+/// File (index key and modification time), variable and offset of a chunk.
+pub type ChunkKey = (String, SystemTime, String, Vec<u64>);
+
+static CACHE: LazyLock<SizedLru<ChunkKey, Bytes>> = LazyLock::new(|| SizedLru::new(0));
+
+/// The chunk cache shared by all datasets.
+pub fn cache() -> &'static SizedLru<ChunkKey, Bytes> {
+    &CACHE
+}
+
+pub fn enabled() -> bool {
+    cache().capacity() > 0
+}
+
+/// Drop the cached chunks of the file with index key `idxkey`.
+pub fn remove(idxkey: &str) {
+    cache().retain(|(k, ..)| k != idxkey);
+}
+
+/// Shape and chunk shape of a variable.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Layout {
+    pub shape: Vec<u64>,
+    pub chunk: Vec<u64>,
+}
+
+impl Layout {
+    /// Offset and size of the chunk at `position` in the grid of chunks. Chunks at the edges are
+    /// cut off at the end of the variable.
+    fn extent(&self, position: &[u64]) -> (Vec<u64>, Vec<u64>) {
+        position
+            .iter()
+            .zip(&self.chunk)
+            .zip(&self.shape)
+            .map(|((p, c), s)| (p * c, (*c).min(s - p * c)))
+            .unzip()
+    }
+}
+
+/// The layout of the chunked variables in `file`.
+pub fn layouts(file: &hdf5::File) -> HashMap<String, Layout> {
+    file.member_names()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|m| file.dataset(&m).ok().map(|d| (m, d)))
+        .filter_map(|(m, d)| {
+            let chunk = d.chunk()?;
+            let shape = d.shape();
+
+            (!shape.is_empty() && chunk.len() == shape.len()).then(|| {
+                let layout = Layout {
+                    shape: shape.iter().map(|s| *s as u64).collect(),
+                    chunk: chunk.iter().map(|c| *c as u64).collect(),
+                };
+                (m, layout)
+            })
+        })
+        .collect()
+}
+
+/// A file to read chunks from.
+pub struct Source {
+    pub path: PathBuf,
+    pub idxkey: String,
+    pub modified: SystemTime,
+    pub idx: Arc<CachedIndex>,
+}
+
+impl Source {
+    /// The chunk of `variable` at `position`, from the cache or the file.
+    async fn chunk(
+        &self,
+        variable: &str,
+        dsz: usize,
+        layout: &Layout,
+        position: &[u64],
+    ) -> anyhow::Result<Bytes> {
+        let (offset, size) = layout.extent(position);
+        let key = (
+            self.idxkey.clone(),
+            self.modified,
+            variable.to_string(),
+            offset.clone(),
+        );
+
+        if let Some(bytes) = cache().get(&key) {
+            return Ok(bytes);
+        }
+
+        trace!(
+            "Reading chunk {:?} of {} from {:?}",
+            offset,
+            variable,
+            self.path
+        );
+
+        let bytes = {
+            let reader = match self.idx.index().dataset(variable) {
+                Some(ds) => ds.as_streamer(&self.path),
+                None => Err(anyhow!("dataset does not exist")),
+            }?;
+
+            reader.stream_xdr(&crate::make_extents((offset.as_slice(), size.as_slice()))?)
+        };
+        let bytes: Vec<Bytes> = bytes.try_collect().await?;
+        let bytes = Bytes::from(bytes.concat());
+
+        ensure!(
+            bytes.len() == size.iter().product::<u64>() as usize * dsz,
+            "unexpected size of chunk {:?} of {}",
+            offset,
+            variable
+        );
+
+        cache().insert(key, bytes.clone(), bytes.len());
+
+        Ok(bytes)
+    }
+}
+
+/// Stream a slab of `variable` through the chunk cache. The chunks are read one row (along the
+/// first dimension) at the time, and the part of the row that overlaps the slab is yielded.
+pub fn stream_xdr(
+    source: Source,
+    variable: &str,
+    vartype: VarType,
+    layout: &Layout,
+    indices: &[u64],
+    counts: &[u64],
+) -> anyhow::Result<BoxStream<'static, Result<Bytes, anyhow::Error>>> {
+    ensure!(
+        indices.len() == layout.shape.len() && counts.len() == layout.shape.len(),
+        "slab does not match the dimensions of {}",
+        variable
+    );
+    ensure!(
+        indices
+            .iter()
+            .zip(counts)
+            .zip(&layout.shape)
+            .all(|((i, c), s)| i + c <= *s),
+        "slab out of range"
+    );
+
+    let dsz = vartype.xdr_size();
+    let variable = variable.to_string();
+    let layout = layout.clone();
+    let slab = Slab {
+        indices: indices.to_vec(),
+        counts: counts.to_vec(),
+    };
+
+    // The positions of the chunks that overlap the slab along each dimension.
+    let grid = slab
+        .indices
+        .iter()
+        .zip(&slab.counts)
+        .zip(&layout.chunk)
+        .map(|((i, n), c)| {
+            if *n == 0 {
+                0..0
+            } else {
+                i / c..(i + n).div_ceil(*c)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(stream! {
+        for row in grid[0].clone() {
+            let mut chunks = Vec::new();
+
+            let ranges = [&[row..row + 1][..], &grid[1..]].concat();
+
+            for position in positions(&ranges) {
+                match source.chunk(&variable, dsz, &layout, &position).await {
+                    Ok(bytes) => chunks.push((position, bytes)),
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                }
+            }
+
+            let c = layout.chunk[0];
+            let start = slab.indices[0].max(row * c);
+            let end = (slab.indices[0] + slab.counts[0]).min((row + 1) * c);
+
+            yield Ok(slab.assemble(&layout, dsz, start..end, &chunks));
+        }
+    }
+    .boxed())
+}
+
+/// All positions in the ranges, in row-major order.
+fn positions(ranges: &[Range<u64>]) -> Vec<Vec<u64>> {
+    match ranges {
+        [] => vec![vec![]],
+        [r] => r.clone().map(|p| vec![p]).collect(),
+        _ => ranges.iter().cloned().multi_cartesian_product().collect(),
+    }
+}
+
+/// Offset of `p` in an array with shape `shape` starting at `start`.
+fn offset(p: &[u64], start: &[u64], shape: &[u64]) -> usize {
+    p.iter()
+        .zip(start)
+        .zip(shape)
+        .fold(0, |o, ((p, s), n)| o * n + (p - s)) as usize
+}
+
+struct Slab {
+    indices: Vec<u64>,
+    counts: Vec<u64>,
+}
+
+impl Slab {
+    /// Copy the parts of the `chunks` that overlap the slab in the `rows` along the first
+    /// dimension.
+    fn assemble(
+        &self,
+        layout: &Layout,
+        dsz: usize,
+        rows: Range<u64>,
+        chunks: &[(Vec<u64>, Bytes)],
+    ) -> Bytes {
+        let start = [&[rows.start][..], &self.indices[1..]].concat();
+        let shape = [&[rows.end - rows.start][..], &self.counts[1..]].concat();
+        let last = shape.len() - 1;
+
+        let mut out = vec![0u8; shape.iter().product::<u64>() as usize * dsz];
+
+        for (position, bytes) in chunks {
+            let (coffset, csize) = layout.extent(position);
+
+            // The part of the chunk that overlaps the output.
+            let overlap = (0..shape.len())
+                .map(|d| start[d].max(coffset[d])..(start[d] + shape[d]).min(coffset[d] + csize[d]))
+                .collect::<Vec<_>>();
+
+            if overlap.iter().any(|r| r.is_empty()) {
+                continue;
+            }
+
+            // Copy contiguous runs along the last dimension.
+            let run = (overlap[last].end - overlap[last].start) as usize * dsz;
+
+            for mut p in positions(&overlap[..last]) {
+                p.push(overlap[last].start);
+
+                let src = offset(&p, &coffset, &csize) * dsz;
+                let dst = offset(&p, &start, &shape) * dsz;
+                out[dst..dst + run].copy_from_slice(&bytes[src..src + run]);
+            }
+        }
+
+        out.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::test_db;
+
+    #[test]
+    fn assemble_slab() {
+        // A 4 x 5 variable with 3 x 2 chunks, each element is its own offset.
+        let layout = Layout {
+            shape: vec![4, 5],
+            chunk: vec![3, 2],
+        };
+        let chunk = |position: Vec<u64>| {
+            let (o, s) = layout.extent(&position);
+            let bytes = positions(&[o[0]..o[0] + s[0], o[1]..o[1] + s[1]])
+                .into_iter()
+                .map(|p| (p[0] * 5 + p[1]) as u8)
+                .collect::<Vec<_>>();
+            (position, Bytes::from(bytes))
+        };
+
+        let slab = Slab {
+            indices: vec![1, 1],
+            counts: vec![3, 4],
+        };
+
+        let first = slab.assemble(
+            &layout,
+            1,
+            1..3,
+            &[chunk(vec![0, 0]), chunk(vec![0, 1]), chunk(vec![0, 2])],
+        );
+        let second = slab.assemble(
+            &layout,
+            1,
+            3..4,
+            &[chunk(vec![1, 0]), chunk(vec![1, 1]), chunk(vec![1, 2])],
+        );
+
+        assert_eq!(
+            [first, second].concat(),
+            [6, 7, 8, 9, 11, 12, 13, 14, 16, 17, 18, 19]
+        );
+    }
+
+    #[tokio::test]
+    async fn same_as_uncached() {
+        let db = test_db();
+        let path = std::fs::canonicalize("../data/coads_climatology.nc4").unwrap();
+        crate::hdf5::Hdf5Dataset::open(&path, "coads".into(), &db).unwrap();
+
+        let idxkey = path.to_string_lossy().to_string();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let idx = crate::index::cache()
+            .get(&db, &idxkey, modified, &path)
+            .await
+            .unwrap();
+
+        let (indices, counts) = ([1, 10, 20], [3, 50, 100]);
+
+        let reader = idx
+            .index()
+            .dataset("SST")
+            .unwrap()
+            .as_streamer(&path)
+            .unwrap();
+        let expected: Vec<Bytes> = reader
+            .stream_xdr(&crate::make_extents((&indices[..], &counts[..])).unwrap())
+            .try_collect()
+            .await
+            .unwrap();
+
+        // Any chunking of the variable gives the same result.
+        let layout = Layout {
+            shape: vec![12, 90, 180],
+            chunk: vec![2, 40, 70],
+        };
+        let source = Source {
+            path: path.clone(),
+            idxkey,
+            modified,
+            idx,
+        };
+        let bytes: Vec<Bytes> =
+            stream_xdr(source, "SST", VarType::Float32, &layout, &indices, &counts)
+                .unwrap()
+                .try_collect()
+                .await
+                .unwrap();
+
+        assert_eq!(bytes.concat(), expected.concat());
+    }
+}
@@ -1,6 +1,7 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use getopts::Options;
 use serde::Deserialize;
@@ -11,11 +12,133 @@ pub struct Config {
     pub data: PathBuf,
     pub address: SocketAddr,
     pub root_url: Option<String>,
+    #[serde(default)]
+    pub ncml: Ncml,
+    #[serde(default)]
+    pub watch: Watch,
+    #[serde(default)]
+    pub admin: Admin,
+    #[serde(default)]
+    pub index: Index,
+    #[serde(default)]
+    pub chunks: Chunks,
+    #[serde(default)]
+    pub dds: Dds,
 }
 
+/// Settings for the db and the storage of the indexes (see [crate::db]).
 #[derive(Debug, Deserialize)]
 pub struct Db {
     pub path: PathBuf,
+    /// Where the indexes are stored.
+    #[serde(default)]
+    pub store: Store,
+    /// Directory mirroring the data directory with the sidecar files, instead of next to the
+    /// data files.
+    #[serde(default)]
+    pub sidecar_dir: Option<PathBuf>,
+    /// Write sidecar files of files that are not indexed, instead of keeping the indexes in
+    /// memory.
+    #[serde(default)]
+    pub sidecar_writable: bool,
+    /// Store a hash of the content of each file with its index. `dars index --check` then also
+    /// fails for files where the content has changed without changing the size or modification
+    /// time. The server only compares the size and modification time when loading.
+    #[serde(default)]
+    pub hash: bool,
+    /// Replace the sled db with an empty db if it is corrupt, instead of failing to start.
+    #[serde(default)]
+    pub rebuild: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Store {
+    /// In the sled db.
+    #[default]
+    Sled,
+    /// In `.idx` files next to the data files.
+    Sidecar,
+    /// Only in memory.
+    Memory,
+}
+
+/// Settings for NcML aggregations.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Ncml {
+    /// Number of members to read ahead concurrently when streaming aggregated variables (`0`
+    /// reads one member at the time).
+    pub prefetch: usize,
+}
+
+/// Settings for watching the data directory for new and removed datasets.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Watch {
+    pub enabled: bool,
+    /// Poll the data directory instead of using file system notifications (e.g. on network file
+    /// systems where notifications are not available).
+    pub poll: bool,
+    /// Interval between polls in seconds.
+    pub poll_interval: u64,
+}
+
+/// Settings for the indexes of the files.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Index {
+    /// Size of the in-memory cache of deserialized indexes in MiB.
+    pub cache_size: usize,
+    /// Number of files to load and index at the same time when starting.
+    pub workers: usize,
+}
+
+/// Settings for the cache of decompressed chunks.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Chunks {
+    /// Size of the cache in MiB, `0` disables it.
+    pub cache_size: usize,
+}
+
+/// Settings for the DDS of the datasets.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Dds {
+    /// Add `Int32` index coordinate variables for dimensions without coordinate variables, so
+    /// that the variables with these dimensions are served as grids.
+    pub index_coordinates: bool,
+    /// Serve CF packed variables (with `scale_factor` or `add_offset`) unpacked, as `Float32` or
+    /// `Float64` with the fill values as `NaN`, see [crate::unpack].
+    pub unpack: bool,
+    /// Override `unpack` for the datasets under these paths, relative to the data directory
+    /// (e.g. `"satellite" = true` or `"model/run.nc4" = false`). The longest matching path
+    /// applies.
+    pub unpack_paths: BTreeMap<String, bool>,
+}
+
+impl Dds {
+    /// Whether the dataset with the key `key` (its path relative to the data directory) is
+    /// served unpacked, see [Dds::unpack_paths].
+    pub fn unpack_for(&self, key: &str) -> bool {
+        self.unpack_paths
+            .iter()
+            .filter(|(path, _)| Path::new(key).starts_with(path))
+            .max_by_key(|(path, _)| Path::new(path).components().count())
+            .map_or(self.unpack, |(_, unpack)| *unpack)
+    }
+}
+
+/// Settings for the admin API. The API is disabled unless a token or an address is set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Admin {
+    /// Requests must have the header `Authorization: Bearer <token>`.
+    pub token: Option<String>,
+    /// Serve the API on a separate address (e.g. one that is only reachable internally) instead
+    /// of under `/admin` on the main address.
+    pub address: Option<SocketAddr>,
 }
 
 impl Default for Config {
@@ -25,6 +148,12 @@ impl Default for Config {
             data: "data/".into(),
             address: "127.0.0.1:8001".parse().unwrap(),
             root_url: None,
+            ncml: Ncml::default(),
+            watch: Watch::default(),
+            admin: Admin::default(),
+            index: Index::default(),
+            chunks: Chunks::default(),
+            dds: Dds::default(),
         }
     }
 }
@@ -33,14 +162,63 @@ impl Default for Db {
     fn default() -> Self {
         Db {
             path: "./dars.db".into(),
+            store: Store::default(),
+            sidecar_dir: None,
+            sidecar_writable: false,
+            hash: false,
+            rebuild: false,
+        }
+    }
+}
+
+impl Default for Ncml {
+    fn default() -> Self {
+        Ncml { prefetch: 4 }
+    }
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Index {
+            cache_size: 256,
+            workers: num_cpus::get(),
         }
     }
 }
 
-pub fn load_config_with_args() -> anyhow::Result<Config> {
-    let args: Vec<String> = env::args().collect();
+impl Default for Watch {
+    fn default() -> Self {
+        Watch {
+            enabled: true,
+            poll: false,
+            poll_interval: 30,
+        }
+    }
+}
+
+/// What to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Start the server.
+    Serve,
+    /// Index the files at `paths` and exit (`dars index`), see [crate::indexer].
+    Index {
+        paths: Vec<PathBuf>,
+        check: bool,
+        /// Remove the indexes of files that no longer exist first.
+        gc: bool,
+    },
+}
+
+pub fn load_config_with_args() -> anyhow::Result<(Config, Command)> {
+    let mut args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
+    let index = args.get(1).is_some_and(|a| a == "index");
+    if index {
+        args.remove(1);
+    }
+
     let mut opts = Options::new();
     opts.optopt(
         "c",
@@ -60,6 +238,14 @@ pub fn load_config_with_args() -> anyhow::Result<Config> {
         "root URL of service (default: empty)",
         "ROOT",
     );
+    if index {
+        opts.optflag(
+            "",
+            "check",
+            "only check that the files are indexed, without indexing them",
+        );
+        opts.optflag("", "gc", "remove the indexes of files that no longer exist");
+    }
     opts.optflag("h", "help", "print this help");
 
     let matches = match opts.parse(&args[1..]) {
@@ -67,8 +253,27 @@ pub fn load_config_with_args() -> anyhow::Result<Config> {
         Err(f) => panic!("{}", f.to_string()),
     };
 
+    if matches.opt_present("h") && index {
+        let brief = format!("Usage: {} index [options] [PATH..]", program);
+        print!("{}", opts.usage(&brief));
+        println!(
+            r#"
+Index the supported files at PATH (directories are searched for files)
+and store the indexes in the configured index store, without starting
+the server. The members of NcML aggregations are indexed as well.
+
+With --check nothing is written, and the files that are not indexed are
+reported. The exit code is non-zero if any file failed. With --gc the
+indexes of files that no longer exist are removed, PATH may be left out.
+
+The sled db can not be opened while the server is running, use the
+sidecar store to index files for a running server."#
+        );
+        return Err(anyhow!("argument help"));
+    }
+
     if matches.opt_present("h") {
-        let brief = format!("Usage: {} [options] [data..]", program);
+        let brief = format!("Usage: {} [index] [options] [data..]", program);
         print!("{}", opts.usage(&brief));
         println!(
             r#"
@@ -95,9 +300,22 @@ If no DATA is specified, "data/" is used."#
         Config::default()
     };
 
-    // Override configuration options with arguments
-    if !matches.free.is_empty() {
-        config.data = matches.free[0].clone().into();
+    let command = if index {
+        let gc = matches.opt_present("gc");
+        ensure!(gc || !matches.free.is_empty(), "no files to index");
+
+        Command::Index {
+            paths: matches.free.iter().map(PathBuf::from).collect(),
+            check: matches.opt_present("check"),
+            gc,
+        }
+    } else {
+        // Override configuration options with arguments
+        if !matches.free.is_empty() {
+            config.data = matches.free[0].clone().into();
+        };
+
+        Command::Serve
     };
 
     matches
@@ -109,5 +327,30 @@ If no DATA is specified, "data/" is used."#
         .iter()
         .for_each(|r| config.root_url = Some((*r).clone()));
 
-    Ok(config)
+    Ok((config, command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpack_paths() {
+        let dds: Dds = toml::from_str(
+            r#"
+            unpack = true
+
+            [unpack_paths]
+            "model" = false
+            "model/packed" = true
+            "#,
+        )
+        .unwrap();
+
+        assert!(dds.unpack_for("sat/coads.nc4"));
+        assert!(!dds.unpack_for("model/run.nc4"));
+        assert!(dds.unpack_for("model/packed/run.nc4"));
+        assert!(!dds.unpack_for("model/packed.nc4"));
+        assert!(dds.unpack_for("models/run.nc4"));
+    }
 }
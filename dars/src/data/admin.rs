@@ -0,0 +1,503 @@
+//! Admin API for managing the datasets while the server is running.
+//!
+//! * `GET /admin/datasets`: the loaded datasets, the files that could not be loaded with their
+//!   errors, and the progress of loading the data directory.
+//! * `GET /admin/metrics`: hit rates and sizes of the index and chunk caches (see [crate::index]
+//!   and [crate::chunks]).
+//! * `POST /admin/rescan?path=<dir>`: scan the data directory (or a directory below it) for new
+//!   datasets, drop datasets whose files have been removed and retry files that failed to load.
+//! * `POST /admin/reindex?path=<file>`: index a file again, replacing its stored index (or the
+//!   cached aggregation of an NcML file), and re-load the datasets served from it.
+//! * `POST /admin/unload?key=<key>`: stop serving a dataset (and the datasets below it). It is
+//!   loaded again by a rescan.
+//! * `POST /admin/gc`: remove the stored indexes of files that no longer exist (see
+//!   [crate::db::Db::gc]).
+//!
+//! Paths are relative to the data directory. The API is configured in the `[admin]` section of
+//! `dars.toml`: if a `token` is set requests must have the header `Authorization: Bearer
+//! <token>`, if an `address` is set the API is served there instead of on the main address.
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use super::dataset::{is_hidden, is_supported, scan};
+use super::{Progress, State};
+use crate::cache::CacheStats;
+use crate::{chunks, config, hdf5, index, ncml};
+
+struct Admin {
+    state: State,
+    /// The data directory (canonical).
+    root: PathBuf,
+    ncml_config: config::Ncml,
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Deserialize)]
+struct PathQuery {
+    #[serde(default)]
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct KeyQuery {
+    key: String,
+}
+
+#[derive(Serialize)]
+struct DatasetList {
+    datasets: Vec<String>,
+    errors: BTreeMap<String, String>,
+    loading: Progress,
+}
+
+#[derive(Serialize)]
+struct Metrics {
+    index_cache: CacheStats,
+    chunk_cache: CacheStats,
+}
+
+#[derive(Serialize, Default)]
+struct Changes {
+    added: Vec<String>,
+    removed: Vec<String>,
+    errors: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct Collected {
+    removed: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Error {
+    error: String,
+}
+
+/// The admin API for the datasets in `datadir`. If `token` is set the requests must be
+/// authorized with it.
+pub fn admin(
+    state: State,
+    datadir: &Path,
+    ncml_config: config::Ncml,
+    token: Option<String>,
+) -> anyhow::Result<impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone> {
+    let admin = Arc::new(Admin {
+        state,
+        root: std::fs::canonicalize(datadir)?,
+        ncml_config,
+    });
+
+    let datasets = warp::path!("datasets")
+        .and(warp::get())
+        .and(with_admin(admin.clone()))
+        .and_then(list);
+
+    let metrics = warp::path!("metrics").and(warp::get()).and_then(metrics);
+
+    let rescan = warp::path!("rescan")
+        .and(warp::post())
+        .and(with_admin(admin.clone()))
+        .and(warp::query::<PathQuery>())
+        .and_then(rescan);
+
+    let reindex = warp::path!("reindex")
+        .and(warp::post())
+        .and(with_admin(admin.clone()))
+        .and(warp::query::<PathQuery>())
+        .and_then(reindex);
+
+    let unload = warp::path!("unload")
+        .and(warp::post())
+        .and(with_admin(admin.clone()))
+        .and(warp::query::<KeyQuery>())
+        .and_then(unload);
+
+    let gc = warp::path!("gc")
+        .and(warp::post())
+        .and(with_admin(admin))
+        .and_then(gc);
+
+    Ok(warp::path("admin")
+        .and(authorized(token.map(Arc::from)))
+        .and(
+            datasets
+                .or(metrics)
+                .or(rescan)
+                .or(reindex)
+                .or(unload)
+                .or(gc),
+        )
+        .recover(unauthorized))
+}
+
+fn with_admin(
+    admin: Arc<Admin>,
+) -> impl Filter<Extract = (Arc<Admin>,), Error = Infallible> + Clone {
+    warp::any().map(move || Arc::clone(&admin))
+}
+
+fn authorized(
+    token: Option<Arc<str>>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = token.clone();
+
+            async move {
+                let Some(token) = token else {
+                    return Ok(());
+                };
+
+                match header.as_deref().and_then(|h| h.strip_prefix("Bearer ")) {
+                    Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => Ok(()),
+                    _ => {
+                        warn!("Unauthorized admin request.");
+                        Err(warp::reject::custom(Unauthorized))
+                    }
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn unauthorized(r: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    if r.find::<Unauthorized>().is_some() {
+        Ok(warp::reply::with_header(
+            StatusCode::UNAUTHORIZED,
+            "WWW-Authenticate",
+            "Bearer",
+        ))
+    } else {
+        Err(r)
+    }
+}
+
+/// Compare without returning early, so that the time taken does not reveal how much of the
+/// token is right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn error(status: StatusCode, e: impl ToString) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&Error {
+            error: e.to_string(),
+        }),
+        status,
+    )
+    .into_response()
+}
+
+async fn list(admin: Arc<Admin>) -> Result<Response, Infallible> {
+    Ok(warp::reply::json(&DatasetList {
+        datasets: admin.state.keys(),
+        errors: admin.state.errors(),
+        loading: admin.state.progress(),
+    })
+    .into_response())
+}
+
+async fn metrics() -> Result<Response, Infallible> {
+    Ok(warp::reply::json(&Metrics {
+        index_cache: index::cache().stats(),
+        chunk_cache: chunks::cache().stats(),
+    })
+    .into_response())
+}
+
+async fn rescan(admin: Arc<Admin>, query: PathQuery) -> Result<Response, Infallible> {
+    let (dir, key) = match admin.resolve(&query.path) {
+        Ok(p) if p.0.is_dir() => p,
+        Ok(_) => return Ok(error(StatusCode::NOT_FOUND, "no such directory")),
+        Err(e) => return Ok(error(StatusCode::BAD_REQUEST, e)),
+    };
+
+    let changes = tokio::task::spawn_blocking(move || admin.rescan(&dir, &key)).await;
+
+    Ok(match changes {
+        Ok(changes) => warp::reply::json(&changes).into_response(),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    })
+}
+
+async fn reindex(admin: Arc<Admin>, query: PathQuery) -> Result<Response, Infallible> {
+    let (path, key) = match admin.resolve(&query.path) {
+        Ok(p) if p.0.is_file() && is_supported(&p.0) => p,
+        Ok(_) => return Ok(error(StatusCode::NOT_FOUND, "no such dataset file")),
+        Err(e) => return Ok(error(StatusCode::BAD_REQUEST, e)),
+    };
+
+    let changes = tokio::task::spawn_blocking(move || admin.reindex(&path, key)).await;
+
+    Ok(match changes {
+        Ok(Ok(changes)) => warp::reply::json(&changes).into_response(),
+        Ok(Err(e)) => error(StatusCode::UNPROCESSABLE_ENTITY, e),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    })
+}
+
+async fn unload(admin: Arc<Admin>, query: KeyQuery) -> Result<Response, Infallible> {
+    let removed = admin.state.remove_under(&query.key);
+
+    if removed.is_empty() {
+        Ok(error(StatusCode::NOT_FOUND, "no such dataset"))
+    } else {
+        info!("Unloaded datasets: {}", removed.join(", ").yellow());
+
+        Ok(warp::reply::json(&Changes {
+            removed,
+            ..Changes::default()
+        })
+        .into_response())
+    }
+}
+
+async fn gc(admin: Arc<Admin>) -> Result<Response, Infallible> {
+    let db = admin.state.db.clone();
+    let removed = tokio::task::spawn_blocking(move || db.gc()).await;
+
+    Ok(match removed {
+        Ok(Ok(removed)) => warp::reply::json(&Collected { removed }).into_response(),
+        Ok(Err(e)) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    })
+}
+
+impl Admin {
+    /// The absolute path and key of `path` (relative to the data directory). Paths outside the
+    /// data directory and hidden files are rejected.
+    fn resolve(&self, path: &str) -> anyhow::Result<(PathBuf, String)> {
+        let path = Path::new(path.trim_matches('/'));
+
+        ensure!(
+            path.components().all(|c| matches!(c, Component::Normal(_))),
+            "path must be relative to the data directory"
+        );
+        ensure!(
+            !path.components().any(|c| is_hidden(Path::new(&c))),
+            "path is hidden"
+        );
+
+        Ok((self.root.join(path), path.to_string_lossy().to_string()))
+    }
+
+    /// Drop the datasets below `key` whose files no longer exist, and load the files in `dir`
+    /// that are not loaded.
+    fn rescan(&self, dir: &Path, key: &str) -> Changes {
+        let state = &self.state;
+        let mut changes = Changes::default();
+
+        let under = |k: &str| key.is_empty() || k == key || k.starts_with(&format!("{}/", key));
+
+        // A file may be served as several datasets below its key (e.g. a forecast model run
+        // collection), the dataset is kept as long as one of its parents is a file.
+        let exists = |k: &str| {
+            Path::new(k)
+                .ancestors()
+                .any(|a| !a.as_os_str().is_empty() && self.root.join(a).is_file())
+        };
+
+        let stale = state
+            .keys()
+            .into_iter()
+            .chain(state.errors().into_keys())
+            .filter(|k| under(k) && !exists(k))
+            .collect::<Vec<_>>();
+
+        for k in stale {
+            changes.removed.extend(state.remove_under(&k));
+        }
+
+        let errors = state.errors();
+
+        for path in scan(dir) {
+            let key = match path.strip_prefix(&self.root) {
+                Ok(key) => key.to_string_lossy().to_string(),
+                Err(_) => continue,
+            };
+
+            if state.is_loading(&key)
+                || (!state.keys_under(&key).is_empty() && !errors.contains_key(&key))
+            {
+                continue;
+            }
+
+            changes
+                .added
+                .extend(state.load(&path, key.clone(), &self.ncml_config));
+        }
+
+        changes.errors = state
+            .errors()
+            .into_iter()
+            .filter(|(k, _)| under(k))
+            .collect();
+
+        if !changes.added.is_empty() || !changes.removed.is_empty() {
+            info!(
+                "Rescanned {}: added {} and removed {} datasets.",
+                dir.to_string_lossy().yellow(),
+                changes.added.len(),
+                changes.removed.len()
+            );
+        }
+
+        changes
+    }
+
+    /// Index the file at `path` again, and re-load the datasets served from it.
+    fn reindex(&self, path: &Path, key: String) -> anyhow::Result<Changes> {
+        info!("Re-indexing {}..", path.to_string_lossy().yellow());
+
+        if ncml::is_ncml(path) {
+            self.state.db.sled().remove(ncml::cache_key(path)?)?;
+        } else {
+            hdf5::Hdf5Dataset::reindex(path, &self.state.db)?;
+        }
+
+        let mut changes = Changes::default();
+
+        let served = !self.state.keys_under(&key).is_empty();
+        if served || self.state.errors().contains_key(&key) {
+            changes.added = self.state.load(path, key.clone(), &self.ncml_config);
+
+            if let Some(e) = self.state.errors().remove(&key) {
+                changes.errors.insert(key, e);
+            }
+        }
+
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Datasets;
+
+    fn setup() -> (State, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("sub")).unwrap();
+        std::fs::copy("../data/ncml/jan.nc4", dir.path().join("sub/jan.nc4")).unwrap();
+        std::fs::write(dir.path().join("broken.nc4"), "not hdf5").unwrap();
+
+        (Arc::new(Datasets::temporary()), dir)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn authorization() {
+        let (state, dir) = setup();
+        let admin = admin(
+            state,
+            dir.path(),
+            config::Ncml::default(),
+            Some("secret".into()),
+        )
+        .unwrap();
+
+        let res = warp::test::request()
+            .path("/admin/datasets")
+            .reply(&admin)
+            .await;
+        assert_eq!(res.status(), 401);
+
+        let res = warp::test::request()
+            .path("/admin/datasets")
+            .header("Authorization", "Bearer wrong")
+            .reply(&admin)
+            .await;
+        assert_eq!(res.status(), 401);
+
+        let res = warp::test::request()
+            .path("/admin/datasets")
+            .header("Authorization", "Bearer secret")
+            .reply(&admin)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let res = warp::test::request()
+            .path("/admin/metrics")
+            .header("Authorization", "Bearer secret")
+            .reply(&admin)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        assert!(
+            !warp::test::request()
+                .path("/data/coads_climatology.nc4.das")
+                .matches(&admin)
+                .await
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rescan_reindex_unload() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let (state, dir) = setup();
+        let admin = admin(state.clone(), dir.path(), config::Ncml::default(), None).unwrap();
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/rescan")
+            .reply(&admin)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(state.keys(), ["sub/jan.nc4"]);
+        assert!(state.errors().contains_key("broken.nc4"));
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/reindex?path=sub/jan.nc4")
+            .reply(&admin)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(state.keys(), ["sub/jan.nc4"]);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/reindex?path=../jan.nc4")
+            .reply(&admin)
+            .await;
+        assert_eq!(res.status(), 400);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/unload?key=sub")
+            .reply(&admin)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert!(state.keys().is_empty());
+
+        std::fs::remove_file(dir.path().join("broken.nc4")).unwrap();
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/rescan?path=")
+            .reply(&admin)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(state.keys(), ["sub/jan.nc4"]);
+        assert!(state.errors().is_empty());
+
+        std::fs::remove_dir_all(dir.path()).unwrap();
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/gc")
+            .reply(&admin)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert!(std::str::from_utf8(res.body()).unwrap().contains("jan.nc4"));
+    }
+}
@@ -1,138 +1,700 @@
 use std::borrow::Borrow;
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use colored::Colorize;
 use futures::Stream;
+use serde::Serialize;
 use std::pin::Pin;
 use walkdir::WalkDir;
 
-use crate::{hdf5, ncml};
+use crate::db::Db;
+use crate::unpack::Packed;
+use crate::{config, hdf5, ncml};
 use dap2::das::Das;
 use dap2::dds::{self, Dds};
 use dap2::Dap2;
 
-/// The map of datasets.
+/// The map of datasets. Datasets may be added and removed while the server is running (see
+/// [super::watch] and [super::admin]).
 pub struct Datasets {
-    pub datasets: HashMap<String, Arc<DatasetType>>,
+    pub datasets: RwLock<HashMap<String, Arc<DatasetSlot>>>,
+    /// Errors of files that could not be loaded.
+    errors: RwLock<HashMap<String, String>>,
+    /// Files that are waiting to be loaded in the background.
+    loading: RwLock<HashSet<String>>,
+    progress: RwLock<Progress>,
     pub url: Option<String>,
-    pub db: sled::Db,
+    pub db: Db,
+    /// Settings for the DDS of the loaded datasets.
+    dds: config::Dds,
 }
 
 #[cfg(feature = "catalog")]
 impl dars_catalog::Catalog for Datasets {
-    fn paths<'a>(&'a self) -> Box<dyn Iterator<Item = &str> + 'a> {
-        Box::new(self.datasets.keys().map(|s| s.as_str()))
+    fn paths(&self) -> Vec<String> {
+        self.keys()
+    }
+
+    fn indexing(&self) -> Option<dars_catalog::Indexing> {
+        let progress = self.progress();
+
+        (progress.done < progress.total).then_some(dars_catalog::Indexing {
+            done: progress.done,
+            total: progress.total,
+        })
+    }
+}
+
+/// A file queued by [Datasets::spawn_load]. It is marked as loaded when dropped, also if loading
+/// it panics, so that requests for it are not answered with `503` forever.
+struct Loading {
+    state: Arc<Datasets>,
+    key: String,
+}
+
+impl Drop for Loading {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.state.panicked(&self.key);
+        }
+
+        self.state.loaded(&self.key);
+    }
+}
+
+/// Re-creates a dataset from its files.
+type Loader = Arc<dyn Fn() -> anyhow::Result<DatasetType> + Send + Sync>;
+
+/// How long to wait before trying to re-load a changed dataset again after a failure.
+const RELOAD_RETRY: Duration = Duration::from_secs(10);
+
+/// A dataset which can be replaced while the server is running. Requests hold on to the
+/// snapshot of the dataset they started with.
+///
+/// If the slot has a loader the dataset is re-loaded (and its changed files re-indexed) when a
+/// request finds that its files have changed on disk, see [DatasetSlot::current]. Requests that
+/// are already streaming keep the DAS, DDS and index of their snapshot.
+pub struct DatasetSlot {
+    dataset: RwLock<Arc<DatasetType>>,
+    loader: Option<Loader>,
+    /// Held while re-loading, with the time of the last failed attempt.
+    reloading: tokio::sync::Mutex<Option<Instant>>,
+}
+
+impl DatasetSlot {
+    pub fn new(dataset: DatasetType) -> DatasetSlot {
+        DatasetSlot {
+            dataset: RwLock::new(Arc::new(dataset)),
+            loader: None,
+            reloading: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// A slot that re-loads the dataset with `loader` when its files change.
+    pub fn with_loader<F>(dataset: DatasetType, loader: F) -> DatasetSlot
+    where
+        F: Fn() -> anyhow::Result<DatasetType> + Send + Sync + 'static,
+    {
+        DatasetSlot {
+            loader: Some(Arc::new(loader)),
+            ..DatasetSlot::new(dataset)
+        }
+    }
+
+    /// The current snapshot of the dataset.
+    pub fn get(&self) -> Arc<DatasetType> {
+        Arc::clone(&self.dataset.read().unwrap())
+    }
+
+    /// Atomically replace the dataset.
+    pub fn swap(&self, dataset: DatasetType) {
+        *self.dataset.write().unwrap() = Arc::new(dataset);
+    }
+
+    /// The current snapshot of the dataset, re-loading it first if it has changed on disk. Fails
+    /// if the dataset has changed and can not be re-loaded, the old snapshot can not be read from
+    /// the changed files. A failed re-load is tried again after [RELOAD_RETRY].
+    ///
+    /// Checking for changes requires the metadata of all the files of the dataset, so it is done
+    /// on the blocking thread pool.
+    pub async fn current(&self) -> anyhow::Result<Arc<DatasetType>> {
+        let dataset = self.get();
+
+        let loader = match &self.loader {
+            Some(loader) => Arc::clone(loader),
+            None => return Ok(dataset),
+        };
+
+        let modified = {
+            let dataset = Arc::clone(&dataset);
+            tokio::task::spawn_blocking(move || dataset.is_modified())
+                .await
+                .unwrap_or(false)
+        };
+
+        if !modified {
+            return Ok(dataset);
+        }
+
+        let mut failed = self.reloading.lock().await;
+
+        // Another request may have re-loaded the dataset while we were waiting.
+        let current = self.get();
+        if !Arc::ptr_eq(&current, &dataset) {
+            return Ok(current);
+        }
+
+        if failed.is_some_and(|t| t.elapsed() < RELOAD_RETRY) {
+            return Err(anyhow!(
+                "{:?} has changed on disk and could not be re-loaded",
+                dataset
+            ));
+        }
+
+        info!("{:?} has changed on disk, re-loading..", dataset);
+
+        let old = Arc::clone(&dataset);
+        let reloaded = tokio::task::spawn_blocking(move || {
+            old.invalidate()?;
+            loader()
+        })
+        .await;
+
+        match reloaded {
+            Ok(Ok(reloaded)) => {
+                *failed = None;
+                self.swap(reloaded);
+                Ok(self.get())
+            }
+            Ok(Err(e)) => {
+                warn!(
+                    "Could not re-load {:?}, error: {}",
+                    dataset,
+                    e.to_string().red()
+                );
+                *failed = Some(Instant::now());
+                Err(e.context(format!("could not re-load {:?}", dataset)))
+            }
+            Err(e) => {
+                error!("Re-loading {:?} failed: {:?}", dataset, e);
+                *failed = Some(Instant::now());
+                Err(anyhow!("re-loading {:?} failed: {}", dataset, e))
+            }
+        }
     }
 }
 
 impl Datasets {
-    pub fn get<Q>(&self, key: &Q) -> Option<&Arc<DatasetType>>
+    pub fn get<Q>(&self, key: &Q) -> Option<Arc<DatasetType>>
     where
         String: Borrow<Q>,
         Q: std::hash::Hash + std::cmp::Eq,
     {
-        self.datasets.get(key)
+        self.datasets
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|slot| slot.get())
+    }
+
+    /// The current snapshot of a dataset, re-loaded if it has changed on disk. Fails if the
+    /// dataset has changed and could not be re-loaded, see [DatasetSlot::current].
+    pub async fn current<Q>(&self, key: &Q) -> anyhow::Result<Option<Arc<DatasetType>>>
+    where
+        String: Borrow<Q>,
+        Q: std::hash::Hash + std::cmp::Eq + Sync,
+    {
+        let slot = self.datasets.read().unwrap().get(key).cloned();
+
+        match slot {
+            Some(slot) => slot.current().await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The keys of all datasets, sorted.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = self
+            .datasets
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    pub fn insert(&self, key: String, dataset: DatasetType) {
+        self.datasets
+            .write()
+            .unwrap()
+            .insert(key, Arc::new(DatasetSlot::new(dataset)));
+    }
+
+    /// Add datasets, replacing existing datasets with the same key.
+    pub fn extend(&self, datasets: Vec<(String, Arc<DatasetSlot>)>) {
+        self.datasets.write().unwrap().extend(datasets);
+    }
+
+    /// The keys of the datasets loaded from the file or directory with key `key`: the dataset
+    /// itself and anything below it (e.g. the datasets of a forecast model run collection).
+    pub fn keys_under(&self, key: &str) -> Vec<String> {
+        let prefix = format!("{}/", key);
+
+        self.datasets
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|k| *k == key || k.starts_with(&prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// Remove the datasets loaded from the file or directory with key `key`. Requests that are
+    /// already running keep their snapshot. Returns the removed keys.
+    pub fn remove_under(&self, key: &str) -> Vec<String> {
+        let keys = self.keys_under(key);
+
+        let mut datasets = self.datasets.write().unwrap();
+        for k in &keys {
+            datasets.remove(k);
+        }
+
+        let prefix = format!("{}/", key);
+        self.errors
+            .write()
+            .unwrap()
+            .retain(|k, _| k != key && !k.starts_with(&prefix));
+
+        keys
+    }
+
+    /// Load the dataset(s) at `path` and add them, replacing existing datasets with the same
+    /// keys. If the file can not be loaded the error is logged and kept until it is loaded
+    /// successfully or removed, see [Datasets::errors]. Returns the keys of the added datasets.
+    pub fn load(&self, path: &Path, key: String, ncml_config: &config::Ncml) -> Vec<String> {
+        debug!(
+            "Loading {}: {}..",
+            key.yellow(),
+            path.to_string_lossy().blue()
+        );
+
+        match load(path, key.clone(), &self.db, ncml_config, &self.dds) {
+            Ok(datasets) => {
+                self.errors.write().unwrap().remove(&key);
+
+                let keys = datasets.iter().map(|(k, _)| k.clone()).collect();
+                self.datasets.write().unwrap().extend(datasets);
+                keys
+            }
+            Err(e) => {
+                warn!(
+                    "Could not load: {}, error: {}",
+                    path.to_string_lossy().blue(),
+                    e.to_string().red()
+                );
+
+                self.errors.write().unwrap().insert(key, e.to_string());
+                Vec::new()
+            }
+        }
+    }
+
+    /// Files that could not be loaded, with their errors.
+    pub fn errors(&self) -> BTreeMap<String, String> {
+        self.errors
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, e)| (k.clone(), e.clone()))
+            .collect()
+    }
+
+    pub fn new(url: Option<String>, db: Db) -> Datasets {
+        Datasets {
+            datasets: RwLock::default(),
+            errors: RwLock::default(),
+            loading: RwLock::default(),
+            progress: RwLock::default(),
+            url,
+            db,
+            dds: config::Dds::default(),
+        }
+    }
+
+    /// Use `dds` for the datasets that are loaded.
+    pub fn with_dds(mut self, dds: config::Dds) -> Datasets {
+        self.dds = dds;
+        self
     }
 
     /// Temporary State for tests.
     #[cfg(test)]
     pub fn temporary() -> Datasets {
-        Datasets {
-            datasets: HashMap::default(),
-            url: None,
-            db: super::test_db(),
-        }
+        Datasets::new(None, super::test_db())
     }
 
+    /// Load the datasets in `datadir` and wait until they are loaded.
     pub async fn new_with_datadir(
         url: Option<String>,
         datadir: PathBuf,
-        db: sled::Db,
-    ) -> anyhow::Result<Datasets> {
+        db: Db,
+        ncml_config: &config::Ncml,
+    ) -> anyhow::Result<Arc<Datasets>> {
+        let datasets = Arc::new(Datasets::new(url, db));
+        datasets
+            .spawn_load(datadir, ncml_config.clone(), num_cpus::get())
+            .await?;
+
+        Ok(datasets)
+    }
+
+    /// Load the datasets in `datadir` in the background, at most `workers` files at the time.
+    /// Datasets are served as soon as they are loaded, until then requests for them are answered
+    /// with `503 Service Unavailable` (see [Datasets::is_loading]).
+    pub fn spawn_load(
+        self: &Arc<Self>,
+        datadir: PathBuf,
+        ncml_config: config::Ncml,
+        workers: usize,
+    ) -> tokio::task::JoinHandle<()> {
         info!(
             "Scanning {} for datasets..",
             datadir.to_string_lossy().yellow()
         );
 
-        let datasets: HashMap<_, _> = WalkDir::new(&datadir)
+        let files = scan(&datadir)
             .into_iter()
-            .filter_entry(|entry| {
-                entry
-                    .file_name()
-                    .to_str()
-                    .map(|s| entry.depth() == 0 || !s.starts_with('.'))
-                    .unwrap_or(false)
-            })
-            .filter_map(|e| e.ok())
-            .filter_map(|entry| {
-                let path = entry.into_path();
-
-                match path.extension() {
-                    Some(ext) => {
-                        if ext == "nc4" || ext == "nc" || ext == "h5" || ext == "ncml" {
-                            Some(path)
-                        } else {
-                            None
-                        }
-                    }
-                    _ => None,
-                }
-            })
-            .filter_map(|path| {
+            .map(|path| {
                 let key = path
                     .strip_prefix(&datadir)
                     .unwrap()
                     .to_string_lossy()
                     .to_string();
+                (path, key)
+            })
+            .collect::<Vec<_>>();
 
-                debug!(
-                    "Loading {}: {}..",
-                    key.yellow(),
-                    path.to_string_lossy().blue()
-                );
+        self.loading
+            .write()
+            .unwrap()
+            .extend(files.iter().map(|(_, key)| key.clone()));
+        self.progress.write().unwrap().total += files.len();
 
-                if path.extension().expect("already filtered on extension") == "ncml" {
-                    match ncml::NcmlDataset::open(path.clone(), key.clone(), db.clone()) {
-                        Ok(d) => Some((key, Arc::new(DatasetType::NCML(d)))),
-                        Err(e) => {
-                            warn!(
-                                "Could not load: {}, error: {}",
-                                path.to_string_lossy().blue(),
-                                e.to_string().red()
-                            );
-                            None
-                        }
-                    }
-                } else {
-                    match hdf5::Hdf5Dataset::open(path.clone(), key.clone(), &db) {
-                        Ok(d) => Some((key, Arc::new(DatasetType::HDF5(d)))),
-                        Err(e) => {
-                            warn!(
-                                "Could not load: {}, error: {}",
-                                path.to_string_lossy().blue(),
-                                e.to_string().red()
-                            );
-                            None
-                        }
+        info!("Loading {} files with {} workers..", files.len(), workers);
+
+        let state = Arc::clone(self);
+        let workers = Arc::new(tokio::sync::Semaphore::new(workers.max(1)));
+
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let mut tasks = Vec::with_capacity(files.len());
+
+            for (path, key) in files {
+                let permit = Arc::clone(&workers).acquire_owned().await.unwrap();
+                let state = Arc::clone(&state);
+                let ncml_config = ncml_config.clone();
+
+                tasks.push(tokio::task::spawn_blocking(move || {
+                    let loading = Loading { state, key };
+                    loading.state.load(&path, loading.key.clone(), &ncml_config);
+                    drop(loading);
+                    drop(permit);
+                }));
+            }
+
+            for task in futures::future::join_all(tasks).await {
+                if let Err(e) = task {
+                    error!("Loading dataset failed: {:?}", e);
+                }
+            }
+
+            let progress = state.progress();
+            info!(
+                "Loaded {} datasets in {:.1?} ({} files failed).",
+                state.datasets.read().unwrap().len(),
+                start.elapsed(),
+                progress.failed
+            );
+        })
+    }
+
+    /// Mark a file queued by [Datasets::spawn_load] as loaded.
+    fn loaded(&self, key: &str) {
+        self.loading.write().unwrap().remove(key);
+
+        let mut progress = self.progress.write().unwrap();
+        progress.done += 1;
+        if self.errors.read().unwrap().contains_key(key) {
+            progress.failed += 1;
+        }
+    }
+
+    /// Record that loading the file `key` panicked.
+    fn panicked(&self, key: &str) {
+        self.errors
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .insert(key.to_string(), "panicked while loading".to_string());
+    }
+
+    /// Whether the dataset `key`, or the file it is loaded from, is waiting to be loaded.
+    pub fn is_loading(&self, key: &str) -> bool {
+        let loading = self.loading.read().unwrap();
+
+        !loading.is_empty()
+            && Path::new(key)
+                .ancestors()
+                .any(|a| loading.contains(a.to_string_lossy().as_ref()))
+    }
+
+    /// Progress of loading datasets in the background.
+    pub fn progress(&self) -> Progress {
+        self.progress.read().unwrap().clone()
+    }
+}
+
+/// Number of files loaded in the background, see [Datasets::spawn_load].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Progress {
+    pub total: usize,
+    pub done: usize,
+    pub failed: usize,
+}
+
+/// Whether the file at `path` can be served.
+pub(crate) fn is_supported(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == "nc4" || ext == "nc" || ext == "h5" || ext == "ncml")
+}
+
+/// Whether the file or directory at `path` is hidden.
+pub(super) fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|s| s.to_str())
+        .is_some_and(|s| s.starts_with('.'))
+}
+
+/// Find the supported files in `dir`, skipping hidden files and directories.
+pub(crate) fn scan(dir: &Path) -> Vec<PathBuf> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() == 0 || !is_hidden(entry.path()))
+        .filter_map(|e| e.ok())
+        .filter(|entry| entry.file_type().is_file() || entry.file_type().is_symlink())
+        .map(|entry| entry.into_path())
+        .filter(|path| is_supported(path))
+        .collect()
+}
+
+/// Load the dataset(s) at `path`. A forecast model run collection is served as several datasets
+/// under `key`. The datasets are re-loaded from `path` if they change on disk.
+fn load(
+    path: &Path,
+    key: String,
+    db: &Db,
+    ncml_config: &config::Ncml,
+    dds_config: &config::Dds,
+) -> anyhow::Result<Vec<(String, Arc<DatasetSlot>)>> {
+    let path = path.to_path_buf();
+    let db = db.clone();
+    let prefetch = ncml_config.prefetch;
+    let index_coordinates = dds_config.index_coordinates;
+    let unpack = dds_config.unpack_for(&key);
+
+    if path.extension().expect("already filtered on extension") == "ncml" {
+        match ncml::aggregation_type(&path)? {
+            ncml::AggregationType::JoinExisting => {
+                let open = {
+                    let key = key.clone();
+                    move || {
+                        ncml::NcmlDataset::open(&path, key.clone(), db.clone()).and_then(|d| {
+                            d.with_prefetch(prefetch)
+                                .with_index_coordinates(index_coordinates)
+                                .with_unpack(unpack)
+                        })
                     }
+                };
+
+                let d = open()?;
+                let recheck = d.recheck_every();
+                let slot = Arc::new(DatasetSlot::with_loader(DatasetType::NCML(d), move || {
+                    open().map(DatasetType::NCML)
+                }));
+
+                if let Some(every) = recheck {
+                    spawn_rescan(key.clone(), Arc::downgrade(&slot), every);
                 }
-            })
-            .collect();
 
-        info!("Loaded {} datasets.", datasets.len());
+                Ok(vec![(key, slot)])
+            }
+            ncml::AggregationType::Fmrc => {
+                let open = Arc::new(move || {
+                    ncml::FmrcDataset::open(&path, &key, db.clone()).and_then(|(fmrc, best)| {
+                        Ok((
+                            fmrc.with_prefetch(prefetch)
+                                .with_index_coordinates(index_coordinates)
+                                .with_unpack(unpack)?,
+                            best.with_prefetch(prefetch)
+                                .with_index_coordinates(index_coordinates)
+                                .with_unpack(unpack)?,
+                        ))
+                    })
+                });
+
+                let (fmrc, best) = open()?;
+                let (fmrc_key, best_key) = (fmrc.key().to_string(), best.key().to_string());
+
+                let open_fmrc = Arc::clone(&open);
+                let open_best = open;
+
+                Ok(vec![
+                    (
+                        fmrc_key,
+                        Arc::new(DatasetSlot::with_loader(
+                            DatasetType::FMRC(fmrc),
+                            move || open_fmrc().map(|(fmrc, _)| DatasetType::FMRC(fmrc)),
+                        )),
+                    ),
+                    (
+                        best_key,
+                        Arc::new(DatasetSlot::with_loader(
+                            DatasetType::NCML(best),
+                            move || open_best().map(|(_, best)| DatasetType::NCML(best)),
+                        )),
+                    ),
+                ])
+            }
+        }
+    } else {
+        let open = {
+            let key = key.clone();
+            move || {
+                hdf5::Hdf5Dataset::open(&path, key.clone(), &db).and_then(|d| {
+                    d.with_index_coordinates(index_coordinates)
+                        .with_unpack(unpack)
+                })
+            }
+        };
+
+        let d = open()?;
 
-        Ok(Datasets { datasets, url, db })
+        Ok(vec![(
+            key,
+            Arc::new(DatasetSlot::with_loader(DatasetType::HDF5(d), move || {
+                open().map(DatasetType::HDF5)
+            })),
+        )])
     }
 }
 
+/// Periodically re-scan a live aggregation, replacing the dataset if it has changed. Stops when
+/// the slot is dropped, i.e. when the dataset is removed or replaced by a newly loaded slot.
+fn spawn_rescan(key: String, slot: Weak<DatasetSlot>, every: Duration) {
+    debug!("Re-scanning {} every {:?}", key.yellow(), every);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let Some(slot) = slot.upgrade() else {
+                debug!("Stopped re-scanning {}", key.yellow());
+                break;
+            };
+
+            let dataset = slot.get();
+            let rescanned = tokio::task::spawn_blocking(move || match &*dataset {
+                DatasetType::NCML(ds) => ds.rescan(),
+                _ => Ok(None),
+            })
+            .await;
+
+            match rescanned {
+                Ok(Ok(Some(dataset))) => {
+                    info!("Updated aggregation: {}", key.yellow());
+                    slot.swap(DatasetType::NCML(dataset));
+                }
+                Ok(Ok(None)) => (),
+                Ok(Err(e)) => warn!(
+                    "Could not re-scan: {}, error: {}",
+                    key.blue(),
+                    e.to_string().red()
+                ),
+                Err(e) => error!("Re-scan of {} failed: {:?}", key, e),
+            }
+        }
+    });
+}
+
 #[derive(Debug)]
 pub enum DatasetType {
     HDF5(hdf5::Hdf5Dataset),
     NCML(ncml::NcmlDataset),
+    FMRC(ncml::FmrcDataset),
+}
+
+impl DatasetType {
+    /// Whether the files of the dataset have changed on disk since it was opened.
+    pub fn is_modified(&self) -> bool {
+        use DatasetType::*;
+
+        match self {
+            HDF5(ds) => ds.is_modified(),
+            NCML(ds) => ds.is_modified(),
+            FMRC(ds) => ds.is_modified(),
+        }
+    }
+
+    /// Remove the indexes of changed files from the db, so that they are re-indexed when the
+    /// dataset is re-opened.
+    pub fn invalidate(&self) -> anyhow::Result<()> {
+        use DatasetType::*;
+
+        match self {
+            HDF5(ds) => ds.invalidate(),
+            NCML(ds) => ds.invalidate(),
+            FMRC(ds) => ds.invalidate(),
+        }
+    }
+
+    /// The variables that are served unpacked, see [crate::unpack].
+    pub fn packed(&self) -> Option<&Packed> {
+        use DatasetType::*;
+
+        match self {
+            HDF5(ds) => ds.packed(),
+            NCML(ds) => ds.packed(),
+            FMRC(ds) => ds.packed(),
+        }
+    }
+
+    /// Stream the variable from the dataset, without unpacking it.
+    async fn source_xdr(
+        &self,
+        variable: &dds::DdsVariableDetails,
+    ) -> Result<
+        Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>,
+        anyhow::Error,
+    > {
+        use dap2::DodsXdr;
+        use DatasetType::*;
+
+        match self {
+            HDF5(ds) => ds.variable_xdr(variable).await,
+            NCML(ds) => ds.variable_xdr(variable).await,
+            FMRC(ds) => ds.variable_xdr(variable).await,
+        }
+    }
 }
 
 #[async_trait]
@@ -143,6 +705,7 @@ impl Dap2 for DatasetType {
         match self {
             HDF5(ds) => ds.das().await,
             NCML(ds) => ds.das().await,
+            FMRC(ds) => ds.das().await,
         }
     }
 
@@ -152,6 +715,7 @@ impl Dap2 for DatasetType {
         match self {
             HDF5(ds) => ds.dds().await,
             NCML(ds) => ds.dds().await,
+            FMRC(ds) => ds.dds().await,
         }
     }
 
@@ -169,6 +733,7 @@ impl Dap2 for DatasetType {
         match self {
             HDF5(ds) => ds.raw().await,
             NCML(ds) => ds.raw().await,
+            FMRC(ds) => ds.raw().await,
         }
     }
 }
@@ -182,11 +747,135 @@ impl dap2::DodsXdr for DatasetType {
         Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>,
         anyhow::Error,
     > {
+        // Packed variables are read with their packed type and unpacked.
+        match self.packed().and_then(|p| p.get(&variable.name)) {
+            Some(packing) => {
+                let packed = self.source_xdr(&packing.source(variable)).await?;
+                Ok(packing.stream_xdr(packed))
+            }
+            None => self.source_xdr(variable).await,
+        }
+    }
+
+    async fn sequence_rows(
+        &self,
+        variable: &dds::DdsVariableDetails,
+    ) -> Result<
+        Pin<Box<dyn Stream<Item = Result<dds::Row, anyhow::Error>> + Send + 'static>>,
+        anyhow::Error,
+    > {
+        use dap2::DodsXdr;
         use DatasetType::*;
 
         match self {
-            HDF5(ds) => ds.variable_xdr(variable).await,
-            NCML(ds) => ds.variable_xdr(variable).await,
+            HDF5(ds) => ds.sequence_rows(variable).await,
+            NCML(ds) => ds.sequence_rows(variable).await,
+            FMRC(ds) => ds.sequence_rows(variable).await,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reload_changed_file() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = super::super::test_db();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("month.nc4");
+        std::fs::copy("../data/ncml/jan.nc4", &path).unwrap();
+
+        let (key, slot) = load(
+            &path,
+            "month.nc4".into(),
+            &db,
+            &config::Ncml::default(),
+            &config::Dds::default(),
+        )
+        .unwrap()
+        .remove(0);
+        assert_eq!(key, "month.nc4");
+
+        let jan = slot.current().await.unwrap();
+        assert!(!jan.is_modified());
+        assert!(jan.dds().await.all().to_string().contains("[time = 31]"));
+
+        // Overwrite the file in place.
+        std::fs::copy("../data/ncml/feb.nc4", &path).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+
+        // The next request gets the re-loaded dataset.
+        let feb = slot.current().await.unwrap();
+        assert!(!Arc::ptr_eq(&jan, &feb));
+        assert!(feb.dds().await.all().to_string().contains("[time = 28]"));
+        assert!(Arc::ptr_eq(&feb, &slot.current().await.unwrap()));
+
+        // The old snapshot is left untouched.
+        assert!(jan.is_modified());
+        assert!(jan.dds().await.all().to_string().contains("[time = 31]"));
+
+        // A file that can not be re-loaded is not served from the old snapshot.
+        std::fs::write(&path, "not hdf5").unwrap();
+        assert!(slot.current().await.is_err());
+        assert!(slot.current().await.is_err());
+        assert!(Arc::ptr_eq(&feb, &slot.get()));
+    }
+
+    #[tokio::test]
+    async fn load_in_background() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("sub")).unwrap();
+        std::fs::copy("../data/ncml/jan.nc4", dir.path().join("sub/jan.nc4")).unwrap();
+
+        let state = Arc::new(Datasets::temporary());
+        let loader = state.spawn_load(dir.path().to_path_buf(), config::Ncml::default(), 1);
+
+        // Nothing has run yet on this runtime.
+        assert!(state.is_loading("sub/jan.nc4"));
+        assert!(!state.is_loading("sub/feb.nc4"));
+
+        let res = warp::test::request()
+            .path("/data/sub/jan.nc4.das")
+            .reply(&super::super::filters::loading(state.clone()))
+            .await;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res.headers()["Retry-After"], "10");
+
+        loader.await.unwrap();
+        assert!(!state.is_loading("sub/jan.nc4"));
+        assert_eq!(state.keys(), ["sub/jan.nc4"]);
+
+        let progress = state.progress();
+        assert_eq!((progress.total, progress.done, progress.failed), (1, 1, 0));
+    }
+
+    #[test]
+    fn load_panics() {
+        let state = Arc::new(Datasets::temporary());
+        state.loading.write().unwrap().insert("jan.nc4".into());
+        state.progress.write().unwrap().total += 1;
+
+        let loading = Loading {
+            state: Arc::clone(&state),
+            key: "jan.nc4".into(),
+        };
+        assert!(std::thread::spawn(move || {
+            let _loading = loading;
+            panic!("failed to load");
+        })
+        .join()
+        .is_err());
+
+        assert!(!state.is_loading("jan.nc4"));
+        let progress = state.progress();
+        assert_eq!((progress.total, progress.done, progress.failed), (1, 1, 1));
+    }
+}
@@ -16,10 +16,12 @@ pub fn datasets(
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     // TODO: Include recover filter to catch errors without falling through to raw
 
-    let dap = das(state.clone())
+    let dap = loading(state.clone())
+        .or(das(state.clone()))
         .or(dds(state.clone()))
         .or(dods(state.clone()))
-        .or(raw(state));
+        .or(raw(state))
+        .recover(handlers::unavailable);
 
     {
         // If catalog is disabled datasets can be queried in JSON
@@ -49,6 +51,30 @@ pub fn dataset_list(
         .and_then(handlers::list_datasets_json)
 }
 
+/// Datasets that are not loaded yet are answered with `503 Service Unavailable`.
+pub fn loading(
+    state: State,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path::path("data")
+        .and(warp::get())
+        .and(warp::path::tail())
+        .and(with_state(state))
+        .and_then(|tail: warp::path::Tail, state: State| async move {
+            let key = [".das", ".dds", ".dods"]
+                .iter()
+                .find_map(|ext| tail.as_str().strip_suffix(ext))
+                .unwrap_or(tail.as_str());
+
+            if state.is_loading(key) && state.get(key).is_none() {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .and_then(handlers::loading)
+}
+
 pub fn das(
     state: State,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -139,11 +165,16 @@ async fn with_dataset(
 ) -> Result<Arc<DatasetType>, warp::reject::Rejection> {
     let state = Arc::clone(&state);
 
-    if let Some(dataset) = state.get(&dataset) {
-        Ok(Arc::clone(dataset))
-    } else {
-        debug!("Could not find dataset: {}", dataset);
-        Err(warp::reject::not_found())
+    match state.current(&dataset).await {
+        Ok(Some(dataset)) => Ok(dataset),
+        Ok(None) => {
+            debug!("Could not find dataset: {}", dataset);
+            Err(warp::reject::not_found())
+        }
+        Err(e) => {
+            warn!("Dataset {} is unavailable: {:?}", dataset, e);
+            Err(warp::reject::custom(handlers::Unavailable))
+        }
     }
 }
 
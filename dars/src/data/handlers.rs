@@ -11,9 +11,36 @@ use super::State;
 
 #[cfg(not(feature = "catalog"))]
 pub async fn list_datasets_json(state: State) -> Result<impl warp::Reply, Infallible> {
-    Ok(warp::reply::json(
-        &state.datasets.keys().map(|s| &**s).collect::<Vec<&str>>(),
-    ))
+    Ok(warp::reply::json(&state.keys()))
+}
+
+/// Seconds a client should wait before requesting a dataset that is being loaded again.
+const RETRY_AFTER: u64 = 10;
+
+pub async fn loading() -> Result<impl warp::Reply, Infallible> {
+    Ok(Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Retry-After", RETRY_AFTER)
+        .body(Body::from("Dataset is being indexed, try again later.")))
+}
+
+/// A dataset that has changed on disk and could not be re-loaded, see
+/// [super::DatasetSlot::current].
+#[derive(Debug)]
+pub struct Unavailable;
+impl warp::reject::Reject for Unavailable {}
+
+/// Datasets that could not be re-loaded are answered with `503 Service Unavailable`, they are
+/// re-loaded again by a later request.
+pub async fn unavailable(r: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    if r.find::<Unavailable>().is_some() {
+        Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Retry-After", RETRY_AFTER)
+            .body(Body::from("Dataset is being updated, try again later.")))
+    } else {
+        Err(r)
+    }
 }
 
 pub async fn das(dataset: Arc<DatasetType>) -> Result<impl warp::Reply, Infallible> {
@@ -24,10 +51,10 @@ pub async fn dds(
     dataset: Arc<DatasetType>,
     constraint: Constraint,
 ) -> Result<impl warp::Reply, Infallible> {
+    // Selections on 1-D variables constrain the variables, and are resolved by reading them.
     dataset
-        .dds()
+        .constrained_dds(&constraint)
         .await
-        .dds(&constraint)
         .map(|dds| dds.to_string().into_response())
         .or_else(|e| {
             error!("Error parsing DDS: {:?}", e);
@@ -49,15 +76,20 @@ pub async fn dods(
         warp::reject::custom(DodsError)
     })?;
 
-    Ok(Response::builder()
+    let mut response = Response::builder()
         .header("Content-Type", "application/octet-stream")
         .header("Content-Description", "dods-data")
-        .header("Content-Length", content_length)
-        .header("XDODS-Server", "dars")
-        .body(Body::wrap_stream(body.map_err(|e| {
-            error!("Error while streaming: {:?}", e);
-            std::io::Error::from(std::io::ErrorKind::UnexpectedEof)
-        }))))
+        .header("XDODS-Server", "dars");
+
+    // Responses with sequences are sent chunked.
+    if let Some(content_length) = content_length {
+        response = response.header("Content-Length", content_length);
+    }
+
+    Ok(response.body(Body::wrap_stream(body.map_err(|e| {
+        error!("Error while streaming: {:?}", e);
+        std::io::Error::from(std::io::ErrorKind::UnexpectedEof)
+    }))))
 }
 
 pub async fn raw(dataset: Arc<DatasetType>) -> Result<impl warp::Reply, Infallible> {
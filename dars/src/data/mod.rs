@@ -3,11 +3,14 @@ use log::debug;
 use std::fmt;
 use std::sync::Arc;
 
+pub mod admin;
 mod dataset;
 pub mod filters;
 pub mod handlers;
+pub mod watch;
 
-pub use dataset::{DatasetType, Datasets};
+pub(crate) use dataset::{is_supported, scan};
+pub use dataset::{DatasetSlot, DatasetType, Datasets, Progress};
 pub type State = Arc<Datasets>;
 
 /// Ripped off from warp::filters::log to get to debug!
@@ -42,37 +45,31 @@ impl<T: fmt::Display> fmt::Display for OptFmt<T> {
 pub fn test_state() -> State {
     use crate::hdf5;
 
-    let mut data = Datasets::temporary();
-    data.datasets.insert(
+    let data = Datasets::temporary();
+    let coads = hdf5::Hdf5Dataset::open(
+        "../data/coads_climatology.nc4",
+        "nested/coads_climatology.nc4".into(),
+        &data.db,
+    )
+    .unwrap();
+    data.insert(
         "coads_climatology.nc4".to_string(),
-        Arc::new(DatasetType::HDF5(
-            hdf5::Hdf5Dataset::open(
-                "../data/coads_climatology.nc4",
-                "nested/coads_climatology.nc4".into(),
-                &data.db,
-            )
-            .unwrap(),
-        )),
+        DatasetType::HDF5(coads),
     );
-    data.datasets.insert(
+    let coads = hdf5::Hdf5Dataset::open(
+        "../data/coads_climatology.nc4",
+        "nested/coads_climatology.nc4".into(),
+        &data.db,
+    )
+    .unwrap();
+    data.insert(
         "nested/coads_climatology.nc4".to_string(),
-        Arc::new(DatasetType::HDF5(
-            hdf5::Hdf5Dataset::open(
-                "../data/coads_climatology.nc4",
-                "nested/coads_climatology.nc4".into(),
-                &data.db,
-            )
-            .unwrap(),
-        )),
+        DatasetType::HDF5(coads),
     );
     Arc::new(data)
 }
 
 #[cfg(test)]
-pub fn test_db() -> sled::Db {
-    sled::Config::default()
-        .temporary(true)
-        .print_profile_on_drop(true)
-        .open()
-        .unwrap()
+pub fn test_db() -> crate::db::Db {
+    crate::db::Db::temporary()
 }
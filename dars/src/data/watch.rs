@@ -0,0 +1,200 @@
+//! Watch the data directory for new and removed datasets.
+//!
+//! File system notifications (inotify on Linux) are used when available, otherwise the directory
+//! is polled (see `[watch]` in `dars.toml`). Events are collected for a short while before they
+//! are handled so that files which are being written are only loaded once. New files are loaded
+//! (and indexed) in the background, removed files are dropped. Changes to files that are already
+//! loaded are picked up by the datasets themselves, see [super::DatasetSlot::current].
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use colored::Colorize;
+use notify::{Event, EventKind, PollWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use super::dataset::{is_hidden, is_supported, scan};
+use super::State;
+use crate::config;
+
+/// Time to wait for more events before handling them.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Start watching `datadir`. The datasets are updated as long as the returned watcher is kept
+/// alive.
+pub fn watch(
+    state: State,
+    datadir: &Path,
+    ncml_config: config::Ncml,
+    config: &config::Watch,
+) -> anyhow::Result<Box<dyn Watcher + Send>> {
+    let root = std::fs::canonicalize(datadir)?;
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let handler = move |event: notify::Result<Event>| {
+        let _ = tx.send(event);
+    };
+
+    let watcher: Box<dyn Watcher + Send> = if config.poll {
+        Box::new(poll_watcher(&root, handler, config)?)
+    } else {
+        match notify::recommended_watcher(handler.clone()).and_then(|mut w| {
+            w.watch(&root, RecursiveMode::Recursive)?;
+            Ok(w)
+        }) {
+            Ok(w) => {
+                info!(
+                    "Watching {} for datasets..",
+                    root.to_string_lossy().yellow()
+                );
+                Box::new(w)
+            }
+            Err(e) => {
+                warn!(
+                    "File system notifications not available ({}), polling instead.",
+                    e.to_string().red()
+                );
+                Box::new(poll_watcher(&root, handler, config)?)
+            }
+        }
+    };
+
+    tokio::spawn(handle_events(state, root, ncml_config, rx));
+
+    Ok(watcher)
+}
+
+fn poll_watcher<F>(root: &Path, handler: F, config: &config::Watch) -> anyhow::Result<PollWatcher>
+where
+    F: Fn(notify::Result<Event>) + Send + 'static,
+{
+    let interval = Duration::from_secs(config.poll_interval);
+    let mut watcher = PollWatcher::new(
+        handler,
+        notify::Config::default().with_poll_interval(interval),
+    )?;
+    watcher.watch(root, RecursiveMode::Recursive)?;
+
+    info!(
+        "Polling {} for datasets every {:?}..",
+        root.to_string_lossy().yellow(),
+        interval
+    );
+
+    Ok(watcher)
+}
+
+async fn handle_events(
+    state: State,
+    root: PathBuf,
+    ncml_config: config::Ncml,
+    mut rx: mpsc::UnboundedReceiver<notify::Result<Event>>,
+) {
+    while let Some(event) = rx.recv().await {
+        let mut paths = HashSet::new();
+        collect(&mut paths, event);
+
+        tokio::time::sleep(DEBOUNCE).await;
+        while let Ok(event) = rx.try_recv() {
+            collect(&mut paths, event);
+        }
+
+        for path in paths {
+            update(&state, &root, &path, &ncml_config).await;
+        }
+    }
+
+    debug!("Stopped watching {:?}", root);
+}
+
+fn collect(paths: &mut HashSet<PathBuf>, event: notify::Result<Event>) {
+    match event {
+        Ok(event) => {
+            if !matches!(event.kind, EventKind::Access(_)) {
+                paths.extend(event.paths);
+            }
+        }
+        Err(e) => warn!("Error while watching data directory: {}", e),
+    }
+}
+
+/// Load new datasets at `path` (a file or a directory), or drop the datasets if it has been
+/// removed.
+async fn update(state: &State, root: &Path, path: &Path, ncml_config: &config::Ncml) {
+    let key = match path.strip_prefix(root) {
+        Ok(key) if !key.as_os_str().is_empty() => key.to_string_lossy().to_string(),
+        _ => return,
+    };
+
+    if key.split('/').any(|c| is_hidden(Path::new(c))) {
+        return;
+    }
+
+    if !path.exists() {
+        let removed = state.remove_under(&key);
+        if !removed.is_empty() {
+            info!("Removed datasets: {}", removed.join(", ").yellow());
+        }
+        return;
+    }
+
+    let files = if path.is_dir() {
+        scan(path)
+    } else if is_supported(path) {
+        vec![path.to_path_buf()]
+    } else {
+        Vec::new()
+    };
+
+    for file in files {
+        let key = match file.strip_prefix(root) {
+            Ok(key) => key.to_string_lossy().to_string(),
+            Err(_) => continue,
+        };
+
+        // Changes to loaded datasets are handled when they are requested.
+        if !state.keys_under(&key).is_empty() || state.is_loading(&key) {
+            continue;
+        }
+
+        let state = Arc::clone(state);
+        let ncml_config = ncml_config.clone();
+        let added = tokio::task::spawn_blocking(move || state.load(&file, key, &ncml_config)).await;
+
+        match added {
+            Ok(added) if !added.is_empty() => {
+                info!("Added datasets: {}", added.join(", ").yellow())
+            }
+            Ok(_) => (),
+            Err(e) => error!("Loading dataset failed: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Datasets;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn add_and_remove() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("sub")).unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+
+        let state = Arc::new(Datasets::temporary());
+        let ncml = config::Ncml::default();
+
+        std::fs::copy("../data/ncml/jan.nc4", root.join("sub/jan.nc4")).unwrap();
+        std::fs::write(root.join("sub/notes.txt"), "").unwrap();
+        update(&state, &root, &root.join("sub"), &ncml).await;
+        assert_eq!(state.keys(), ["sub/jan.nc4"]);
+
+        std::fs::remove_file(root.join("sub/jan.nc4")).unwrap();
+        update(&state, &root, &root.join("sub/jan.nc4"), &ncml).await;
+        assert!(state.keys().is_empty());
+    }
+}
@@ -0,0 +1,740 @@
+//! Storage of the indexes and cached aggregations.
+//!
+//! The indexes of the files are kept in an [IndexStore], selected with `store` in the `[db]`
+//! section of `dars.toml`:
+//!
+//! * `sled` (default): in the sled db at `path`, together with the cached NcML aggregations.
+//! * `sidecar`: in `.idx` files next to the data files, or in a mirror of the data directory at
+//!   `sidecar_dir`. The sidecar files are only read unless `sidecar_writable` is set, files that
+//!   are not indexed are indexed when they are loaded and the index is kept in memory. This
+//!   allows several servers to share the indexes, and to serve from a read-only file system.
+//! * `memory`: only in memory, everything is indexed when it is loaded.
+//!
+//! With the `sidecar` and `memory` stores the cached aggregations are kept in a temporary db.
+//!
+//! ## Validation
+//!
+//! The indexes are keyed by the path of the file, and a file may be replaced while the server is
+//! not running. Each index is therefore stored together with the [Identity] of the file it was
+//! made from (size, modification time, optionally a hash of the content, and the index format),
+//! and the file is re-indexed if it no longer matches. The hash is only computed when the index
+//! is stored and when the indexes are checked with `dars index --check`, reading every file on
+//! each load would be too slow. Indexes of files that no longer exist are removed by [Db::gc].
+//!
+//! Indexes that can not be read when a variable is requested are re-made on the fly, see
+//! [crate::index]. The sled db is read through when it is opened, and if it is corrupt the server
+//! does not start unless `rebuild` is set in the `[db]` section.
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use colored::Colorize;
+use hidefix::idx;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::config::{self, Store};
+
+/// Marks the start of a stored index, followed by the [Identity] and the index.
+const MAGIC: &[u8; 8] = b"DARSIDX1";
+
+/// The format of the indexes, indexes made with other versions of hidefix are re-made. The
+/// version is taken from `Cargo.lock` by the build script.
+const FORMAT: &str = concat!("hidefix-", env!("HIDEFIX_VERSION"));
+
+/// The file an index was made from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    pub format: String,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// SHA-256 of the content, if enabled with `hash` in the `[db]` section.
+    pub hash: Option<[u8; 32]>,
+}
+
+impl Identity {
+    /// The identity of the file at `path`, hashing the content if `hash` is set.
+    pub fn of(path: &Path, hash: bool) -> anyhow::Result<Identity> {
+        let md = std::fs::metadata(path)?;
+
+        let hash = if hash {
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+            Some(hasher.finalize().into())
+        } else {
+            None
+        };
+
+        Ok(Identity {
+            format: FORMAT.to_string(),
+            size: md.len(),
+            modified: md.modified()?,
+            hash,
+        })
+    }
+
+    /// Whether an index made from a file with identity `self` can be used for the file with
+    /// identity `current`. The hash is only compared if `current` has one.
+    fn matches(&self, current: &Identity) -> bool {
+        self.format == current.format
+            && self.size == current.size
+            && self.modified == current.modified
+            && (current.hash.is_none() || self.hash == current.hash)
+    }
+}
+
+/// The identity of the file at the start of a stored index, without reading the index.
+fn identity(bytes: &[u8]) -> anyhow::Result<Identity> {
+    ensure!(bytes.starts_with(MAGIC), "unknown index format");
+
+    Ok(bincode::deserialize_from(&bytes[MAGIC.len()..])?)
+}
+
+/// Split a stored index into the identity of the file and the serialized index.
+fn split(bytes: Bytes) -> anyhow::Result<(Identity, Bytes)> {
+    ensure!(bytes.starts_with(MAGIC), "unknown index format");
+
+    let mut rest = &bytes[MAGIC.len()..];
+    let identity: Identity = bincode::deserialize_from(&mut rest)?;
+    let offset = bytes.len() - rest.len();
+
+    Ok((identity, bytes.slice(offset..)))
+}
+
+/// Storage of serialized indexes, keyed by the canonical path of the file.
+pub trait IndexStore: Send + Sync {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>>;
+
+    fn contains(&self, key: &str) -> anyhow::Result<bool>;
+
+    /// Store the index, replacing the existing index.
+    fn insert(&self, key: &str, index: Vec<u8>) -> anyhow::Result<()>;
+
+    fn remove(&self, key: &str) -> anyhow::Result<()>;
+
+    /// The keys of all stored indexes.
+    fn keys(&self) -> anyhow::Result<Vec<String>>;
+}
+
+/// The sled db (for cached aggregations) and the index store.
+#[derive(Clone)]
+pub struct Db {
+    sled: sled::Db,
+    indexes: Arc<dyn IndexStore>,
+    /// Hash the content of the files when indexing.
+    hash: bool,
+    /// Also compare the hash of the content when validating the indexes, see [Db::read_only].
+    verify: bool,
+}
+
+impl Db {
+    pub fn new(sled: sled::Db, indexes: Arc<dyn IndexStore>) -> Db {
+        Db {
+            sled,
+            indexes,
+            hash: false,
+            verify: false,
+        }
+    }
+
+    /// Store and check a hash of the content of the files, see [Identity].
+    pub fn with_hash(mut self, hash: bool) -> Db {
+        self.hash = hash;
+        self
+    }
+
+    /// Open the db configured in `config`. Sidecar files are mirrored from `datadir`.
+    pub fn open(config: &config::Db, datadir: &Path) -> anyhow::Result<Db> {
+        let db = match config.store {
+            Store::Sled => {
+                info!(
+                    "Opening sled db: {}..",
+                    config.path.to_string_lossy().yellow()
+                );
+                let sled = open_sled(&config.path, config.rebuild)?;
+
+                Db::new(sled.clone(), Arc::new(SledStore(sled)))
+            }
+            Store::Sidecar => {
+                info!(
+                    "Using sidecar indexes in {} ({})..",
+                    config
+                        .sidecar_dir
+                        .as_deref()
+                        .unwrap_or(datadir)
+                        .to_string_lossy()
+                        .yellow(),
+                    if config.sidecar_writable {
+                        "writable"
+                    } else {
+                        "read-only"
+                    }
+                );
+                let store = SidecarStore::new(
+                    datadir,
+                    config.sidecar_dir.clone(),
+                    config.sidecar_writable,
+                )?;
+
+                Db::new(temporary()?, Arc::new(store))
+            }
+            Store::Memory => {
+                info!("Keeping indexes in memory..");
+                Db::new(temporary()?, Arc::new(MemoryStore::default()))
+            }
+        };
+
+        Ok(db.with_hash(config.hash))
+    }
+
+    /// A temporary sled db with the indexes in it.
+    pub fn temporary() -> Db {
+        let sled = temporary().expect("could not open temporary db");
+        Db::new(sled.clone(), Arc::new(SledStore(sled)))
+    }
+
+    /// A db with the same indexes that never writes them: storing a new index fails. The
+    /// aggregations are cached in a temporary db. Used to check that files are indexed, the hash
+    /// of the content is compared as well if enabled.
+    pub fn read_only(&self) -> anyhow::Result<Db> {
+        Ok(Db {
+            verify: true,
+            ..Db::new(
+                temporary()?,
+                Arc::new(ReadOnlyStore(Arc::clone(&self.indexes))),
+            )
+            .with_hash(self.hash)
+        })
+    }
+
+    /// The sled db, used for the cached aggregations.
+    pub fn sled(&self) -> &sled::Db {
+        &self.sled
+    }
+
+    pub fn indexes(&self) -> &dyn IndexStore {
+        self.indexes.as_ref()
+    }
+
+    /// Store the index of the file at `path` as `key`, replacing the existing index.
+    pub fn insert_index(&self, key: &str, path: &Path, idx: &idx::Index) -> anyhow::Result<()> {
+        let identity = Identity::of(path, self.hash)?;
+
+        let mut bts = MAGIC.to_vec();
+        bincode::serialize_into(&mut bts, &identity)?;
+        bincode::serialize_into(&mut bts, idx)?;
+
+        self.indexes.insert(key, bts)
+    }
+
+    /// Whether the file at `path` is indexed as `key`, and the index was made from the file as
+    /// it is now: with the same size and modification time, and when verifying the same content.
+    pub fn is_indexed(&self, key: &str, path: &Path) -> anyhow::Result<bool> {
+        let stored = match self.indexes.get(key)? {
+            Some(bts) => bts,
+            None => return Ok(false),
+        };
+
+        let stored = match split(stored) {
+            Ok((identity, _)) => identity,
+            Err(e) => {
+                debug!("Index of {} can not be read: {}", key, e);
+                return Ok(false);
+            }
+        };
+
+        let identity = Identity::of(path, false)?;
+        if !stored.matches(&identity) {
+            debug!(
+                "Index of {} does not match the file: {:?} != {:?}",
+                key, stored, identity
+            );
+            return Ok(false);
+        }
+
+        // Only hash the file if it otherwise matches.
+        if !(self.verify && self.hash) {
+            return Ok(true);
+        }
+
+        let identity = Identity::of(path, true)?;
+        if !stored.matches(&identity) {
+            debug!(
+                "Index of {} does not match the file: {:?} != {:?}",
+                key, stored, identity
+            );
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// The serialized index stored as `key` (without checking the file).
+    pub fn index(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        self.indexes
+            .get(key)?
+            .map(|bts| split(bts).map(|(_, idx)| idx))
+            .transpose()
+    }
+
+    /// Remove the indexes of files that no longer exist. Returns the removed keys.
+    pub fn gc(&self) -> anyhow::Result<Vec<String>> {
+        let removed = self
+            .indexes
+            .keys()?
+            .into_iter()
+            .filter(|key| !Path::new(key).exists())
+            .collect::<Vec<_>>();
+
+        for key in &removed {
+            debug!("Removing index of missing file: {}", key);
+            self.indexes.remove(key)?;
+        }
+
+        info!("Removed {} indexes of missing files.", removed.len());
+
+        Ok(removed)
+    }
+}
+
+/// Open the sled db at `path` and read through it to detect corruption. A corrupt db is moved
+/// aside and replaced with an empty db if `rebuild` is set, the files are then re-indexed when
+/// they are loaded. Indexes that can not be read are only logged, they are re-made when the
+/// files are loaded.
+fn open_sled(path: &Path, rebuild: bool) -> anyhow::Result<sled::Db> {
+    fn check(path: &Path) -> anyhow::Result<sled::Db> {
+        let sled = sled::open(path)?;
+
+        let mut unreadable = 0;
+        for entry in sled.iter() {
+            let (key, value) = entry?;
+
+            // The indexes are keyed by absolute paths, see [SledStore::keys].
+            let key = String::from_utf8_lossy(&key);
+            if Path::new(key.as_ref()).is_absolute() {
+                if let Err(e) = identity(&value) {
+                    debug!("Index of {} can not be read: {}", key, e);
+                    unreadable += 1;
+                }
+            }
+        }
+
+        if unreadable > 0 {
+            warn!(
+                "{} indexes in the db {:?} can not be read, the files will be re-indexed.",
+                unreadable, path
+            );
+        }
+
+        Ok(sled)
+    }
+
+    match check(path) {
+        Ok(sled) => Ok(sled),
+        Err(e) if rebuild => {
+            let mut corrupt = path.to_path_buf().into_os_string();
+            corrupt.push(".corrupt");
+            let corrupt = PathBuf::from(corrupt);
+
+            error!(
+                "The db {:?} can not be read: {}, moving it to {:?} and rebuilding..",
+                path, e, corrupt
+            );
+
+            if corrupt.exists() {
+                std::fs::remove_dir_all(&corrupt)?;
+            }
+            std::fs::rename(path, &corrupt)?;
+
+            Ok(sled::open(path)?)
+        }
+        Err(e) => Err(e.context(format!(
+            "the db {:?} can not be read, set `rebuild = true` in the [db] section to rebuild it",
+            path
+        ))),
+    }
+}
+
+fn temporary() -> anyhow::Result<sled::Db> {
+    Ok(sled::Config::default().temporary(true).open()?)
+}
+
+/// Indexes stored in the sled db.
+pub struct SledStore(pub sled::Db);
+
+impl IndexStore for SledStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        Ok(self.0.get(key)?.map(|b| Bytes::copy_from_slice(&b)))
+    }
+
+    fn contains(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.0.contains_key(key)?)
+    }
+
+    fn insert(&self, key: &str, index: Vec<u8>) -> anyhow::Result<()> {
+        self.0.insert(key, index)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> anyhow::Result<()> {
+        self.0.remove(key)?;
+        Ok(())
+    }
+
+    /// The indexes are keyed by canonical (absolute) paths, the other keys in the db are cached
+    /// aggregations.
+    fn keys(&self) -> anyhow::Result<Vec<String>> {
+        let mut keys = Vec::new();
+
+        for key in self.0.iter().keys() {
+            let key = String::from_utf8_lossy(&key?).to_string();
+            if Path::new(&key).is_absolute() {
+                keys.push(key);
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+/// Indexes kept in memory.
+#[derive(Default)]
+pub struct MemoryStore(RwLock<HashMap<String, Bytes>>);
+
+impl IndexStore for MemoryStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        Ok(self.0.read().unwrap().get(key).cloned())
+    }
+
+    fn contains(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.0.read().unwrap().contains_key(key))
+    }
+
+    fn insert(&self, key: &str, index: Vec<u8>) -> anyhow::Result<()> {
+        self.0
+            .write()
+            .unwrap()
+            .insert(key.to_string(), index.into());
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> anyhow::Result<()> {
+        self.0.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn keys(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.0.read().unwrap().keys().cloned().collect())
+    }
+}
+
+/// Indexes of another store that can not be changed.
+struct ReadOnlyStore(Arc<dyn IndexStore>);
+
+impl IndexStore for ReadOnlyStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        self.0.get(key)
+    }
+
+    fn contains(&self, key: &str) -> anyhow::Result<bool> {
+        self.0.contains(key)
+    }
+
+    fn insert(&self, key: &str, _index: Vec<u8>) -> anyhow::Result<()> {
+        Err(anyhow!("{} is not indexed", key))
+    }
+
+    fn remove(&self, key: &str) -> anyhow::Result<()> {
+        Err(anyhow!(
+            "can not remove index of {}, the store is read-only",
+            key
+        ))
+    }
+
+    fn keys(&self) -> anyhow::Result<Vec<String>> {
+        self.0.keys()
+    }
+}
+
+/// Indexes stored in `.idx` files next to the data files, or in a mirror of the data directory.
+pub struct SidecarStore {
+    /// The data directory (canonical).
+    datadir: PathBuf,
+    /// Directory mirroring the data directory.
+    dir: Option<PathBuf>,
+    writable: bool,
+    /// Indexes that can not be written to sidecar files.
+    memory: MemoryStore,
+    /// Sidecar files that have been removed, but can not be deleted.
+    removed: RwLock<HashSet<String>>,
+}
+
+impl SidecarStore {
+    pub fn new(
+        datadir: &Path,
+        dir: Option<PathBuf>,
+        writable: bool,
+    ) -> anyhow::Result<SidecarStore> {
+        Ok(SidecarStore {
+            datadir: std::fs::canonicalize(datadir)?,
+            dir,
+            writable,
+            memory: MemoryStore::default(),
+            removed: RwLock::default(),
+        })
+    }
+
+    /// The sidecar file of the file at `key`. Files outside the data directory have their
+    /// sidecar file next to them.
+    pub fn sidecar(&self, key: &str) -> PathBuf {
+        let path = Path::new(key);
+
+        let sidecar = match (&self.dir, path.strip_prefix(&self.datadir)) {
+            (Some(dir), Ok(relative)) => dir.join(relative),
+            _ => path.to_path_buf(),
+        };
+
+        let mut sidecar = sidecar.into_os_string();
+        sidecar.push(".idx");
+        sidecar.into()
+    }
+
+    /// The key of the file with the sidecar file at `sidecar`, the inverse of
+    /// [SidecarStore::sidecar].
+    fn key(&self, sidecar: &Path) -> Option<String> {
+        let path = Path::new(sidecar.to_str()?.strip_suffix(".idx")?);
+
+        let path = match &self.dir {
+            Some(dir) => self.datadir.join(path.strip_prefix(dir).ok()?),
+            None => path.to_path_buf(),
+        };
+
+        Some(path.to_string_lossy().to_string())
+    }
+}
+
+impl IndexStore for SidecarStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        if let Some(index) = self.memory.get(key)? {
+            return Ok(Some(index));
+        }
+
+        if self.removed.read().unwrap().contains(key) {
+            return Ok(None);
+        }
+
+        match std::fs::read(self.sidecar(key)) {
+            Ok(index) => Ok(Some(index.into())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn contains(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.memory.contains(key)?
+            || (!self.removed.read().unwrap().contains(key) && self.sidecar(key).is_file()))
+    }
+
+    fn insert(&self, key: &str, index: Vec<u8>) -> anyhow::Result<()> {
+        if !self.writable {
+            trace!(
+                "Sidecar files are read-only, keeping index of {} in memory",
+                key
+            );
+            return self.memory.insert(key, index);
+        }
+
+        let sidecar = self.sidecar(key);
+        if let Some(parent) = sidecar.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // Write to a temporary file first, so that other readers never see a partial index.
+        let mut tmp = sidecar.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, index)?;
+        std::fs::rename(&tmp, &sidecar)?;
+
+        self.removed.write().unwrap().remove(key);
+
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> anyhow::Result<()> {
+        self.memory.remove(key)?;
+
+        if self.writable {
+            match std::fs::remove_file(self.sidecar(key)) {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        } else {
+            self.removed.write().unwrap().insert(key.to_string());
+            Ok(())
+        }
+    }
+
+    /// The sidecar files in the mirror directory (or the data directory), and the indexes in
+    /// memory.
+    fn keys(&self) -> anyhow::Result<Vec<String>> {
+        let removed = self.removed.read().unwrap();
+
+        let sidecars = WalkDir::new(self.dir.as_deref().unwrap_or(&self.datadir))
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter_map(|e| self.key(e.path()))
+            .filter(|key| !removed.contains(key));
+
+        let mut keys = self.memory.keys()?;
+        keys.extend(sidecars);
+        keys.sort();
+        keys.dedup();
+
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidecar_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data, mirror) = (dir.path().join("data"), dir.path().join("mirror"));
+        std::fs::create_dir_all(data.join("sub")).unwrap();
+        let data = std::fs::canonicalize(data).unwrap();
+        let key = data.join("sub/jan.nc4").to_string_lossy().to_string();
+
+        let store = SidecarStore::new(&data, None, true).unwrap();
+        assert_eq!(store.sidecar(&key), data.join("sub/jan.nc4.idx"));
+
+        let store = SidecarStore::new(&data, Some(mirror.clone()), true).unwrap();
+        assert_eq!(store.sidecar(&key), mirror.join("sub/jan.nc4.idx"));
+        assert_eq!(store.sidecar("/other/a.nc4"), Path::new("/other/a.nc4.idx"));
+
+        assert!(!store.contains(&key).unwrap());
+        store.insert(&key, vec![1, 2, 3]).unwrap();
+        assert!(mirror.join("sub/jan.nc4.idx").is_file());
+        assert_eq!(store.get(&key).unwrap().unwrap(), [1, 2, 3][..]);
+        assert_eq!(store.keys().unwrap(), [key.as_str()]);
+
+        // A read-only store keeps new indexes in memory, and masks removed sidecar files.
+        let readonly = SidecarStore::new(&data, Some(mirror.clone()), false).unwrap();
+        assert!(readonly.contains(&key).unwrap());
+        readonly.remove(&key).unwrap();
+        assert!(!readonly.contains(&key).unwrap());
+        assert!(mirror.join("sub/jan.nc4.idx").is_file());
+
+        readonly.insert(&key, vec![4]).unwrap();
+        assert_eq!(readonly.get(&key).unwrap().unwrap(), [4][..]);
+        assert_eq!(store.get(&key).unwrap().unwrap(), [1, 2, 3][..]);
+
+        store.remove(&key).unwrap();
+        assert!(!mirror.join("sub/jan.nc4.idx").exists());
+    }
+
+    #[test]
+    fn validate_and_gc() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("coads.nc4");
+        std::fs::copy("../data/coads_climatology.nc4", &path).unwrap();
+        let path = std::fs::canonicalize(path).unwrap();
+        let key = path.to_string_lossy().to_string();
+
+        let db = Db::new(temporary().unwrap(), Arc::new(MemoryStore::default())).with_hash(true);
+        assert!(!db.is_indexed(&key, &path).unwrap());
+
+        let hf = hdf5::File::open(&path).unwrap();
+        let idx = idx::Index::index_file(&hf, Some(&path)).unwrap();
+        drop(hf);
+
+        db.insert_index(&key, &path, &idx).unwrap();
+        assert!(db.is_indexed(&key, &path).unwrap());
+        let bts = db.index(&key).unwrap().unwrap();
+        assert!(bincode::deserialize::<idx::Index>(&bts).is_ok());
+
+        // Same size and modification time, but different content.
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let mut content = std::fs::read(&path).unwrap();
+        let last = content.len() - 1;
+        content[last] = content[last].wrapping_add(1);
+        std::fs::write(&path, content).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        // The content is only compared when checking the indexes.
+        assert!(db.is_indexed(&key, &path).unwrap());
+        assert!(!db.read_only().unwrap().is_indexed(&key, &path).unwrap());
+        assert!(db
+            .clone()
+            .with_hash(false)
+            .read_only()
+            .unwrap()
+            .is_indexed(&key, &path)
+            .unwrap());
+
+        // Indexes stored without the identity of the file are re-made.
+        db.indexes()
+            .insert(&key, bincode::serialize(&idx).unwrap())
+            .unwrap();
+        assert!(!db.is_indexed(&key, &path).unwrap());
+        assert!(db.index(&key).is_err());
+
+        assert!(db.gc().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(db.gc().unwrap(), [key.as_str()]);
+        assert!(!db.indexes().contains(&key).unwrap());
+    }
+
+    #[test]
+    fn unreadable_index_in_sled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dars.db");
+
+        {
+            let sled = sled::open(&path).unwrap();
+            sled.insert("/data/broken.nc4", &b"garbage"[..]).unwrap();
+            sled.insert("aggregation", &b"cached"[..]).unwrap();
+            sled.flush().unwrap();
+        }
+
+        // The db is not rebuilt, the index is re-made when the file is loaded.
+        let sled = open_sled(&path, false).unwrap();
+        assert_eq!(sled.len(), 2);
+        assert!(identity(&sled.get("/data/broken.nc4").unwrap().unwrap()).is_err());
+    }
+
+    #[test]
+    fn rebuild_corrupt_sled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dars.db");
+        std::fs::write(&path, b"garbage").unwrap();
+
+        assert!(open_sled(&path, false).is_err());
+
+        let sled = open_sled(&path, true).unwrap();
+        assert!(sled.is_empty());
+        assert!(dir.path().join("dars.db.corrupt").is_file());
+        drop(sled);
+    }
+
+    #[test]
+    fn memory_store() {
+        let store = MemoryStore::default();
+        store.insert("a", vec![1]).unwrap();
+        assert!(store.contains("a").unwrap());
+        assert_eq!(store.get("a").unwrap().unwrap(), [1][..]);
+        store.remove("a").unwrap();
+        assert!(store.get("a").unwrap().is_none());
+    }
+}
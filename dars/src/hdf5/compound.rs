@@ -0,0 +1,396 @@
+//! HDF5 compound datasets are served as DAP2 structures (or arrays of structures) with the
+//! numeric members of the compound type. Other members (strings, arrays, nested compounds) are
+//! left out.
+//!
+//! The index does not cover compound datasets, so the records are read through the HDF5 library:
+//! the members are read into a packed in-memory type, which lets HDF5 convert them to native
+//! types and drop the unsupported members, and are then encoded as XDR.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::pin::Pin;
+
+use async_stream::stream;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use hdf5::types::{CompoundField, CompoundType, FloatSize, IntSize, TypeDescriptor};
+use hdf5_sys as hs;
+
+use dap2::dds::{DdsVariableDetails, VarType};
+
+/// Number of records in each block read from the file.
+const BLOCK: usize = 64 * 1024;
+
+/// The supported members of a compound type.
+#[derive(Debug, Clone)]
+pub(crate) struct Compound {
+    pub members: Vec<(String, VarType)>,
+}
+
+impl Compound {
+    /// The compound type of `dataset`, `None` if the dataset is not a compound or has no
+    /// supported members.
+    pub fn from_dataset(dataset: &hdf5::Dataset) -> Option<Compound> {
+        let fields = match dataset.dtype().and_then(|t| t.to_descriptor()) {
+            Ok(TypeDescriptor::Compound(c)) => c.fields,
+            _ => return None,
+        };
+
+        let members: Vec<(String, VarType)> = fields
+            .into_iter()
+            .filter_map(|f| match member_vartype(&f.ty) {
+                Some(vartype) => Some((f.name, vartype)),
+                None => {
+                    trace!("Unsupported compound member: {} ({:?})", f.name, f.ty);
+                    None
+                }
+            })
+            .collect();
+
+        if members.is_empty() {
+            None
+        } else {
+            Some(Compound { members })
+        }
+    }
+
+    /// The in-memory type with `members` packed in order.
+    fn descriptor(members: &[(String, VarType)]) -> TypeDescriptor {
+        let mut offset = 0;
+        let fields = members
+            .iter()
+            .enumerate()
+            .map(|(index, (name, vartype))| {
+                let field = CompoundField {
+                    name: name.clone(),
+                    ty: member_descriptor(*vartype),
+                    offset,
+                    index,
+                };
+                offset += vartype.size();
+                field
+            })
+            .collect();
+
+        TypeDescriptor::Compound(CompoundType {
+            fields,
+            size: offset,
+        })
+    }
+}
+
+/// The compound datasets in `file`.
+pub(crate) fn compounds(file: &hdf5::File) -> anyhow::Result<HashMap<String, Compound>> {
+    Ok(file
+        .member_names()?
+        .into_iter()
+        .filter_map(|m| {
+            let c = Compound::from_dataset(&file.dataset(&m).ok()?)?;
+            Some((m, c))
+        })
+        .collect())
+}
+
+fn member_vartype(ty: &TypeDescriptor) -> Option<VarType> {
+    match ty {
+        TypeDescriptor::Unsigned(IntSize::U1) => Some(VarType::Byte),
+        TypeDescriptor::Unsigned(IntSize::U2) => Some(VarType::UInt16),
+        TypeDescriptor::Unsigned(IntSize::U4) => Some(VarType::UInt32),
+        TypeDescriptor::Unsigned(IntSize::U8) => Some(VarType::UInt64),
+        TypeDescriptor::Integer(IntSize::U2) => Some(VarType::Int16),
+        TypeDescriptor::Integer(IntSize::U4) => Some(VarType::Int32),
+        TypeDescriptor::Integer(IntSize::U8) => Some(VarType::Int64),
+        TypeDescriptor::Float(FloatSize::U4) => Some(VarType::Float32),
+        TypeDescriptor::Float(FloatSize::U8) => Some(VarType::Float64),
+        _ => None,
+    }
+}
+
+fn member_descriptor(vartype: VarType) -> TypeDescriptor {
+    match vartype {
+        VarType::Byte => TypeDescriptor::Unsigned(IntSize::U1),
+        VarType::UInt16 => TypeDescriptor::Unsigned(IntSize::U2),
+        VarType::UInt32 => TypeDescriptor::Unsigned(IntSize::U4),
+        VarType::UInt64 => TypeDescriptor::Unsigned(IntSize::U8),
+        VarType::Int16 => TypeDescriptor::Integer(IntSize::U2),
+        VarType::Int32 => TypeDescriptor::Integer(IntSize::U4),
+        VarType::Int64 => TypeDescriptor::Integer(IntSize::U8),
+        VarType::Float32 => TypeDescriptor::Float(FloatSize::U4),
+        VarType::Float64 => TypeDescriptor::Float(FloatSize::U8),
+        _ => unreachable!("not a compound member type: {:?}", vartype),
+    }
+}
+
+/// Encode the native `value` as a scalar XDR member, 1 and 2 byte integers are upcast to 4
+/// bytes.
+fn encode(vartype: VarType, value: &[u8], xdr: &mut Vec<u8>) {
+    match vartype {
+        VarType::Byte => xdr.extend_from_slice(&(value[0] as u32).to_be_bytes()),
+        VarType::UInt16 => {
+            xdr.extend_from_slice(&(u16::from_ne_bytes([value[0], value[1]]) as u32).to_be_bytes())
+        }
+        VarType::Int16 => {
+            xdr.extend_from_slice(&(i16::from_ne_bytes([value[0], value[1]]) as i32).to_be_bytes())
+        }
+        _ if cfg!(target_endian = "little") => xdr.extend(value.iter().rev()),
+        _ => xdr.extend_from_slice(value),
+    }
+}
+
+/// Encode the packed `records` as XDR.
+fn encode_records(members: &[(String, VarType)], records: &[u8]) -> Bytes {
+    let size: usize = members.iter().map(|(_, t)| t.size()).sum();
+    let mut xdr = Vec::with_capacity(records.len() / size * VarType::structure(members).size());
+
+    for record in records.chunks_exact(size) {
+        let mut offset = 0;
+        for (_, vartype) in members {
+            encode(*vartype, &record[offset..offset + vartype.size()], &mut xdr);
+            offset += vartype.size();
+        }
+    }
+
+    Bytes::from(xdr)
+}
+
+/// A dataspace, closed when dropped.
+struct Space(hs::h5i::hid_t);
+
+impl Space {
+    fn new(id: hs::h5i::hid_t) -> anyhow::Result<Space> {
+        ensure!(id >= 0, "failed to create dataspace");
+        Ok(Space(id))
+    }
+}
+
+impl Drop for Space {
+    fn drop(&mut self) {
+        unsafe {
+            hs::h5s::H5Sclose(self.0);
+        }
+    }
+}
+
+/// Read `counts` records starting at `indices` of the `members` of `dataset`.
+fn read(
+    dataset: &hdf5::Dataset,
+    members: &[(String, VarType)],
+    indices: &[u64],
+    counts: &[u64],
+) -> anyhow::Result<Vec<u8>> {
+    let dtype = hdf5::Datatype::from_descriptor(&Compound::descriptor(members))?;
+    let n: u64 = counts.iter().product();
+    let mut records = vec![0_u8; n as usize * dtype.size()];
+
+    hdf5::sync::sync(|| unsafe {
+        let space = Space::new(hs::h5d::H5Dget_space(dataset.id()))?;
+
+        let mem = if indices.is_empty() {
+            Space::new(hs::h5s::H5Screate(hs::h5s::H5S_class_t::H5S_SCALAR))?
+        } else {
+            let err = hs::h5s::H5Sselect_hyperslab(
+                space.0,
+                hs::h5s::H5S_seloper_t::H5S_SELECT_SET,
+                indices.as_ptr(),
+                std::ptr::null(),
+                counts.as_ptr(),
+                std::ptr::null(),
+            );
+            ensure!(
+                err >= 0,
+                "failed to select {:?} / {:?} in {}",
+                indices,
+                counts,
+                dataset.name()
+            );
+
+            Space::new(hs::h5s::H5Screate_simple(
+                counts.len() as _,
+                counts.as_ptr(),
+                std::ptr::null(),
+            ))?
+        };
+
+        let err = hs::h5d::H5Dread(
+            dataset.id(),
+            dtype.id(),
+            mem.0,
+            space.0,
+            hs::h5p::H5P_DEFAULT,
+            records.as_mut_ptr().cast(),
+        );
+        ensure!(
+            err >= 0,
+            "failed to read compound dataset {}",
+            dataset.name()
+        );
+
+        Ok(())
+    })?;
+
+    Ok(records)
+}
+
+/// Read and encode the records on the blocking pool, see [read].
+async fn read_xdr(
+    dataset: hdf5::Dataset,
+    members: Vec<(String, VarType)>,
+    indices: Vec<u64>,
+    counts: Vec<u64>,
+) -> anyhow::Result<Bytes> {
+    tokio::task::spawn_blocking(move || {
+        read(&dataset, &members, &indices, &counts).map(|r| encode_records(&members, &r))
+    })
+    .await?
+}
+
+/// Stream the constrained members of the compound dataset as XDR, reading blocks of records
+/// along the first dimension.
+pub(crate) fn stream_xdr(
+    path: PathBuf,
+    variable: &DdsVariableDetails,
+) -> anyhow::Result<Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>> {
+    let name = variable.name.clone();
+    let members = variable.members.clone();
+    let indices: Vec<u64> = variable.indices.iter().map(|i| *i as u64).collect();
+    let counts: Vec<u64> = variable.counts.iter().map(|c| *c as u64).collect();
+
+    Ok(stream! {
+        let open = tokio::task::spawn_blocking(move || {
+            Ok::<_, anyhow::Error>(hdf5::File::open(&path)?.dataset(&name)?)
+        });
+
+        let dataset = match open.await {
+            Ok(Ok(d)) => d,
+            Ok(Err(e)) => {
+                yield Err(e);
+                return;
+            }
+            Err(e) => {
+                yield Err(e.into());
+                return;
+            }
+        };
+
+        if indices.is_empty() {
+            yield read_xdr(dataset, members, indices, counts).await;
+            return;
+        }
+
+        let row: u64 = counts[1..].iter().product();
+        let rows = (BLOCK as u64 / row.max(1)).max(1);
+
+        let mut i = 0;
+        while i < counts[0] {
+            let n = rows.min(counts[0] - i);
+
+            let mut start = indices.clone();
+            start[0] += i;
+            let mut count = counts.clone();
+            count[0] = n;
+
+            match read_xdr(dataset.clone(), members.clone(), start, count).await {
+                Ok(xdr) => yield Ok(xdr),
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+
+            i += n;
+        }
+    }
+    .boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(hdf5::H5Type, Clone, Copy)]
+    #[repr(C)]
+    struct Observation {
+        station: i16,
+        flags: [u8; 2],
+        temperature: f32,
+        pressure: f64,
+    }
+
+    #[tokio::test]
+    async fn compound_dataset() {
+        use crate::data::test_db;
+        use crate::hdf5::Hdf5Dataset;
+        use dap2::dds::ConstrainedVariable;
+        use dap2::DodsXdr;
+        use futures::TryStreamExt;
+
+        let db = test_db();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compound.h5");
+
+        {
+            let f = hdf5::File::create(&path).unwrap();
+            let obs: Vec<Observation> = (0..5)
+                .map(|i| Observation {
+                    station: i as i16,
+                    flags: [0, 1],
+                    temperature: i as f32 + 0.5,
+                    pressure: 1000. + i as f64,
+                })
+                .collect();
+            f.new_dataset::<Observation>()
+                .shape(5)
+                .create("obs")
+                .unwrap()
+                .write_raw(&obs)
+                .unwrap();
+        }
+
+        let hd = Hdf5Dataset::open(&path, "compound".into(), &db).unwrap();
+        assert!(hd.dds.all().to_string().contains(
+            r#"    Structure {
+        Int16 station;
+        Float32 temperature;
+        Float64 pressure;
+    } obs[obs = 5];"#
+        ));
+
+        let c = dap2::Constraint::parse("obs[1:2].temperature").unwrap();
+        let v = match hd.dds.dds(&c).unwrap().variables.remove(0) {
+            ConstrainedVariable::Variable(v) => v,
+            _ => panic!("expected variable"),
+        };
+        assert_eq!(v.dods_size(), 4 + 2 * 4);
+
+        let bytes: Vec<Bytes> = hd
+            .variable_xdr(&v)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let values = dap2::dods::xdr::xdr_decode_f64(VarType::Float32, &bytes.concat()).unwrap();
+        assert_eq!(values, [1.5, 2.5]);
+    }
+
+    #[test]
+    fn encode_members() {
+        let members = vec![
+            ("station".to_string(), VarType::Int16),
+            ("temperature".to_string(), VarType::Float32),
+        ];
+
+        let mut records = Vec::new();
+        for (s, t) in [(-2_i16, 1.5_f32), (7, -3.)] {
+            records.extend_from_slice(&s.to_ne_bytes());
+            records.extend_from_slice(&t.to_ne_bytes());
+        }
+
+        let xdr = encode_records(&members, &records);
+        assert_eq!(xdr.len(), 2 * 8);
+        assert_eq!(xdr[..4], (-2_i32).to_be_bytes());
+        assert_eq!(xdr[4..8], 1.5_f32.to_be_bytes());
+        assert_eq!(xdr[8..12], 7_i32.to_be_bytes());
+        assert_eq!(xdr[12..], (-3_f32).to_be_bytes());
+    }
+}
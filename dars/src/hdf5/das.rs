@@ -29,7 +29,6 @@ impl das::ToDas for &HDF5File {
                 .iter()
                 .map(|m| self.0.dataset(m).map(|d| (m, d)))
                 .filter_map(Result::ok)
-                .filter(|(_, d)| d.is_chunked() || d.offset().is_some()) // skipping un-allocated datasets.
                 .map(|(m, _)| m.clone())
                 .collect::<Vec<String>>()
                 .into_iter(),
@@ -59,7 +58,7 @@ impl das::ToDas for &HDF5File {
     }
 }
 
-fn h5attr_to_das(n: &str, a: hdf5::Attribute) -> das::Attribute {
+pub(crate) fn h5attr_to_das(n: &str, a: hdf5::Attribute) -> das::Attribute {
     use das::AttrValue::*;
     use hdf5::types::TypeDescriptor as h5t;
     use hdf5::types::{FloatSize, IntSize};
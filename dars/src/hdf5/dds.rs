@@ -6,6 +6,7 @@
 //! There are some types of datasets that apparently should be ignored.
 use hdf5_sys as hs;
 use hdf5_sys::h5t::hvl_t;
+use std::collections::HashMap;
 use std::convert::TryInto;
 
 use dap2::dds::{self, Variable};
@@ -43,7 +44,13 @@ pub(crate) fn hdf5_vartype(dtype: &hdf5::Datatype) -> dds::VarType {
     }
 }
 
-pub(crate) fn hdf5_dimensions(m: &str, dataset: &hdf5::Dataset) -> Vec<String> {
+/// The dimensions of the dataset `m`. Datasets without dimension scales get their dimensions from
+/// `phony` (see [phony_dimensions]), except 1-D datasets which are named after themselves.
+pub(crate) fn hdf5_dimensions(
+    m: &str,
+    dataset: &hdf5::Dataset,
+    phony: &HashMap<String, Vec<String>>,
+) -> Vec<String> {
     if let Ok(dim_list) = dataset.attr("DIMENSION_LIST") {
         // HDF5 references not yet supported in hdf5-rust:
         // https://github.com/aldanor/hdf5-rust/issues/98
@@ -104,12 +111,61 @@ pub(crate) fn hdf5_dimensions(m: &str, dataset: &hdf5::Dataset) -> Vec<String> {
             }
         })
     } else {
-        vec![m.to_string()]
+        phony
+            .get(m)
+            .filter(|_| dataset.ndim() > 1)
+            .cloned()
+            .unwrap_or_else(|| vec![m.to_string()])
     }
 }
 
+/// Dimension scales are marked with the `CLASS` attribute by the HDF5 dimension scale API.
+fn is_dimension_scale(dataset: &hdf5::Dataset) -> bool {
+    dataset.attr("CLASS").is_ok()
+}
+
+/// Datasets without dimension scales get dimensions named `phony_dim_<n>` like netCDF does: the
+/// datasets of the root group are visited in order and a dimension of the same length from an
+/// earlier dataset is re-used, unless it is already used by the dataset.
+pub(crate) fn phony_dimensions(file: &hdf5::File) -> HashMap<String, Vec<String>> {
+    let mut lengths: Vec<usize> = Vec::new();
+    let mut phony = HashMap::new();
+
+    for member in file.member_names().unwrap_or_default() {
+        let d = match file.dataset(&member) {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+
+        if d.attr("DIMENSION_LIST").is_ok() || d.ndim() == 0 || is_dimension_scale(&d) {
+            continue;
+        }
+
+        let mut dims = Vec::with_capacity(d.ndim());
+        for len in d.shape() {
+            let n = (0..lengths.len())
+                .find(|n| lengths[*n] == len && !dims.contains(n))
+                .unwrap_or_else(|| {
+                    lengths.push(len);
+                    lengths.len() - 1
+                });
+            dims.push(n);
+        }
+
+        let dims = dims
+            .into_iter()
+            .map(|n| format!("phony_dim_{}", n))
+            .collect();
+        phony.insert(member, dims);
+    }
+
+    phony
+}
+
 impl dds::ToDds for &HDF5File {
     fn variables(&self) -> Vec<Variable> {
+        let phony = phony_dimensions(&self.0);
+
         self.0
             .group("/")
             .unwrap()
@@ -118,13 +174,22 @@ impl dds::ToDds for &HDF5File {
             .iter()
             .map(|m| self.0.dataset(m).map(|d| (m, d)))
             .filter_map(Result::ok)
-            .filter(|(_, d)| d.is_chunked() || d.offset().is_some()) // skipping un-allocated datasets.
             .map(|(m, d)| {
+                if let Some(c) = super::compound::Compound::from_dataset(&d) {
+                    trace!("Structure: {} {:?}", m, c.members);
+                    return Variable::structure(
+                        m.clone(),
+                        hdf5_dimensions(m, &d, &phony),
+                        d.shape(),
+                        c.members,
+                    );
+                }
+
                 trace!("Variable: {} {:?}", m, hdf5_vartype(&d.dtype().unwrap()));
                 Variable::new(
                     m.clone(),
                     hdf5_vartype(&d.dtype().unwrap()),
-                    hdf5_dimensions(m, &d),
+                    hdf5_dimensions(m, &d, &phony),
                     d.shape(),
                 )
             })
@@ -272,4 +337,75 @@ mod tests {
 
         assert_eq!(hd.dds.all().to_string(), res);
     }
+
+    #[test]
+    fn phony_dimensions() {
+        let db = test_db();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("phony.h5");
+
+        {
+            let f = hdf5::File::create(&path).unwrap();
+            for (name, shape) in [("a", (2, 3)), ("b", (3, 3))] {
+                f.new_dataset::<f32>()
+                    .shape(shape)
+                    .create(name)
+                    .unwrap()
+                    .write_raw(&vec![0_f32; shape.0 * shape.1])
+                    .unwrap();
+            }
+            f.new_dataset::<f32>()
+                .shape(4)
+                .create("c")
+                .unwrap()
+                .write_raw(&[0_f32; 4])
+                .unwrap();
+        }
+
+        let hd = Hdf5Dataset::open(&path, "phony".into(), &db).unwrap();
+
+        let res = r#"Dataset {
+    Float32 a[phony_dim_0 = 2][phony_dim_1 = 3];
+    Float32 b[phony_dim_1 = 3][phony_dim_2 = 3];
+    Float32 c[c = 4];
+} phony;"#;
+
+        assert_eq!(hd.dds.all().to_string(), res);
+
+        let hd = hd.with_index_coordinates(true);
+
+        let res = r#"Dataset {
+    Grid {
+     ARRAY:
+        Float32 a[phony_dim_0 = 2][phony_dim_1 = 3];
+     MAPS:
+        Int32 phony_dim_0[phony_dim_0 = 2];
+        Int32 phony_dim_1[phony_dim_1 = 3];
+    } a;
+    Grid {
+     ARRAY:
+        Float32 b[phony_dim_1 = 3][phony_dim_2 = 3];
+     MAPS:
+        Int32 phony_dim_1[phony_dim_1 = 3];
+        Int32 phony_dim_2[phony_dim_2 = 3];
+    } b;
+    Grid {
+     ARRAY:
+        Float32 c[phony_dim_3 = 4];
+     MAPS:
+        Int32 phony_dim_3[phony_dim_3 = 4];
+    } c;
+    Int32 phony_dim_0[phony_dim_0 = 2];
+    Int32 phony_dim_1[phony_dim_1 = 3];
+    Int32 phony_dim_2[phony_dim_2 = 3];
+    Int32 phony_dim_3[phony_dim_3 = 4];
+} phony;"#;
+
+        assert_eq!(hd.dds.all().to_string(), res);
+        assert!(hd
+            .das
+            .to_string()
+            .contains("phony_dim_2 {\n        String long_name \"phony_dim_2 index\";"));
+    }
 }
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
@@ -6,11 +7,16 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use futures::{Stream, StreamExt};
 
-use dap2::dds::DdsVariableDetails;
+use crate::chunks::{self, Layout};
+use crate::db::Db;
+use crate::unpack::Packed;
+use dap2::dds::{DdsVariableDetails, VarType};
 use hidefix::idx;
 
-mod das;
+pub(crate) mod compound;
+pub(crate) mod das;
 pub(crate) mod dds;
+pub(crate) mod ragged;
 
 /// HDF5 dataset source.
 pub struct Hdf5Dataset {
@@ -19,7 +25,19 @@ pub struct Hdf5Dataset {
     pub das: dap2::Das,
     pub dds: dap2::Dds,
     modified: std::time::SystemTime,
-    db: sled::Db,
+    /// Chunking of the chunked variables, used with the chunk cache.
+    layouts: HashMap<String, Layout>,
+    /// Fill values of the variables that have not been allocated, see [stream_fill].
+    unallocated: HashMap<String, f64>,
+    /// Packed variables that are served unpacked, see [Hdf5Dataset::with_unpack].
+    packed: Option<Packed>,
+    /// Compound datasets, served as structures (see [compound]).
+    compounds: HashMap<String, compound::Compound>,
+    /// Ragged arrays, served as sequences (see [ragged]).
+    ragged: HashMap<String, ragged::Ragged>,
+    /// Phony dimensions of the datasets without dimension scales, see [dds::phony_dimensions].
+    phony: HashMap<String, Vec<String>>,
+    db: Db,
 }
 
 impl fmt::Debug for Hdf5Dataset {
@@ -31,11 +49,7 @@ impl fmt::Debug for Hdf5Dataset {
 pub struct HDF5File(pub hdf5::File, pub String);
 
 impl Hdf5Dataset {
-    pub fn open<P: AsRef<Path>>(
-        path: P,
-        key: String,
-        db: &sled::Db,
-    ) -> anyhow::Result<Hdf5Dataset> {
+    pub fn open<P: AsRef<Path>>(path: P, key: String, db: &Db) -> anyhow::Result<Hdf5Dataset> {
         let path = path.as_ref();
 
         let modified = std::fs::metadata(path)?.modified()?;
@@ -46,16 +60,30 @@ impl Hdf5Dataset {
         let das = (&hf).into();
 
         trace!("Building DDS of {:?}..", path);
-        let dds = (&hf).into();
+        let mut dds: dap2::Dds = (&hf).into();
+
+        let layouts = chunks::layouts(&hf.0);
+        let unallocated = unallocated(&hf.0)?;
+        let compounds = compound::compounds(&hf.0)?;
+        let phony = dds::phony_dimensions(&hf.0);
+
+        let ragged = ragged::ragged(&hf.0)?;
+        for r in ragged.values() {
+            dds.add_variable(dap2::dds::Variable::sequence(
+                r.name.clone(),
+                r.members.clone(),
+            ));
+        }
 
         let idxkey = std::fs::canonicalize(path)?.to_string_lossy().to_string();
-        if !db.contains_key(&idxkey)? {
+        if !db.is_indexed(&idxkey, path)? {
             debug!("Indexing: {:?}..", path);
             let idx = hdf5::sync::sync(|| idx::Index::index_file(&hf.0, Some(&path)))?;
-            let bts = bincode::serialize(&idx)?;
 
             trace!("Inserting index into db ({})", idxkey);
-            db.insert(&idxkey, bts)?;
+            db.insert_index(&idxkey, path, &idx)?;
+            crate::index::cache().remove(&idxkey);
+            chunks::remove(&idxkey);
         } else {
             trace!("{} already indexed.", idxkey);
         };
@@ -66,13 +94,174 @@ impl Hdf5Dataset {
             das,
             dds,
             modified,
+            layouts,
+            unallocated,
+            packed: None,
+            compounds,
+            ragged,
+            phony,
             db: db.clone(),
         })
     }
 
+    /// Index the file at `path` and store the index in the db, replacing the existing index. The
+    /// index is shared with the NcML aggregations the file is a member of.
+    pub fn reindex<P: AsRef<Path>>(path: P, db: &Db) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let idxkey = std::fs::canonicalize(path)?.to_string_lossy().to_string();
+
+        debug!("Re-indexing: {:?}..", path);
+        let hf = hdf5::File::open(path)?;
+        let idx = hdf5::sync::sync(|| idx::Index::index_file(&hf, Some(path)))?;
+
+        trace!("Replacing index in db ({})", idxkey);
+        db.insert_index(&idxkey, path, &idx)?;
+        crate::index::cache().remove(&idxkey);
+        chunks::remove(&idxkey);
+
+        Ok(())
+    }
+
+    /// Serve index coordinates for the dimensions without coordinate variables, see
+    /// [add_index_coordinates]. 1-D datasets without dimension scales get their phony dimension
+    /// instead of being their own coordinate variable.
+    pub fn with_index_coordinates(mut self, enabled: bool) -> Hdf5Dataset {
+        if enabled {
+            for (m, dimensions) in &self.phony {
+                self.dds.set_dimensions(m, dimensions.clone());
+            }
+            add_index_coordinates(&mut self.das, &mut self.dds);
+        }
+        self
+    }
+
+    /// Serve the packed variables unpacked, see [crate::unpack].
+    pub fn with_unpack(mut self, enabled: bool) -> anyhow::Result<Hdf5Dataset> {
+        if enabled && self.packed.is_none() {
+            let packed = Packed::from_file(&hdf5::File::open(&self.path)?)?;
+            packed.apply(&mut self.das, &mut self.dds);
+            self.packed = Some(packed);
+        }
+        Ok(self)
+    }
+
+    /// The variables that are served unpacked.
+    pub fn packed(&self) -> Option<&Packed> {
+        self.packed.as_ref()
+    }
+
     pub fn get_dds(&self) -> &dap2::Dds {
         &self.dds
     }
+
+    /// Whether the file has changed on disk since it was opened.
+    pub fn is_modified(&self) -> bool {
+        !std::fs::metadata(&self.path)
+            .and_then(|md| md.modified())
+            .is_ok_and(|modified| modified == self.modified)
+    }
+
+    /// Remove the index of the file from the db if the file has changed, so that it is re-indexed
+    /// when it is opened again.
+    pub fn invalidate(&self) -> anyhow::Result<()> {
+        if self.is_modified() {
+            debug!("Removing stale index: {}", self.idxkey);
+            self.db.indexes().remove(&self.idxkey)?;
+            crate::index::cache().remove(&self.idxkey);
+            chunks::remove(&self.idxkey);
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether storage has been allocated for the dataset. Variables that are defined but never
+/// written (e.g. in pre-allocated model output) have no storage, and are not in the index.
+pub(crate) fn is_allocated(dataset: &hdf5::Dataset) -> bool {
+    dataset.is_chunked() || dataset.offset().is_some()
+}
+
+/// The fill value of a variable: the `_FillValue` attribute, the HDF5 fill value if it has been set
+/// when the dataset was created, or the netCDF default fill value.
+pub(crate) fn fill_value(dataset: &hdf5::Dataset, vartype: VarType) -> f64 {
+    if let Ok(fill) = dataset.attr("_FillValue").and_then(|a| a.read_raw::<f64>()) {
+        if let Some(fill) = fill.first() {
+            return *fill;
+        }
+    }
+
+    if let Some(fill) = dataset
+        .dcpl()
+        .ok()
+        .filter(|dcpl| dcpl.fill_value_defined() == hdf5::dataset::FillValue::UserDefined)
+        .and_then(|dcpl| dcpl.fill_value_as::<f64>())
+    {
+        return fill;
+    }
+
+    match vartype {
+        VarType::Byte => 255.,
+        VarType::Int16 => -32767.,
+        VarType::UInt16 => 65535.,
+        VarType::Int32 => -2147483647.,
+        VarType::UInt32 => 4294967295.,
+        VarType::Int64 => -9223372036854775806_i64 as f64,
+        VarType::UInt64 => 18446744073709551614_u64 as f64,
+        VarType::Float32 | VarType::Float64 => 9.969_209_968_386_869e36,
+        VarType::String(_) | VarType::Structure(_) | VarType::Sequence | VarType::Unimplemented => {
+            0.
+        }
+    }
+}
+
+/// The fill values of the variables in `file` that have not been allocated.
+pub(crate) fn unallocated(file: &hdf5::File) -> anyhow::Result<HashMap<String, f64>> {
+    file.member_names()?
+        .into_iter()
+        .filter_map(|m| file.dataset(&m).ok().map(|d| (m, d)))
+        .filter(|(_, d)| !is_allocated(d))
+        .map(|(m, d)| {
+            let vartype = dds::hdf5_vartype(&d.dtype()?);
+            Ok((m, fill_value(&d, vartype)))
+        })
+        .collect()
+}
+
+/// Number of values in each block of [stream_fill].
+const FILL_BLOCK: u64 = 1024 * 1024;
+
+/// Stream `n` XDR encoded fill values, for a variable that has not been allocated.
+pub(crate) fn stream_fill(
+    vartype: VarType,
+    fill: f64,
+    n: u64,
+) -> anyhow::Result<Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>> {
+    let value = dap2::dods::xdr::xdr_encode_f64(vartype, &[fill])?;
+    let block = Bytes::from(value.repeat(n.min(FILL_BLOCK) as usize));
+    let sz = value.len() as u64;
+
+    Ok(futures::stream::iter(
+        (0..n)
+            .step_by(FILL_BLOCK as usize)
+            .map(move |i| Ok(block.slice(..((n - i).min(FILL_BLOCK) * sz) as usize))),
+    )
+    .boxed())
+}
+
+/// Add `Int32` coordinate variables with the indices along the dimensions that do not have a
+/// coordinate variable (see [dap2::Dds::add_index_coordinates]), so that clients get grids with
+/// maps for all dimensions. The values are generated, the files are not read.
+pub(crate) fn add_index_coordinates(das: &mut dap2::Das, dds: &mut dap2::Dds) {
+    use dap2::das::{AttrValue, Attribute};
+
+    for dim in dds.add_index_coordinates() {
+        let long_name = Attribute {
+            name: "long_name".into(),
+            value: AttrValue::Str(format!("{} index", dim)),
+        };
+
+        das.add_variable(&dim, vec![long_name]);
+    }
 }
 
 #[async_trait]
@@ -131,23 +320,74 @@ impl dap2::DodsXdr for Hdf5Dataset {
             variable.name, variable.indices, variable.counts
         );
 
-        trace!("fetching index from db: {}", self.idxkey);
-        let bts = self.db.get(&self.idxkey)?.unwrap();
-        let idx = bincode::deserialize::<idx::Index>(&bts)?;
+        if self.compounds.contains_key(&variable.name) {
+            return compound::stream_xdr(self.path.clone(), variable);
+        }
+
+        if let Some(fill) = self.unallocated.get(&variable.name) {
+            trace!("{} is not allocated, streaming fill values", variable.name);
+            return stream_fill(variable.vartype, *fill, variable.len() as u64);
+        }
+
+        let idx = crate::index::cache()
+            .get(&self.db, &self.idxkey, self.modified, &self.path)
+            .await?;
+
+        let indices: Vec<u64> = variable.indices.iter().map(|c| *c as u64).collect();
+        let counts: Vec<u64> = variable.counts.iter().map(|c| *c as u64).collect();
+
+        if let Some(layout) = self
+            .layouts
+            .get(&variable.name)
+            .filter(|_| chunks::enabled())
+        {
+            let source = chunks::Source {
+                path: self.path.clone(),
+                idxkey: self.idxkey.clone(),
+                modified: self.modified,
+                idx,
+            };
+            return chunks::stream_xdr(
+                source,
+                &variable.name,
+                variable.vartype,
+                layout,
+                &indices,
+                &counts,
+            );
+        }
 
         trace!("creating streamer: {}", variable.name);
 
-        let reader = match idx.dataset(&variable.name) {
+        let reader = match idx.index().dataset(&variable.name) {
             Some(ds) => ds.as_streamer(&self.path),
             None => Err(anyhow!("dataset does not exist")),
         }?;
 
-        let indices: Vec<u64> = variable.indices.iter().map(|c| *c as u64).collect();
-        let counts: Vec<u64> = variable.counts.iter().map(|c| *c as u64).collect();
-
         let ex = crate::make_extents((indices.as_slice(), counts.as_slice()))?;
         Ok(reader.stream_xdr(&ex).boxed())
     }
+
+    async fn sequence_rows(
+        &self,
+        variable: &DdsVariableDetails,
+    ) -> Result<
+        Pin<Box<dyn Stream<Item = Result<dap2::dds::Row, anyhow::Error>> + Send + 'static>>,
+        anyhow::Error,
+    > {
+        let modified = std::fs::metadata(&self.path)?.modified()?;
+        if modified != self.modified {
+            warn!("{:?} has changed on disk", self.path);
+            return Err(anyhow!("{:?} has changed on disk", self.path));
+        }
+
+        debug!("streaming rows: {} {:?}", variable.name, variable.members);
+
+        match self.ragged.get(&variable.name) {
+            Some(ragged) => ragged::stream_rows(self.path.clone(), ragged, variable),
+            None => Err(anyhow!("{} is not a sequence", variable.name)),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -160,4 +400,68 @@ mod tests {
         let db = test_db();
         Hdf5Dataset::open("../data/coads_climatology.nc4", "coads".into(), &db).unwrap();
     }
+
+    #[tokio::test]
+    async fn unallocated() {
+        use dap2::dds::ConstrainedVariable;
+        use dap2::DodsXdr;
+        use futures::TryStreamExt;
+
+        let db = test_db();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("unallocated.h5");
+
+        {
+            let f = hdf5::File::create(&path).unwrap();
+            f.new_dataset::<f32>()
+                .shape(4)
+                .create("written")
+                .unwrap()
+                .write_raw(&[1_f32, 2., 3., 4.])
+                .unwrap();
+            f.new_dataset::<f32>().shape(4).create("empty").unwrap();
+            f.new_dataset::<i16>()
+                .shape(4)
+                .create("filled")
+                .unwrap()
+                .new_attr::<i16>()
+                .create("_FillValue")
+                .unwrap()
+                .write_scalar(&-1_i16)
+                .unwrap();
+        }
+
+        let hd = Hdf5Dataset::open(&path, "unallocated".into(), &db).unwrap();
+        assert!(hd
+            .dds
+            .all()
+            .to_string()
+            .contains("Float32 empty[empty = 4];"));
+        assert!(hd.das.to_string().contains("filled {"));
+
+        let read = |c: &str| {
+            let c = dap2::Constraint::parse(c).unwrap();
+            match hd.dds.dds(&c).unwrap().variables.remove(0) {
+                ConstrainedVariable::Variable(v) => v,
+                _ => panic!("expected variable"),
+            }
+        };
+
+        for (c, vartype, expected) in [
+            ("written[1:2]", VarType::Float32, vec![2., 3.]),
+            ("empty", VarType::Float32, vec![9.969_209_968_386_869e36; 4]),
+            ("filled[1:2]", VarType::Int16, vec![-1., -1.]),
+        ] {
+            let bytes: Vec<Bytes> = hd
+                .variable_xdr(&read(c))
+                .await
+                .unwrap()
+                .try_collect()
+                .await
+                .unwrap();
+            let values = dap2::dods::xdr::xdr_decode_f64(vartype, &bytes.concat()).unwrap();
+            assert_eq!(values, expected, "{}", c);
+        }
+    }
 }
@@ -0,0 +1,419 @@
+//! CF discrete sampling geometry (DSG) files with ragged arrays are served as DAP2 sequences with a
+//! row for each observation. The variables along the instance dimension (e.g. the id and position
+//! of a buoy) are repeated in each row of the observations of the instance.
+//!
+//! * Contiguous ragged arrays: a count variable with the `sample_dimension` attribute has the
+//!   number of observations of each instance, the observations are stored one instance after the
+//!   other.
+//! * Indexed ragged arrays: an index variable with the `instance_dimension` attribute has the
+//!   instance of each observation.
+//!
+//! The sequence is named after the sample dimension, and has the numeric variables along the
+//! instance and sample dimensions as members. The variables are served as arrays as well. Like
+//! [super::compound] datasets, the values are read through the HDF5 library.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_stream::stream;
+use futures::{Stream, StreamExt};
+
+use dap2::das::AttrValue;
+use dap2::dds::{DdsVariableDetails, Row, Value, VarType};
+
+use super::das::h5attr_to_das;
+use super::dds::{hdf5_dimensions, hdf5_vartype};
+
+/// Number of observations in each block read from the file.
+const BLOCK: usize = 64 * 1024;
+
+/// How the observations are assigned to the instances.
+#[derive(Debug, Clone, PartialEq)]
+enum Layout {
+    /// The count variable has the number of observations of each instance.
+    Contiguous(String),
+    /// The index variable has the instance of each observation.
+    Indexed(String),
+}
+
+/// A ragged array, served as a sequence.
+#[derive(Debug, Clone)]
+pub(crate) struct Ragged {
+    /// Name of the sequence (the sample dimension).
+    pub name: String,
+    layout: Layout,
+    /// The members along the instance dimension.
+    instances: Vec<String>,
+    pub members: Vec<(String, VarType)>,
+}
+
+/// A 1-D variable of a file.
+struct Column {
+    name: String,
+    vartype: VarType,
+    dimension: String,
+    /// The `sample_dimension` attribute of a count variable.
+    sample_dimension: Option<String>,
+    /// The `instance_dimension` attribute of an index variable.
+    instance_dimension: Option<String>,
+}
+
+/// The ragged arrays in `file`, by the name of the sequence.
+pub(crate) fn ragged(file: &hdf5::File) -> anyhow::Result<HashMap<String, Ragged>> {
+    // Only 1-D variables are considered, which do not use phony dimensions.
+    let phony = HashMap::new();
+
+    let columns: Vec<Column> = file
+        .member_names()?
+        .into_iter()
+        .filter_map(|m| file.dataset(&m).ok().map(|d| (m, d)))
+        .filter(|(_, d)| d.ndim() == 1)
+        .filter_map(|(m, d)| {
+            Some(Column {
+                vartype: hdf5_vartype(&d.dtype().ok()?),
+                dimension: hdf5_dimensions(&m, &d, &phony).pop()?,
+                sample_dimension: string_attr(&d, "sample_dimension"),
+                instance_dimension: string_attr(&d, "instance_dimension"),
+                name: m,
+            })
+        })
+        .collect();
+
+    Ok(find(&columns)
+        .into_iter()
+        .map(|r| (r.name.clone(), r))
+        .collect())
+}
+
+fn string_attr(dataset: &hdf5::Dataset, name: &str) -> Option<String> {
+    match dataset.attr(name).map(|a| h5attr_to_das(name, a).value) {
+        Ok(AttrValue::Str(s)) => Some(s),
+        _ => None,
+    }
+}
+
+/// Find the ragged arrays among the `columns`.
+fn find(columns: &[Column]) -> Vec<Ragged> {
+    columns
+        .iter()
+        .filter_map(|c| {
+            let (layout, instance, sample) = match (&c.sample_dimension, &c.instance_dimension) {
+                (Some(sample), _) => (Layout::Contiguous(c.name.clone()), &c.dimension, sample),
+                (None, Some(instance)) => (Layout::Indexed(c.name.clone()), instance, &c.dimension),
+                (None, None) => return None,
+            };
+
+            if columns.iter().any(|o| o.name == *sample) {
+                debug!(
+                    "Ragged array {} not served as a sequence, {} is a variable",
+                    c.name, sample
+                );
+                return None;
+            }
+
+            let members: Vec<&Column> = columns
+                .iter()
+                .filter(|o| o.name != c.name)
+                .filter(|o| o.dimension == *instance || o.dimension == *sample)
+                .filter(|o| {
+                    !matches!(
+                        o.vartype,
+                        VarType::String(_)
+                            | VarType::Structure(_)
+                            | VarType::Sequence
+                            | VarType::Unimplemented
+                    )
+                })
+                .collect();
+
+            trace!(
+                "Ragged array: {} ({:?}) [{} / {}]",
+                c.name,
+                layout,
+                instance,
+                sample
+            );
+
+            Some(Ragged {
+                name: sample.clone(),
+                layout,
+                instances: members
+                    .iter()
+                    .filter(|o| o.dimension == *instance)
+                    .map(|o| o.name.clone())
+                    .collect(),
+                members: members
+                    .iter()
+                    .map(|o| (o.name.clone(), o.vartype))
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// The instance of each observation of a contiguous ragged array, with `ends` the cumulative
+/// counts of the instances.
+fn contiguous(ends: &[u64], observations: std::ops::Range<u64>) -> Vec<usize> {
+    observations
+        .map(|o| ends.partition_point(|e| *e <= o))
+        .collect()
+}
+
+/// The rows of the observations with `instances`. `columns` has the values of each member, for
+/// all instances if `instance` is set for the member, otherwise for the observations.
+fn rows(instance: &[bool], columns: &[&[f64]], instances: &[usize]) -> Vec<Row> {
+    instances
+        .iter()
+        .enumerate()
+        .map(|(o, i)| {
+            (0..columns.len())
+                .map(|m| {
+                    let v = if instance[m] {
+                        columns[m].get(*i).copied().unwrap_or(f64::NAN)
+                    } else {
+                        columns[m][o]
+                    };
+                    Value::Number(v)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The values of the instance members and the cumulative counts (of a contiguous ragged array),
+/// read once for all the blocks of observations.
+struct Instances {
+    columns: Vec<Option<Vec<f64>>>,
+    ends: Vec<u64>,
+    observations: u64,
+}
+
+impl Instances {
+    fn read(
+        file: &hdf5::File,
+        ragged: &Ragged,
+        members: &[(String, VarType)],
+    ) -> anyhow::Result<Instances> {
+        let columns = members
+            .iter()
+            .map(|(m, _)| {
+                if ragged.instances.contains(m) {
+                    Ok(Some(file.dataset(m)?.read_raw::<f64>()?))
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let (ends, observations) = match &ragged.layout {
+            Layout::Contiguous(count) => {
+                let ends: Vec<u64> = file
+                    .dataset(count)?
+                    .read_raw::<f64>()?
+                    .iter()
+                    .scan(0, |end, c| {
+                        *end += c.max(0.) as u64;
+                        Some(*end)
+                    })
+                    .collect();
+                let n = ends.last().copied().unwrap_or(0);
+
+                (ends, n)
+            }
+            Layout::Indexed(index) => (Vec::new(), file.dataset(index)?.size() as u64),
+        };
+
+        Ok(Instances {
+            columns,
+            ends,
+            observations,
+        })
+    }
+
+    /// Read the rows of the observations `start..start + n`.
+    fn block(
+        &self,
+        file: &hdf5::File,
+        ragged: &Ragged,
+        members: &[(String, VarType)],
+        start: u64,
+        n: u64,
+    ) -> anyhow::Result<Vec<Row>> {
+        let range = start as usize..(start + n) as usize;
+
+        let instances = match &ragged.layout {
+            Layout::Contiguous(_) => contiguous(&self.ends, start..start + n),
+            Layout::Indexed(index) => file
+                .dataset(index)?
+                .read_slice_1d::<f64, _>(range.clone())?
+                .iter()
+                .map(|i| if *i >= 0. { *i as usize } else { usize::MAX })
+                .collect(),
+        };
+
+        let observed = members
+            .iter()
+            .zip(&self.columns)
+            .map(|((m, _), c)| match c {
+                Some(_) => Ok(None),
+                None => Ok(Some(
+                    file.dataset(m)?
+                        .read_slice_1d::<f64, _>(range.clone())?
+                        .to_vec(),
+                )),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let instance: Vec<bool> = self.columns.iter().map(Option::is_some).collect();
+        let columns: Vec<&[f64]> = self
+            .columns
+            .iter()
+            .zip(&observed)
+            .map(|(c, o)| c.as_deref().or(o.as_deref()).unwrap_or_default())
+            .collect();
+
+        Ok(rows(&instance, &columns, &instances))
+    }
+}
+
+/// Stream the rows of the constrained members of the sequence, reading blocks of observations.
+pub(crate) fn stream_rows(
+    path: PathBuf,
+    ragged: &Ragged,
+    variable: &DdsVariableDetails,
+) -> anyhow::Result<Pin<Box<dyn Stream<Item = Result<Row, anyhow::Error>> + Send + 'static>>> {
+    let ragged = Arc::new(ragged.clone());
+    let members = Arc::new(variable.members.clone());
+
+    Ok(stream! {
+        let open = {
+            let ragged = Arc::clone(&ragged);
+            let members = Arc::clone(&members);
+
+            tokio::task::spawn_blocking(move || {
+                let file = hdf5::File::open(&path)?;
+                let instances = Instances::read(&file, &ragged, &members)?;
+                Ok::<_, anyhow::Error>((file, Arc::new(instances)))
+            })
+        };
+
+        let (file, instances) = match open.await {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => {
+                yield Err(e);
+                return;
+            }
+            Err(e) => {
+                yield Err(e.into());
+                return;
+            }
+        };
+
+        let mut start = 0;
+        while start < instances.observations {
+            let n = (BLOCK as u64).min(instances.observations - start);
+
+            let block = {
+                let file = file.clone();
+                let ragged = Arc::clone(&ragged);
+                let members = Arc::clone(&members);
+                let instances = Arc::clone(&instances);
+
+                tokio::task::spawn_blocking(move || {
+                    instances.block(&file, &ragged, &members, start, n)
+                })
+            };
+
+            match block.await {
+                Ok(Ok(rows)) => {
+                    for row in rows {
+                        yield Ok(row);
+                    }
+                }
+                Ok(Err(e)) => {
+                    yield Err(e);
+                    return;
+                }
+                Err(e) => {
+                    yield Err(e.into());
+                    return;
+                }
+            }
+
+            start += n;
+        }
+    }
+    .boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, dimension: &str) -> Column {
+        Column {
+            name: name.into(),
+            vartype: VarType::Float32,
+            dimension: dimension.into(),
+            sample_dimension: None,
+            instance_dimension: None,
+        }
+    }
+
+    #[test]
+    fn find_ragged_arrays() {
+        let mut count = column("row_size", "trajectory");
+        count.sample_dimension = Some("obs".into());
+
+        let mut columns = vec![
+            column("lat", "obs"),
+            count,
+            column("temperature", "obs"),
+            column("trajectory_id", "trajectory"),
+            column("time", "time"),
+        ];
+
+        let ragged = find(&columns);
+        assert_eq!(ragged.len(), 1);
+        assert_eq!(ragged[0].name, "obs");
+        assert_eq!(ragged[0].layout, Layout::Contiguous("row_size".into()));
+        assert_eq!(ragged[0].instances, ["trajectory_id"]);
+        assert_eq!(
+            ragged[0]
+                .members
+                .iter()
+                .map(|(m, _)| m.as_str())
+                .collect::<Vec<_>>(),
+            ["lat", "temperature", "trajectory_id"]
+        );
+
+        // Indexed.
+        let mut index = column("station_index", "obs");
+        index.instance_dimension = Some("station".into());
+        columns[1] = index;
+        columns[3] = column("station_id", "station");
+
+        let ragged = find(&columns);
+        assert_eq!(ragged[0].layout, Layout::Indexed("station_index".into()));
+        assert_eq!(ragged[0].instances, ["station_id"]);
+
+        // The sample dimension is a variable.
+        columns.push(column("obs", "obs"));
+        assert!(find(&columns).is_empty());
+    }
+
+    #[test]
+    fn observation_rows() {
+        assert_eq!(contiguous(&[2, 2, 5], 0..5), [0, 0, 2, 2, 2]);
+        assert_eq!(contiguous(&[2, 2, 5], 3..4), [2]);
+
+        // An instance member (id) and an observation member (temperature).
+        let columns: [&[f64]; 2] = [&[10., 20.], &[1.5, 2.5, 3.5]];
+
+        let rows = rows(&[true, false], &columns, &[0, 1, usize::MAX]);
+        assert_eq!(rows[0], [Value::Number(10.), Value::Number(1.5)]);
+        assert_eq!(rows[1], [Value::Number(20.), Value::Number(2.5)]);
+        assert!(matches!(rows[2][0], Value::Number(v) if v.is_nan()));
+    }
+}
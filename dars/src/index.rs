@@ -0,0 +1,251 @@
+//! In-memory cache of deserialized indexes.
+//!
+//! Reading a variable requires the index of its file (the locations of the chunks). The indexes
+//! are stored serialized in the db, and deserializing the index of a file with many chunks can
+//! take longer than reading a small slab. The most recently used indexes are therefore kept
+//! deserialized in memory, shared by all datasets and NcML members. The size of the cache is set
+//! with `cache_size` (in MiB) in the `[index]` section of `dars.toml`, and the hit rate is
+//! available from the admin API (see [crate::data::admin]).
+//!
+//! Entries are keyed by the index key and the modification time of the file, so an index of a
+//! file that has changed on disk is never used.
+//!
+//! If the stored index is missing or can not be deserialized (e.g. it is corrupt, or was made
+//! with an incompatible version of hidefix) the file is re-indexed and the request continues
+//! with the new index.
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use hidefix::idx;
+use yoke::{Yoke, Yokeable};
+
+use crate::cache::{CacheStats, SizedLru};
+use crate::db::Db;
+
+/// Default size of the cache in bytes.
+const DEFAULT_CAPACITY: usize = 256 * 1024 * 1024;
+
+static CACHE: LazyLock<IndexCache> = LazyLock::new(|| IndexCache::new(DEFAULT_CAPACITY));
+
+/// The index cache shared by all datasets.
+pub fn cache() -> &'static IndexCache {
+    &CACHE
+}
+
+/// The index borrowing from the serialized bytes it was deserialized from.
+#[derive(Yokeable)]
+struct BorrowedIndex<'a>(idx::Index<'a>);
+
+/// A deserialized index, together with the serialized bytes it borrows from.
+pub struct CachedIndex(Yoke<BorrowedIndex<'static>, Arc<Bytes>>);
+
+impl CachedIndex {
+    fn deserialize(bytes: Bytes) -> anyhow::Result<CachedIndex> {
+        let idx = Yoke::try_attach_to_cart(Arc::new(bytes), |bytes: &Bytes| {
+            bincode::deserialize::<idx::Index>(bytes).map(BorrowedIndex)
+        })?;
+
+        Ok(CachedIndex(idx))
+    }
+
+    pub fn index(&self) -> &idx::Index<'_> {
+        &self.0.get().0
+    }
+
+    /// Size of the serialized index.
+    fn size(&self) -> usize {
+        self.0.backing_cart().len()
+    }
+}
+
+type Key = (String, SystemTime);
+
+/// Size-bounded LRU cache of deserialized indexes.
+pub struct IndexCache(SizedLru<Key, Arc<CachedIndex>>);
+
+impl IndexCache {
+    /// A cache holding up to `capacity` bytes of indexes.
+    pub fn new(capacity: usize) -> IndexCache {
+        IndexCache(SizedLru::new(capacity))
+    }
+
+    /// Change the size of the cache, evicting indexes if it shrinks.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.0.set_capacity(capacity)
+    }
+
+    /// The index of the file at `path` (stored in the db as `idxkey`, and last modified at
+    /// `modified`), from the cache or the db.
+    pub async fn get(
+        &self,
+        db: &Db,
+        idxkey: &str,
+        modified: SystemTime,
+        path: &Path,
+    ) -> anyhow::Result<Arc<CachedIndex>> {
+        let key = (idxkey.to_string(), modified);
+
+        if let Some(idx) = self.0.get(&key) {
+            return Ok(idx);
+        }
+
+        let idx = match load(db, idxkey, path) {
+            Ok(idx) => idx,
+            Err(e) => {
+                warn!("Index of {:?} can not be used: {}, re-indexing..", path, e);
+                recover(db, idxkey, modified, path).await?
+            }
+        };
+        let idx = Arc::new(idx);
+
+        self.0.insert(key, Arc::clone(&idx), idx.size());
+
+        Ok(idx)
+    }
+
+    /// Drop the cached indexes with key `idxkey`, e.g. when the file has been re-indexed.
+    pub fn remove(&self, idxkey: &str) {
+        self.0.retain(|(k, _)| k != idxkey);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.0.stats()
+    }
+}
+
+fn load(db: &Db, idxkey: &str, path: &Path) -> anyhow::Result<CachedIndex> {
+    trace!("fetching index from db: {}", idxkey);
+    let bts = db
+        .index(idxkey)?
+        .ok_or_else(|| anyhow!("{:?} is not indexed", path))?;
+
+    CachedIndex::deserialize(bts)
+}
+
+/// Index the file at `path` again after its stored index could not be loaded. A file is
+/// re-indexed by one request at the time, and requests waiting for the same file use the index
+/// made by the first.
+async fn recover(
+    db: &Db,
+    idxkey: &str,
+    modified: SystemTime,
+    path: &Path,
+) -> anyhow::Result<CachedIndex> {
+    let lock = recovering(idxkey);
+    let _recovering = lock.lock().await;
+
+    if let Ok(idx) = load(db, idxkey, path) {
+        return Ok(idx);
+    }
+
+    let db = db.clone();
+    let idxkey = idxkey.to_string();
+    let path = path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        ensure!(
+            std::fs::metadata(&path)?.modified()? == modified,
+            "{:?} has changed on disk",
+            path
+        );
+
+        crate::hdf5::Hdf5Dataset::reindex(&path, &db)?;
+        load(&db, &idxkey, &path)
+    })
+    .await?
+}
+
+/// The lock for re-indexing the file with index key `idxkey`, shared by the requests recovering
+/// the same file.
+fn recovering(idxkey: &str) -> Arc<tokio::sync::Mutex<()>> {
+    static RECOVERING: LazyLock<Mutex<HashMap<String, Weak<tokio::sync::Mutex<()>>>>> =
+        LazyLock::new(Default::default);
+
+    let mut locks = RECOVERING.lock().unwrap();
+    locks.retain(|_, l| l.strong_count() > 0);
+
+    match locks.get(idxkey).and_then(Weak::upgrade) {
+        Some(lock) => lock,
+        None => {
+            let lock = Arc::new(tokio::sync::Mutex::new(()));
+            locks.insert(idxkey.to_string(), Arc::downgrade(&lock));
+            lock
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::test_db;
+
+    #[tokio::test]
+    async fn hits_and_evictions() {
+        let db = test_db();
+        let path = std::fs::canonicalize("../data/coads_climatology.nc4").unwrap();
+        crate::hdf5::Hdf5Dataset::open(&path, "coads".into(), &db).unwrap();
+
+        let idxkey = path.to_string_lossy().to_string();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+
+        let cache = IndexCache::new(DEFAULT_CAPACITY);
+        let idx = cache.get(&db, &idxkey, modified, &path).await.unwrap();
+        assert!(idx.index().dataset("SST").is_some());
+
+        cache.get(&db, &idxkey, modified, &path).await.unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+        assert_eq!(stats.hit_rate, 0.5);
+
+        // A changed file is a different entry.
+        cache
+            .get(&db, &idxkey, SystemTime::UNIX_EPOCH, &path)
+            .await
+            .unwrap();
+        assert_eq!(cache.stats().entries, 2);
+
+        cache.set_capacity(stats.size);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions), (1, 1));
+
+        cache.remove(&idxkey);
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.stats().size, 0);
+
+        assert!(cache
+            .get(&db, "missing", modified, Path::new("missing.nc4"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn recover_corrupt_index() {
+        let db = test_db();
+        let path = std::fs::canonicalize("../data/coads_climatology.nc4").unwrap();
+        crate::hdf5::Hdf5Dataset::open(&path, "coads".into(), &db).unwrap();
+
+        let idxkey = path.to_string_lossy().to_string();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let cache = IndexCache::new(DEFAULT_CAPACITY);
+
+        db.indexes().insert(&idxkey, b"garbage".to_vec()).unwrap();
+        let idx = cache.get(&db, &idxkey, modified, &path).await.unwrap();
+        assert!(idx.index().dataset("SST").is_some());
+        assert!(db.is_indexed(&idxkey, &path).unwrap());
+
+        cache.remove(&idxkey);
+        db.indexes().remove(&idxkey).unwrap();
+        assert!(cache.get(&db, &idxkey, modified, &path).await.is_ok());
+
+        // A file that has changed since the dataset was opened is not re-indexed.
+        cache.remove(&idxkey);
+        db.indexes().insert(&idxkey, b"garbage".to_vec()).unwrap();
+        assert!(cache
+            .get(&db, &idxkey, SystemTime::UNIX_EPOCH, &path)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! Indexing files without starting the server (`dars index`).
+//!
+//! The files are opened the same way as when they are loaded by the server: HDF5 and NetCDF4
+//! files are indexed, and the members of NcML aggregations are indexed and the aggregations
+//! cached. The indexes are written to the configured index store (see [crate::db]), so that the
+//! server does not have to index the files when it loads them. E.g. with the sidecar store the
+//! files can be indexed as soon as they are added, while the server is running.
+//!
+//! With `--check` nothing is written, and a file fails if it (or a member of an aggregation) is
+//! not indexed, or with `hash` set if its content no longer matches the index.
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use colored::Colorize;
+use rayon::prelude::*;
+
+use crate::db::Db;
+use crate::{data, hdf5, ncml};
+
+/// A file that could not be indexed.
+#[derive(Debug)]
+pub struct Failure {
+    pub path: PathBuf,
+    pub error: anyhow::Error,
+}
+
+/// The supported files at `paths`, directories are searched for files.
+pub fn files(paths: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for path in paths {
+        if path.is_dir() {
+            files.extend(data::scan(path));
+        } else if path.is_file() {
+            ensure!(data::is_supported(path), "unsupported file: {:?}", path);
+            files.push(path.clone());
+        } else {
+            return Err(anyhow!("no such file or directory: {:?}", path));
+        }
+    }
+
+    Ok(files)
+}
+
+/// Index (or with `check`, check the indexes of) `files`, at most `workers` at the time.
+/// Returns the files that failed.
+pub fn index(
+    files: &[PathBuf],
+    datadir: &Path,
+    db: &Db,
+    workers: usize,
+    check: bool,
+) -> anyhow::Result<Vec<Failure>> {
+    let db = if check { db.read_only()? } else { db.clone() };
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(workers.max(1))
+        .build()?;
+
+    info!(
+        "{} {} files with {} workers..",
+        if check { "Checking" } else { "Indexing" },
+        files.len(),
+        workers
+    );
+    let start = Instant::now();
+
+    let failures = pool.install(|| {
+        files
+            .par_iter()
+            .filter_map(|path| {
+                index_file(path, datadir, &db)
+                    .map_err(|error| {
+                        warn!(
+                            "Failed: {}, error: {}",
+                            path.to_string_lossy().blue(),
+                            error.to_string().red()
+                        );
+                        Failure {
+                            path: path.clone(),
+                            error,
+                        }
+                    })
+                    .err()
+            })
+            .collect::<Vec<_>>()
+    });
+
+    info!(
+        "Done with {} files in {:.1?} ({} failed).",
+        files.len(),
+        start.elapsed(),
+        failures.len()
+    );
+
+    Ok(failures)
+}
+
+/// Open the file at `path`, indexing it (or the members of the aggregation) if it is not already
+/// indexed.
+fn index_file(path: &Path, datadir: &Path, db: &Db) -> anyhow::Result<()> {
+    debug!("Indexing {}..", path.to_string_lossy().blue());
+
+    // The key is only used for naming the datasets, but is the same as in the server.
+    let key = path
+        .strip_prefix(datadir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string();
+
+    if path.extension().is_some_and(|ext| ext == "ncml") {
+        match ncml::aggregation_type(path)? {
+            ncml::AggregationType::JoinExisting => {
+                ncml::NcmlDataset::open(path, key, db.clone())?;
+            }
+            ncml::AggregationType::Fmrc => {
+                ncml::FmrcDataset::open(path, &key, db.clone())?;
+            }
+        }
+    } else {
+        hdf5::Hdf5Dataset::open(path, key, db)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::test_db;
+
+    #[test]
+    fn index_and_check() {
+        let db = test_db();
+        let datadir = Path::new("../data");
+        let files = files(&[
+            datadir.join("coads_climatology.nc4"),
+            datadir.join("ncml/aggExisting.ncml"),
+        ])
+        .unwrap();
+
+        let failures = index(&files, datadir, &db, 2, true).unwrap();
+        assert_eq!(failures.len(), 2);
+        assert!(failures[0].error.to_string().contains("is not indexed"));
+
+        assert!(index(&files, datadir, &db, 2, false).unwrap().is_empty());
+        assert!(index(&files, datadir, &db, 2, true).unwrap().is_empty());
+
+        assert!(super::files(&[datadir.join("missing.nc4")]).is_err());
+    }
+}
@@ -3,10 +3,16 @@ extern crate log;
 #[macro_use]
 extern crate anyhow;
 
+pub mod cache;
+pub mod chunks;
 pub mod config;
 pub mod data;
+pub mod db;
 pub mod hdf5;
+pub mod index;
+pub mod indexer;
 pub mod ncml;
+pub mod unpack;
 
 fn make_extents<E>(e: E) -> anyhow::Result<hidefix::extent::Extents>
 where
@@ -4,6 +4,8 @@ static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate anyhow;
 
 use colored::Colorize;
 use env_logger::Env;
@@ -12,7 +14,7 @@ use warp::Filter;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-use dars::{config, data};
+use dars::{chunks, config, data, db, index, indexer};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -22,22 +24,96 @@ async fn main() -> anyhow::Result<()> {
     #[cfg(debug_assertions)]
     info!("Debug build");
 
-    let config = config::load_config_with_args()?;
+    let (config, command) = config::load_config_with_args()?;
 
-    info!(
-        "Opening sled db: {}..",
-        config.db.path.to_string_lossy().yellow()
-    );
-    let db = sled::open(config.db.path)?;
+    let db = db::Db::open(&config.db, &config.data)?;
+
+    if let config::Command::Index { paths, check, gc } = command {
+        if gc {
+            db.gc()?;
+        }
+
+        let files = indexer::files(&paths)?;
+        let failures = indexer::index(&files, &config.data, &db, config.index.workers, check)?;
+
+        for f in &failures {
+            eprintln!("{}: {}", f.path.to_string_lossy(), f.error);
+        }
+
+        ensure!(
+            failures.is_empty(),
+            "{} of {} files failed",
+            failures.len(),
+            files.len()
+        );
+
+        return Ok(());
+    }
+
+    index::cache().set_capacity(config.index.cache_size * 1024 * 1024);
+    chunks::cache().set_capacity(config.chunks.cache_size * 1024 * 1024);
 
+    // Datasets are loaded (and indexed) in the background while the server is running.
     let data =
-        Arc::new(data::Datasets::new_with_datadir(config.root_url.clone(), config.data, db).await?);
+        Arc::new(data::Datasets::new(config.root_url.clone(), db).with_dds(config.dds.clone()));
+    data.spawn_load(
+        config.data.clone(),
+        config.ncml.clone(),
+        config.index.workers,
+    );
+
+    // Keep the watcher alive for as long as the server is running.
+    let _watcher = if config.watch.enabled {
+        Some(data::watch::watch(
+            data.clone(),
+            &config.data,
+            config.ncml.clone(),
+            &config.watch,
+        )?)
+    } else {
+        None
+    };
+
+    let admin = data::admin::admin(
+        data.clone(),
+        &config.data,
+        config.ncml.clone(),
+        config.admin.token.clone(),
+    )?;
+
+    if let Some(address) = config.admin.address {
+        if config.admin.token.is_none() {
+            warn!("The admin API has no token, anyone that can reach it can use it.");
+        }
+
+        info!(
+            "Admin API listening on {}",
+            format!("http://{}", address).yellow()
+        );
+        tokio::spawn(warp::serve(admin.clone()).run(address));
+    }
+
+    // Without a separate address the admin API is served under /admin, but only with a token.
+    let admin_on_main = config.admin.address.is_none() && config.admin.token.is_some();
+    let admin = warp::any()
+        .and_then(move || async move {
+            if admin_on_main {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .and(admin);
+
     let dars = data::filters::datasets(data.clone()).with(warp::log::custom(data::request_log));
 
     #[cfg(feature = "catalog")]
     let dars =
         dars_catalog::catalog(config.root_url.clone().unwrap_or_else(|| "".into()), data)?.or(dars);
 
+    let dars = admin.or(dars);
+
     info!(
         "Listening on {} {}",
         format!("http://{}", config.address).yellow(),
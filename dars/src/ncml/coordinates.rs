@@ -0,0 +1,403 @@
+//! The aggregated coordinate variable.
+//!
+//! Members may overlap, e.g. in forecast archives where each file starts before the previous one
+//! ends. The `overlap` attribute of the `aggregation` element selects how overlapping coordinate
+//! values are handled:
+//!
+//! * `overlap="keep"` (default): all values are concatenated in order.
+//! * `overlap="newest"`: overlapping values are taken from the newest member (the one starting
+//!   last), the values of the older members within its span are dropped.
+//! * `overlap="oldest"`: overlapping values are taken from the oldest member, the values of the
+//!   newer members within its span are dropped.
+//!
+//! The aggregated coordinate variable is validated when the dataset is loaded, duplicates,
+//! decreasing values and gaps are logged and added as global attributes (see
+//! [CoordinateReport::attributes]).
+use std::ops::Range;
+use std::str::FromStr;
+
+use bytes::Bytes;
+use futures::Stream;
+
+use super::member::NcmlMember;
+use crate::hdf5::das::h5attr_to_das;
+use crate::hdf5::dds::hdf5_vartype;
+use dap2::das::{AttrValue, Attribute};
+use dap2::dds::VarType;
+
+/// How overlapping coordinate values between members are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Overlap {
+    #[default]
+    Keep,
+    Newest,
+    Oldest,
+}
+
+impl FromStr for Overlap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Overlap, Self::Err> {
+        match s {
+            "keep" => Ok(Overlap::Keep),
+            "newest" => Ok(Overlap::Newest),
+            "oldest" => Ok(Overlap::Oldest),
+            s => Err(anyhow!(
+                "unknown overlap: {}, expected 'keep', 'newest' or 'oldest'",
+                s
+            )),
+        }
+    }
+}
+
+/// A run of coordinate values of one member used in the aggregation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// Index of the member.
+    pub member: usize,
+    /// Indices of the values along the aggregation dimension in the member.
+    pub used: Range<usize>,
+}
+
+impl Overlap {
+    /// The segments of the members (sorted by their first coordinate value) that make up the
+    /// aggregation, in order. Only the values that fall within the span of a newer (`Newest`) or
+    /// older (`Oldest`) member are left out, so a member may be split around a member that it
+    /// overlaps completely. Members that are completely overlapped have no segments.
+    pub fn apply(&self, members: &[NcmlMember]) -> Vec<Segment> {
+        let spans = members
+            .iter()
+            .map(|m| match (m.coordinates.first(), m.coordinates.last()) {
+                (Some(first), Some(last)) => Some((*first, *last)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut segments = Vec::new();
+
+        for (i, m) in members.iter().enumerate() {
+            let others = match self {
+                Overlap::Keep => &spans[..0],
+                Overlap::Newest => &spans[i + 1..],
+                Overlap::Oldest => &spans[..i],
+            };
+            let others = others.iter().flatten().collect::<Vec<_>>();
+
+            let overlapped = |v: f64| others.iter().any(|(a, b)| *a <= v && v <= *b);
+            let between = |v: f64, w: f64| others.iter().any(|(a, b)| *a < w && v < *b);
+
+            let mut start: Option<usize> = None;
+            for (j, v) in m.coordinates.iter().enumerate() {
+                if overlapped(*v) {
+                    if let Some(s) = start.take() {
+                        segments.push(Segment {
+                            member: i,
+                            used: s..j,
+                        });
+                    }
+                    continue;
+                }
+
+                match start {
+                    // Another member fits between this and the previous value.
+                    Some(s) if between(m.coordinates[j - 1], *v) => {
+                        segments.push(Segment {
+                            member: i,
+                            used: s..j,
+                        });
+                        start = Some(j);
+                    }
+                    Some(_) => {}
+                    None => start = Some(j),
+                }
+            }
+
+            if let Some(s) = start {
+                segments.push(Segment {
+                    member: i,
+                    used: s..m.coordinates.len(),
+                });
+            }
+
+            let used: usize = segments
+                .iter()
+                .filter(|s| s.member == i)
+                .map(|s| s.used.len())
+                .sum();
+            if used != m.coordinates.len() {
+                debug!(
+                    "Member {:?} overlaps, using {} of {}",
+                    m.path,
+                    used,
+                    m.coordinates.len()
+                );
+            }
+        }
+
+        // Segments of older members may come after newer members.
+        segments.sort_by(|a, b| {
+            members[a.member].coordinates[a.used.start]
+                .partial_cmp(&members[b.member].coordinates[b.used.start])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        segments
+    }
+}
+
+/// Problems found when validating the aggregated coordinate variable.
+#[derive(Debug, Default, PartialEq)]
+pub struct CoordinateReport {
+    /// Values that occur more than once.
+    pub duplicates: Vec<f64>,
+    /// Values where the coordinate decreases, with the value before.
+    pub decreasing: Vec<(f64, f64)>,
+    /// Steps that are more than 1.5 times the median step, given by the values before and after.
+    pub gaps: Vec<(f64, f64)>,
+}
+
+impl CoordinateReport {
+    pub fn from(values: &[f64]) -> CoordinateReport {
+        let mut report = CoordinateReport::default();
+
+        let mut steps = values
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .filter(|s| *s > 0.)
+            .collect::<Vec<_>>();
+        steps.sort_by(|a, b| a.total_cmp(b));
+        let median = steps.get(steps.len() / 2).copied();
+
+        for w in values.windows(2) {
+            let step = w[1] - w[0];
+
+            if step == 0. {
+                report.duplicates.push(w[1]);
+            } else if step < 0. {
+                report.decreasing.push((w[0], w[1]));
+            } else if median.is_some_and(|median| step > 1.5 * median) {
+                report.gaps.push((w[0], w[1]));
+            }
+        }
+
+        report
+    }
+
+    pub fn is_monotonic(&self) -> bool {
+        self.duplicates.is_empty() && self.decreasing.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.is_monotonic() && self.gaps.is_empty()
+    }
+
+    /// Global attributes describing the problems: `aggregation_duplicates` with the duplicated
+    /// values, and `aggregation_decreasing` and `aggregation_gaps` with pairs of values before
+    /// and after.
+    pub fn attributes(&self) -> Vec<Attribute> {
+        let pairs = |v: &[(f64, f64)]| v.iter().flat_map(|(a, b)| [*a, *b]).collect::<Vec<_>>();
+
+        [
+            ("aggregation_duplicates", self.duplicates.clone()),
+            ("aggregation_decreasing", pairs(&self.decreasing)),
+            ("aggregation_gaps", pairs(&self.gaps)),
+        ]
+        .into_iter()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(name, v)| Attribute {
+            name: name.to_string(),
+            value: AttrValue::Doubles(v),
+        })
+        .collect()
+    }
+
+    pub fn log(&self, key: &str, dimension: &str) {
+        if !self.duplicates.is_empty() {
+            warn!(
+                "{}: {} duplicate values in aggregated coordinate variable {}: {:?}",
+                key,
+                self.duplicates.len(),
+                dimension,
+                self.duplicates
+            );
+        }
+
+        if !self.decreasing.is_empty() {
+            warn!(
+                "{}: aggregated coordinate variable {} is not monotonic, decreasing at: {:?}",
+                key, dimension, self.decreasing
+            );
+        }
+
+        if !self.gaps.is_empty() {
+            warn!(
+                "{}: {} gaps in aggregated coordinate variable {}: {:?}",
+                key,
+                self.gaps.len(),
+                dimension,
+                self.gaps
+            );
+        }
+    }
+}
+
+/// The coordinate variable is kept in memory since it is always requested and requires all files
+/// to be opened and read.
+pub struct CoordinateVariable {
+    /// Values, converted to the units of the aggregation.
+    pub values: Vec<f64>,
+    bytes: Bytes,
+    /// Data type size
+    dsz: usize,
+}
+
+impl CoordinateVariable {
+    /// Concatenate the coordinate values of the segments.
+    pub fn from(
+        members: &[NcmlMember],
+        segments: &[Segment],
+        vartype: VarType,
+    ) -> anyhow::Result<CoordinateVariable> {
+        let values = segments
+            .iter()
+            .flat_map(|s| {
+                members[s.member].coordinates[s.used.clone()]
+                    .iter()
+                    .copied()
+            })
+            .collect::<Vec<_>>();
+
+        let bytes = Bytes::from(dap2::dods::xdr::xdr_encode_f64(vartype, &values)?);
+        let dsz = vartype.xdr_size();
+
+        Ok(CoordinateVariable { values, bytes, dsz })
+    }
+
+    pub fn stream_xdr(
+        &self,
+        indices: &[u64],
+        counts: &[u64],
+    ) -> Result<impl Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static, anyhow::Error>
+    {
+        ensure!(
+            indices.len() == 1 && counts.len() == 1,
+            "coordinate dimension is always 1 dimension"
+        );
+
+        let start = indices[0] as usize * self.dsz;
+        let end = (indices[0] + counts[0]) as usize * self.dsz;
+        ensure!(end <= self.bytes.len(), "slab out of range");
+
+        let bytes = self.bytes.slice(start..end);
+        Ok(futures::stream::once(async { Ok(bytes) }))
+    }
+}
+
+/// Attributes of the coordinate variable `dataset` when the values are served as `Float64`, the
+/// fill value and the valid and actual ranges are converted to doubles.
+pub fn float64_attributes(dataset: &hdf5::Dataset) -> anyhow::Result<Vec<Attribute>> {
+    let mut attributes = Vec::new();
+
+    for name in dataset.attr_names()? {
+        let a = dataset.attr(&name)?;
+
+        let numeric = !matches!(
+            hdf5_vartype(&a.dtype()?),
+            VarType::String(_) | VarType::Structure(_) | VarType::Unimplemented
+        );
+
+        attributes.push(match name.as_str() {
+            "_FillValue" | "missing_value" | "valid_min" | "valid_max" | "valid_range"
+            | "actual_range"
+                if numeric =>
+            {
+                let mut values = a.read_raw::<f64>()?;
+                Attribute {
+                    name,
+                    value: match values.len() {
+                        1 => AttrValue::Double(values.remove(0)),
+                        _ => AttrValue::Doubles(values),
+                    },
+                }
+            }
+            _ => h5attr_to_das(&name, a),
+        });
+    }
+
+    Ok(attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(coordinates: Vec<f64>) -> NcmlMember {
+        NcmlMember {
+            path: "test.nc".into(),
+            idxkey: "test.nc".into(),
+            modified: std::time::SystemTime::UNIX_EPOCH,
+            n: coordinates.len(),
+            rank: coordinates[0],
+            units: Default::default(),
+            coordinates,
+            reference_time: None,
+            schema: Default::default(),
+            fill: Default::default(),
+            layouts: Default::default(),
+            dataset: None,
+        }
+    }
+
+    fn used(segments: &[Segment]) -> Vec<(usize, Range<usize>)> {
+        segments
+            .iter()
+            .map(|s| (s.member, s.used.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn overlap() {
+        let members = vec![
+            member(vec![0., 1., 2., 3.]),
+            member(vec![2., 3., 4., 5.]),
+            member(vec![3., 4.]),
+        ];
+
+        let keep = Overlap::Keep.apply(&members);
+        assert_eq!(used(&keep), [(0, 0..4), (1, 0..4), (2, 0..2)]);
+
+        // The newest member splits the one before it, the last value of which is kept.
+        let newest = Overlap::Newest.apply(&members);
+        assert_eq!(used(&newest), [(0, 0..2), (1, 0..1), (2, 0..2), (1, 3..4)]);
+
+        let oldest = Overlap::Oldest.apply(&members);
+        assert_eq!(used(&oldest), [(0, 0..4), (1, 2..4)]);
+
+        for segments in [newest, oldest] {
+            let c = CoordinateVariable::from(&members, &segments, VarType::Int32).unwrap();
+            assert_eq!(c.values, [0., 1., 2., 3., 4., 5.]);
+            assert!(CoordinateReport::from(&c.values).is_empty());
+        }
+
+        // A member within the gap of an older member.
+        let members = vec![member(vec![0., 10.]), member(vec![4., 5.])];
+        let newest = Overlap::Newest.apply(&members);
+        assert_eq!(used(&newest), [(0, 0..1), (1, 0..2), (0, 1..2)]);
+
+        assert!("sometimes".parse::<Overlap>().is_err());
+    }
+
+    #[test]
+    fn report() {
+        let r = CoordinateReport::from(&[0., 1., 2., 2., 3., 6., 7., 5.]);
+        assert_eq!(r.duplicates, [2.]);
+        assert_eq!(r.decreasing, [(7., 5.)]);
+        assert_eq!(r.gaps, [(3., 6.)]);
+        assert!(!r.is_monotonic());
+        assert_eq!(r.attributes().len(), 3);
+
+        assert!(CoordinateReport::from(&[0., 1., 2.])
+            .attributes()
+            .is_empty());
+    }
+}
@@ -0,0 +1,64 @@
+use dap2::das::{self, ToDas};
+
+use crate::hdf5::HDF5File;
+
+/// DAS of the first member with additional global attributes describing the aggregation, and
+/// possibly additional variables.
+pub struct NcmlDasBuilder<'a> {
+    file: &'a HDF5File,
+    attributes: Vec<das::Attribute>,
+    variables: Vec<(String, Vec<das::Attribute>)>,
+}
+
+impl<'a> NcmlDasBuilder<'a> {
+    pub fn new(file: &'a HDF5File, attributes: Vec<das::Attribute>) -> NcmlDasBuilder<'a> {
+        NcmlDasBuilder {
+            file,
+            attributes,
+            variables: Vec::new(),
+        }
+    }
+
+    /// Add a variable which is not in the first member.
+    pub fn with_variable(
+        mut self,
+        name: &str,
+        attributes: Vec<das::Attribute>,
+    ) -> NcmlDasBuilder<'a> {
+        self.variables.push((name.to_string(), attributes));
+        self
+    }
+}
+
+impl ToDas for NcmlDasBuilder<'_> {
+    fn has_global_attributes(&self) -> bool {
+        self.file.has_global_attributes() || !self.attributes.is_empty()
+    }
+
+    fn global_attributes(&self) -> Box<dyn Iterator<Item = das::Attribute>> {
+        let attributes: Vec<das::Attribute> = if self.file.has_global_attributes() {
+            self.file.global_attributes().collect()
+        } else {
+            Vec::new()
+        };
+
+        Box::new(attributes.into_iter().chain(self.attributes.clone()))
+    }
+
+    fn variables(&self) -> Box<dyn Iterator<Item = String>> {
+        let variables = self
+            .variables
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+
+        Box::new(self.file.variables().chain(variables))
+    }
+
+    fn variable_attributes(&self, variable: &str) -> Box<dyn Iterator<Item = das::Attribute>> {
+        match self.variables.iter().find(|(name, _)| name == variable) {
+            Some((_, attributes)) => Box::new(attributes.clone().into_iter()),
+            None => self.file.variable_attributes(variable),
+        }
+    }
+}
@@ -21,6 +21,8 @@ impl NcmlDdsBuilder {
 
 impl dds::ToDds for NcmlDdsBuilder {
     fn variables(&self) -> Vec<Variable> {
+        let phony = hdf5dds::phony_dimensions(&self.file);
+
         self.file
             .group("/")
             .unwrap()
@@ -29,17 +31,16 @@ impl dds::ToDds for NcmlDdsBuilder {
             .iter()
             .map(|m| self.file.dataset(m).map(|d| (m, d)))
             .filter_map(Result::ok)
-            .filter(|(_, d)| d.is_chunked() || d.offset().is_some()) // skipping un-allocated datasets.
             .map(|(m, d)| {
                 trace!(
                     "Variable: {} {:?}",
                     m,
                     hdf5dds::hdf5_vartype(&d.dtype().unwrap())
                 );
-                let dimensions = hdf5dds::hdf5_dimensions(m, &d);
+                let dimensions = hdf5dds::hdf5_dimensions(m, &d, &phony);
                 let mut shape = d.shape();
-                if !dimensions.is_empty() && dimensions[0] == self.dimension {
-                    shape[0] = self.n;
+                if let Some(p) = dimensions.iter().position(|d| *d == self.dimension) {
+                    shape[p] = self.n;
                 }
                 Variable::new(
                     m.clone(),
@@ -55,3 +56,75 @@ impl dds::ToDds for NcmlDdsBuilder {
         self.key.clone()
     }
 }
+
+/// DDS of a forecast model run collection: aggregated variables get a leading `run` dimension and
+/// the aggregation dimension becomes the longest forecast in the collection.
+pub struct FmrcDdsBuilder {
+    file: hdf5::File,
+    key: String,
+    dimension: String,
+    runs: usize,
+    n: usize,
+}
+
+impl FmrcDdsBuilder {
+    pub fn new(
+        file: hdf5::File,
+        key: String,
+        dimension: String,
+        runs: usize,
+        n: usize,
+    ) -> FmrcDdsBuilder {
+        FmrcDdsBuilder {
+            file,
+            key,
+            dimension,
+            runs,
+            n,
+        }
+    }
+}
+
+impl dds::ToDds for FmrcDdsBuilder {
+    fn variables(&self) -> Vec<Variable> {
+        let phony = hdf5dds::phony_dimensions(&self.file);
+
+        self.file
+            .group("/")
+            .unwrap()
+            .member_names()
+            .unwrap()
+            .iter()
+            .map(|m| self.file.dataset(m).map(|d| (m, d)))
+            .filter_map(Result::ok)
+            .map(|(m, d)| {
+                let mut vartype = hdf5dds::hdf5_vartype(&d.dtype().unwrap());
+                let mut dimensions = hdf5dds::hdf5_dimensions(m, &d, &phony);
+                let mut shape = d.shape();
+
+                if !dimensions.is_empty() && dimensions[0] == self.dimension {
+                    shape[0] = self.n;
+                    shape.insert(0, self.runs);
+                    dimensions.insert(0, super::fmrc::RUN.to_string());
+
+                    // The coordinate variable is padded with NaN's for shorter runs.
+                    if *m == self.dimension {
+                        vartype = dds::VarType::Float64;
+                    }
+                }
+
+                Variable::new(m.clone(), vartype, dimensions, shape)
+            })
+            .chain(std::iter::once(Variable::new(
+                super::fmrc::RUN.to_string(),
+                dds::VarType::Float64,
+                vec![super::fmrc::RUN.to_string()],
+                vec![self.runs],
+            )))
+            .collect()
+    }
+
+    fn file_name(&self) -> String {
+        self.key.clone()
+    }
+}
@@ -0,0 +1,460 @@
+//! # Forecast model run collections (FMRC)
+//!
+//! An aggregation of type `forecastModelRunCollection` combines forecast files, each containing
+//! one model run, along the forecast time dimension (`dimName`):
+//!
+//! ```xml
+//! <netcdf xmlns="http://www.unidata.ucar.edu/namespaces/netcdf/ncml-2.2">
+//!   <aggregation dimName="time" type="forecastModelRunCollection">
+//!     <scan location="runs/" suffix=".nc"/>
+//!   </aggregation>
+//! </netcdf>
+//! ```
+//!
+//! The run of each file is given by its `forecast_reference_time` variable, or by the first
+//! forecast time if it is missing. The collection is served as two datasets under the path of the
+//! NcML file:
+//!
+//! * `<path>/2d`: all runs. Variables along the aggregation dimension get a leading `run`
+//!   dimension, and the aggregation dimension is as long as the longest run. Shorter runs are
+//!   padded with fill values. The coordinate variable is `run × time` (Float64, padded with NaN).
+//! * `<path>/best`: the best estimate time series. A `joinExisting` aggregation of the runs where
+//!   overlapping forecast times are taken from the newest run.
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+
+use super::prefetch::{stream_slabs, Slab};
+use super::{
+    das::NcmlDasBuilder, dds::FmrcDdsBuilder, fill_values, Aggregation, AggregationType,
+    NcmlDataset, NcmlMember, Overlap, SchemaReport,
+};
+use crate::db::Db;
+use crate::hdf5::HDF5File;
+use crate::unpack::Packed;
+use dap2::das::{AttrValue, Attribute};
+use dap2::dds::{DdsVariableDetails, VarType};
+use dap2::dods::xdr::xdr_encode_f64;
+
+/// Name of the run dimension and variable.
+pub const RUN: &str = "run";
+
+/// The 2D (`run × time`) dataset of a forecast model run collection.
+pub struct FmrcDataset {
+    path: PathBuf,
+    key: String,
+    das: dap2::Das,
+    dds: dap2::Dds,
+    /// Aggregation dimension (forecast time).
+    dimension: String,
+    /// Length of the longest run.
+    n: usize,
+    /// Reference times of the runs (XDR encoded Float64).
+    runs: Bytes,
+    /// Forecast times of the runs, `runs × n` (XDR encoded Float64).
+    times: Bytes,
+    /// Fill values of the variables in the first member.
+    fill_values: HashMap<String, f64>,
+    modified: std::time::SystemTime,
+    /// One member for each run, sorted by reference time.
+    members: Arc<Vec<NcmlMember>>,
+    /// Number of runs to read ahead when streaming aggregated variables.
+    prefetch: usize,
+    /// Packed variables that are served unpacked, see [FmrcDataset::with_unpack].
+    packed: Option<Packed>,
+    db: Db,
+}
+
+impl fmt::Debug for FmrcDataset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FmrcDataset <{:?}>", self.path)
+    }
+}
+
+impl FmrcDataset {
+    /// Open the collection, returning the 2D dataset and the best estimate time series.
+    pub fn open<P>(path: P, key: &str, db: Db) -> anyhow::Result<(FmrcDataset, NcmlDataset)>
+    where
+        P: AsRef<Path>,
+    {
+        FmrcDataset::open_nested(path, key, db, &[])
+    }
+
+    /// Open a collection nested in the NcML files `parents`, see [NcmlMember::open_nested].
+    pub(super) fn open_nested<P>(
+        path: P,
+        key: &str,
+        db: Db,
+        parents: &[PathBuf],
+    ) -> anyhow::Result<(FmrcDataset, NcmlDataset)>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let modified = std::fs::metadata(path)?.modified()?;
+        info!("Loading forecast model run collection {:?}..", path);
+
+        let aggregation = Aggregation::parse(path)?;
+        ensure!(
+            aggregation.kind == AggregationType::Fmrc,
+            "expected 'forecastModelRunCollection' aggregation"
+        );
+        if aggregation.recheck.is_some() {
+            warn!(
+                "{:?}: recheckEvery is not supported for FMRC, ignoring.",
+                path
+            );
+        }
+
+        let (cache_key, mut members, units) =
+            NcmlDataset::load_members(path, &aggregation, &db, parents, false)?;
+        ensure!(!members.is_empty(), "no members in aggregate.");
+
+        members.sort_by(|a, b| a.run().total_cmp(&b.run()));
+        if members.windows(2).any(|w| w[0].run() == w[1].run()) {
+            warn!("{:?}: several members belong to the same run.", path);
+        }
+
+        let best = NcmlDataset::from_members(
+            path,
+            format!("{}/best", key),
+            modified,
+            Aggregation {
+                kind: AggregationType::JoinExisting,
+                recheck: None,
+                overlap: Overlap::Newest,
+                ..aggregation.clone()
+            },
+            cache_key,
+            members.clone(),
+            units.clone(),
+            db.clone(),
+        )?;
+
+        let dimension = aggregation.dimension;
+        let key = format!("{}/2d", key);
+
+        let schema = SchemaReport::validate(&mut members, &dimension);
+        schema.log(&key);
+        let n = members.iter().map(|m| m.n).max().unwrap_or(0);
+
+        let runs = members.iter().map(|m| m.run()).collect::<Vec<_>>();
+        let runs = Bytes::from(xdr_encode_f64(VarType::Float64, &runs)?);
+
+        let times = members
+            .iter()
+            .flat_map(|m| {
+                m.coordinates
+                    .iter()
+                    .copied()
+                    .chain(std::iter::repeat(f64::NAN))
+                    .take(n)
+            })
+            .collect::<Vec<_>>();
+        let times = Bytes::from(xdr_encode_f64(VarType::Float64, &times)?);
+
+        let hf = HDF5File(hdf5::File::open(members[0].file())?, key.clone());
+
+        let fill_values = fill_values(&hf.0)?;
+
+        trace!("Building DAS..");
+        let run_attributes = [
+            ("standard_name", "forecast_reference_time"),
+            ("long_name", "model run"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name, Some(value.to_string())))
+        .chain([("units", units.units.clone()), ("calendar", units.calendar)])
+        .filter_map(|(name, value)| {
+            value.map(|value| Attribute {
+                name: name.to_string(),
+                value: AttrValue::Str(value),
+            })
+        })
+        .collect();
+
+        let das = NcmlDasBuilder::new(&hf, schema.attributes())
+            .with_variable(RUN, run_attributes)
+            .into();
+
+        trace!("Building DDS..");
+        let dds = FmrcDdsBuilder::new(
+            hdf5::File::open(members[0].file())?,
+            key.clone(),
+            dimension.clone(),
+            members.len(),
+            n,
+        )
+        .into();
+
+        Ok((
+            FmrcDataset {
+                path: path.into(),
+                key,
+                das,
+                dds,
+                dimension,
+                n,
+                runs,
+                times,
+                fill_values,
+                modified,
+                members: Arc::new(members),
+                prefetch: crate::config::Ncml::default().prefetch,
+                packed: None,
+                db,
+            },
+            best,
+        ))
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Whether the NcML file or any of the runs have changed on disk since the collection was
+    /// opened.
+    pub fn is_modified(&self) -> bool {
+        !std::fs::metadata(&self.path)
+            .and_then(|md| md.modified())
+            .is_ok_and(|modified| modified == self.modified)
+            || self.members.iter().any(NcmlMember::is_modified)
+    }
+
+    /// Remove the indexes of runs that have changed on disk from the db.
+    pub fn invalidate(&self) -> anyhow::Result<()> {
+        self.members.iter().try_for_each(|m| m.invalidate(&self.db))
+    }
+
+    /// Set the number of runs to read ahead concurrently when streaming aggregated variables.
+    pub fn with_prefetch(mut self, prefetch: usize) -> FmrcDataset {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// Serve index coordinates for the dimensions without coordinate variables, see
+    /// [crate::hdf5::add_index_coordinates].
+    pub fn with_index_coordinates(mut self, enabled: bool) -> FmrcDataset {
+        if enabled {
+            crate::hdf5::add_index_coordinates(&mut self.das, &mut self.dds);
+        }
+        self
+    }
+
+    /// Serve the packed variables unpacked, see [crate::unpack].
+    pub fn with_unpack(mut self, enabled: bool) -> anyhow::Result<FmrcDataset> {
+        if enabled && self.packed.is_none() {
+            let mut packed = Packed::from_file(&hdf5::File::open(self.members[0].file())?)?;
+            packed.remove(&self.dimension);
+            packed.apply(&mut self.das, &mut self.dds);
+            self.packed = Some(packed);
+        }
+        Ok(self)
+    }
+
+    /// The variables that are served unpacked.
+    pub fn packed(&self) -> Option<&Packed> {
+        self.packed.as_ref()
+    }
+}
+
+#[async_trait]
+impl dap2::Dap2 for FmrcDataset {
+    async fn das(&self) -> &dap2::Das {
+        &self.das
+    }
+
+    async fn dds(&self) -> &dap2::Dds {
+        &self.dds
+    }
+
+    async fn raw(
+        &self,
+    ) -> Result<
+        (
+            u64,
+            Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static>>,
+        ),
+        std::io::Error,
+    > {
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    }
+}
+
+#[async_trait]
+impl dap2::DodsXdr for FmrcDataset {
+    async fn variable_xdr(
+        &self,
+        variable: &DdsVariableDetails,
+    ) -> Result<
+        Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>,
+        anyhow::Error,
+    > {
+        if self.modified != std::fs::metadata(&self.path)?.modified()? {
+            warn!("{:?} has changed on disk", self.path);
+            return Err(anyhow!("{:?} has changed on disk", self.path));
+        }
+
+        debug!(
+            "streaming: {} [{:?} / {:?}]",
+            variable.name, variable.indices, variable.counts
+        );
+
+        let indices: Vec<u64> = variable.indices.iter().map(|c| *c as u64).collect();
+        let counts: Vec<u64> = variable.counts.iter().map(|c| *c as u64).collect();
+
+        let db = self.db.clone();
+
+        Ok(if variable.name == RUN {
+            ensure!(indices.len() == 1, "run is always 1 dimension");
+
+            let start = indices[0] as usize * 8;
+            let end = (indices[0] + counts[0]) as usize * 8;
+            ensure!(end <= self.runs.len(), "slab out of range");
+
+            let bytes = self.runs.slice(start..end);
+            futures::stream::once(async { Ok(bytes) }).boxed()
+        } else if variable.name == self.dimension {
+            ensure!(indices.len() == 2, "forecast time is always 2 dimensions");
+
+            let (r, t) = (indices[0] as usize, indices[1] as usize);
+            let (rc, tc) = (counts[0] as usize, counts[1] as usize);
+            ensure!(
+                r + rc <= self.members.len() && t + tc <= self.n,
+                "slab out of range"
+            );
+
+            let mut bytes = BytesMut::with_capacity(rc * tc * 8);
+            for run in r..(r + rc) {
+                let start = (run * self.n + t) * 8;
+                bytes.extend_from_slice(&self.times[start..(start + tc * 8)]);
+            }
+
+            let bytes = bytes.freeze();
+            futures::stream::once(async { Ok(bytes) }).boxed()
+        } else if variable
+            .dimensions
+            .first()
+            .map(|d| d.0 != RUN)
+            .unwrap_or(true)
+        {
+            // Non-aggregated variable, using first member.
+            self.members[0]
+                .stream_xdr(
+                    &variable.name,
+                    variable.vartype,
+                    db,
+                    indices.as_slice(),
+                    counts.as_slice(),
+                )
+                .await?
+                .boxed()
+        } else {
+            // Aggregated variable, one run at the time.
+            let var = variable.name.clone();
+
+            let fill = self.fill_values.get(&var).copied().unwrap_or(f64::NAN);
+            let fill = xdr_encode_f64(variable.vartype, &[fill])?;
+
+            let (r, t) = (indices[0] as usize, indices[1]);
+            let (rc, tc) = (counts[0] as usize, counts[1]);
+
+            // Elements in each forecast time.
+            let sz: u64 = counts[2..].iter().product();
+
+            let mut slabs = Vec::new();
+
+            for (i, m) in self.members.iter().enumerate().skip(r).take(rc) {
+                if m.fill.contains(&var) {
+                    trace!("Run {} is missing {}", m.run(), var);
+                    slabs.push(Slab::Fill(Bytes::from(fill.repeat((tc * sz) as usize))));
+                    continue;
+                }
+
+                let available = min(tc, (m.n as u64).saturating_sub(t));
+
+                if available > 0 {
+                    let mut mcounts = counts[1..].to_vec();
+                    mcounts[0] = available;
+
+                    slabs.push(Slab::Member {
+                        member: i,
+                        indices: indices[1..].to_vec(),
+                        counts: mcounts,
+                    });
+                }
+
+                if available < tc {
+                    trace!(
+                        "Padding run {} with {} forecast times",
+                        m.run(),
+                        tc - available
+                    );
+                    slabs.push(Slab::Fill(Bytes::from(
+                        fill.repeat(((tc - available) * sz) as usize),
+                    )));
+                }
+            }
+
+            stream_slabs(
+                Arc::clone(&self.members),
+                var,
+                variable.vartype,
+                db,
+                slabs,
+                self.prefetch,
+            )
+            .boxed()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::test_db;
+    use dap2::DodsXdr;
+    use futures::TryStreamExt;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fmrc_2d_and_best() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = test_db();
+        let (fmrc, best) = FmrcDataset::open("../data/ncml/fmrc.ncml", "fmrc.ncml", db).unwrap();
+
+        assert_eq!(fmrc.key(), "fmrc.ncml/2d");
+        assert_eq!(best.key(), "fmrc.ncml/best");
+
+        assert_eq!(fmrc.members.len(), 2);
+        assert_eq!(fmrc.n, 31);
+        assert_eq!(fmrc.times.len(), 2 * 31 * 8);
+        assert!(fmrc.dds.all().to_string().contains("[run = 2][time = 31]"));
+        assert!(fmrc.das.as_str().contains("forecast_reference_time"));
+
+        // February is padded with 3 fill values.
+        let c = dap2::Constraint::parse("time[1][0:30]").unwrap();
+        let time = match fmrc.dds.dds(&c).unwrap().variables.remove(0) {
+            dap2::dds::ConstrainedVariable::Variable(time) => time,
+            _ => panic!("time should not be a grid"),
+        };
+        let bytes: Vec<Bytes> = fmrc
+            .variable_xdr(&time)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let values = dap2::dods::xdr::xdr_decode_f64(VarType::Float64, &bytes.concat()).unwrap();
+        assert_eq!(values[0], 31.);
+        assert!(values[28..].iter().all(|v| v.is_nan()));
+
+        assert_eq!(best.coordinate_values().unwrap().len(), 31 + 28);
+    }
+}
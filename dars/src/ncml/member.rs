@@ -1,28 +1,79 @@
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
 
-use async_stream::stream;
 use bytes::Bytes;
-use futures::{pin_mut, Stream, StreamExt};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 
+use super::schema::Schema;
+use super::units::Units;
+use super::{Aggregation, AggregationType, FmrcDataset, NcmlDataset};
+use crate::chunks::{self, Layout};
+use crate::db::Db;
+use dap2::dds::VarType;
 use hidefix::idx;
 
-/// One member of the NCML dataset.
+/// One member of the NCML dataset. A member is either a HDF5 (or netCDF4) file, or another NcML
+/// aggregation (see [NcmlMember::open_nested]).
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NcmlMember {
     pub path: PathBuf,
     pub idxkey: String,
     pub modified: std::time::SystemTime,
     pub n: usize,
     pub rank: f64,
+    /// Units of the aggregation dimension.
+    pub units: Units,
+    /// Values of the aggregation dimension, converted to the units of the aggregation. Empty
+    /// until they are read, see [NcmlMember::read_coordinates].
+    pub coordinates: Vec<f64>,
+    /// The `forecast_reference_time` (model run) of forecast files, in the same units as the
+    /// coordinate values.
+    pub reference_time: Option<f64>,
+    /// Type, dimensions and shape of the variables.
+    pub schema: HashMap<String, Schema>,
+    /// Variables that are missing or incompatible in this member and are served as fill values.
+    pub fill: HashSet<String>,
+    /// Chunking of the chunked variables, used with the chunk cache.
+    pub layouts: HashMap<String, Layout>,
+    /// The aggregation if the member is a nested NcML file. Not cached, it is re-opened (from its
+    /// own cache) when the aggregation is loaded from the db, see [NcmlMember::resolve].
+    #[serde(skip)]
+    pub dataset: Option<Arc<NcmlDataset>>,
 }
 
 impl NcmlMember {
-    pub fn open<P>(path: P, dimension: &str, db: &sled::Db) -> anyhow::Result<NcmlMember>
+    pub fn open<P>(path: P, dimension: &str, db: &Db) -> anyhow::Result<NcmlMember>
+    where
+        P: AsRef<Path>,
+    {
+        NcmlMember::open_nested(path, dimension, db, &[])
+    }
+
+    /// Open a member of an aggregation nested in the NcML files `parents` (canonical paths, the
+    /// outermost first). Members that are NcML files are opened as datasets (like they would be
+    /// when served): `joinExisting` aggregations as they are, and forecast model run collections
+    /// as their best estimate time series. They must be aggregated along the same dimension.
+    pub fn open_nested<P>(
+        path: P,
+        dimension: &str,
+        db: &Db,
+        parents: &[PathBuf],
+    ) -> anyhow::Result<NcmlMember>
     where
         P: AsRef<Path>,
     {
         let path = path.as_ref();
         debug!("Opening member: {:?}", path);
 
+        if super::is_ncml(path) {
+            let dataset = open_dataset(path, dimension, db, parents)?;
+            return NcmlMember::from_dataset(path, dataset, parents);
+        }
+
         let modified = std::fs::metadata(path)?.modified()?;
 
         let hf = hdf5::File::open(path)?;
@@ -31,20 +82,36 @@ impl NcmlMember {
         let agg = hf.dataset(dimension)?;
         let n = agg.size();
 
-        // Read first value of aggregate dimension
+        // Read first value of aggregate dimension, the rest are read with the coordinate variable
+        // (see [NcmlMember::read_coordinates]).
         let rank: f64 = *agg
             .read_slice_1d::<f64, _>(0..1)?
             .get(0)
             .ok_or_else(|| anyhow!("aggregate dimension is empty"))?;
 
-        let idxkey = path.to_string_lossy().to_string();
-        if !db.contains_key(&idxkey)? {
+        let units = Units::from_dataset(&agg);
+
+        let reference_time = match hf.dataset("forecast_reference_time") {
+            Ok(frt) => {
+                let mut v = frt.read_raw::<f64>()?;
+                ensure!(v.len() == 1, "forecast_reference_time is not a scalar");
+                Units::from_dataset(&frt).convert(&mut v, &units)?;
+                Some(v[0])
+            }
+            Err(_) => None,
+        };
+
+        let schema = Schema::from_file(&hf)?;
+
+        let idxkey = std::fs::canonicalize(path)?.to_string_lossy().to_string();
+        if !db.is_indexed(&idxkey, path)? {
             debug!("Indexing: {:?}..", path);
             let idx = hdf5::sync::sync(|| idx::Index::index_file(&hf, Some(path)))?;
-            let bts = bincode::serialize(&idx)?;
 
             trace!("Inserting index into db ({})", idxkey);
-            db.insert(&idxkey, bts)?;
+            db.insert_index(&idxkey, path, &idx)?;
+            crate::index::cache().remove(&idxkey);
+            chunks::remove(&idxkey);
         } else {
             trace!("{} already indexed.", idxkey);
         };
@@ -55,17 +122,176 @@ impl NcmlMember {
             modified,
             n,
             rank,
+            units,
+            coordinates: Vec::new(),
+            reference_time,
+            schema,
+            fill: HashSet::new(),
+            layouts: chunks::layouts(&hf),
+            dataset: None,
         })
     }
 
+    fn from_dataset(
+        path: &Path,
+        dataset: NcmlDataset,
+        parents: &[PathBuf],
+    ) -> anyhow::Result<NcmlMember> {
+        let modified = last_modified(path, parents)?;
+
+        let coordinates = dataset.coordinate_values()?.to_vec();
+        let n = coordinates.len();
+        let rank: f64 = *coordinates
+            .first()
+            .ok_or_else(|| anyhow!("aggregate dimension is empty"))?;
+
+        // The variables of the first member, with the aggregated length.
+        let schema = dataset.members[0]
+            .schema
+            .iter()
+            .map(|(name, s)| {
+                let mut s = s.clone();
+                if let Some(p) = s.position(&dataset.dimension) {
+                    s.shape[p] = n;
+                }
+                (name.clone(), s)
+            })
+            .collect();
+
+        Ok(NcmlMember {
+            path: path.into(),
+            idxkey: dataset.cache_key.clone(),
+            modified,
+            n,
+            rank,
+            units: dataset.units.clone(),
+            coordinates,
+            reference_time: None,
+            schema,
+            fill: HashSet::new(),
+            layouts: HashMap::new(),
+            dataset: Some(Arc::new(dataset)),
+        })
+    }
+
+    /// Whether the coordinate values are converted from other units, to `units` or within the
+    /// nested aggregation.
+    pub fn is_converted(&self, units: &Units) -> bool {
+        self.units.converts_to(units) || self.dataset.as_ref().is_some_and(|d| d.is_converted())
+    }
+
+    /// Re-open the nested aggregation of a member loaded from the db.
+    pub fn resolve(&mut self, dimension: &str, db: &Db, parents: &[PathBuf]) -> anyhow::Result<()> {
+        if super::is_ncml(&self.path) && self.dataset.is_none() {
+            let dataset = open_dataset(&self.path, dimension, db, parents)?;
+            self.dataset = Some(Arc::new(dataset));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the member (or any of the members of a nested aggregation) has changed on disk
+    /// since it was opened.
+    pub fn is_modified(&self) -> bool {
+        match &self.dataset {
+            Some(dataset) => dataset.is_modified(),
+            None => !std::fs::metadata(&self.path)
+                .and_then(|md| md.modified())
+                .is_ok_and(|modified| modified == self.modified),
+        }
+    }
+
+    /// Remove the index of the member from the db if it has changed on disk, so that it is
+    /// re-indexed when it is opened again.
+    pub fn invalidate(&self, db: &Db) -> anyhow::Result<()> {
+        match &self.dataset {
+            Some(dataset) => dataset.invalidate(),
+            None => {
+                if self.is_modified() {
+                    debug!("Removing stale index: {}", self.idxkey);
+                    db.indexes().remove(&self.idxkey)?;
+                    crate::index::cache().remove(&self.idxkey);
+                    chunks::remove(&self.idxkey);
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// The first HDF5 file of the member, used for the DAS and DDS of the aggregation.
+    pub fn file(&self) -> &Path {
+        match &self.dataset {
+            Some(dataset) => dataset.members[0].file(),
+            None => &self.path,
+        }
+    }
+
+    /// Whether the coordinate values have been read.
+    pub fn has_coordinates(&self) -> bool {
+        self.coordinates.len() == self.n
+    }
+
+    /// Read the coordinate values from the file, converted to `units`. Nested aggregations always
+    /// have their coordinate values.
+    pub fn read_coordinates(&mut self, dimension: &str, units: &Units) -> anyhow::Result<()> {
+        if self.has_coordinates() {
+            return Ok(());
+        }
+
+        trace!("Reading coordinate values: {:?}", self.path);
+        let mut coordinates = hdf5::File::open(&self.path)?
+            .dataset(dimension)?
+            .read_raw::<f64>()?;
+        ensure!(
+            coordinates.len() == self.n,
+            "{:?} has changed on disk",
+            self.path
+        );
+
+        self.units.convert(&mut coordinates, units)?;
+        self.coordinates = coordinates;
+
+        Ok(())
+    }
+
+    /// Convert the first and the coordinate values (if read) to `units`.
+    pub fn convert(&mut self, units: &Units) -> anyhow::Result<()> {
+        self.units.convert(&mut self.coordinates, units)?;
+        self.units
+            .convert(std::slice::from_mut(&mut self.rank), units)?;
+
+        if let Some(reference_time) = self.reference_time.as_mut() {
+            self.units
+                .convert(std::slice::from_mut(reference_time), units)?;
+        }
+
+        Ok(())
+    }
+
+    /// The model run of the member: the forecast reference time, or the first coordinate value.
+    pub fn run(&self) -> f64 {
+        self.reference_time.unwrap_or(self.rank)
+    }
+
+    /// Stream a slab of `variable` from the member. The type is used for the fill values of
+    /// nested aggregations and for the size of the cached chunks.
     pub async fn stream_xdr(
         &self,
         variable: &str,
-        db: sled::Db,
+        vartype: VarType,
+        db: Db,
         indices: &[u64],
         counts: &[u64],
-    ) -> Result<impl Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static, anyhow::Error>
-    {
+    ) -> Result<
+        Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>,
+        anyhow::Error,
+    > {
+        if let Some(dataset) = &self.dataset {
+            // The nested aggregation checks its own files.
+            return dataset.stream_variable(variable, vartype, indices.to_vec(), counts.to_vec());
+        }
+
         let modified = std::fs::metadata(&self.path)?.modified()?;
         if modified != self.modified {
             warn!("{:?} has changed on disk", self.path);
@@ -74,25 +300,81 @@ impl NcmlMember {
 
         debug!("streaming: {} [{:?} / {:?}]", variable, indices, counts);
 
-        trace!("fetching index from db: {}", self.idxkey);
-        let bts = db.get(&self.idxkey)?.unwrap();
-        let idx = bincode::deserialize::<idx::Index>(&bts)?;
+        if let Some(fill) = self.schema.get(variable).and_then(|s| s.unallocated) {
+            trace!(
+                "{} is not allocated in {:?}, streaming fill values",
+                variable,
+                self.path
+            );
+            return crate::hdf5::stream_fill(vartype, fill, counts.iter().product());
+        }
+
+        let idx = crate::index::cache()
+            .get(&db, &self.idxkey, self.modified, &self.path)
+            .await?;
+
+        if let Some(layout) = self.layouts.get(variable).filter(|_| chunks::enabled()) {
+            let source = chunks::Source {
+                path: self.path.clone(),
+                idxkey: self.idxkey.clone(),
+                modified: self.modified,
+                idx,
+            };
+            return chunks::stream_xdr(source, variable, vartype, layout, indices, counts);
+        }
+
         trace!("creating streamer: {}", variable);
 
-        let reader = match idx.dataset(variable) {
+        let reader = match idx.index().dataset(variable) {
             Some(ds) => ds.as_streamer(&self.path),
             None => Err(anyhow!("dataset does not exist")),
         }?;
         let bytes = reader.stream_xdr(&crate::make_extents((indices, counts))?);
 
-        Ok(stream! {
-            pin_mut!(bytes);
+        Ok(bytes.boxed())
+    }
+}
+
+/// Open a nested NcML aggregation.
+fn open_dataset(
+    path: &Path,
+    dimension: &str,
+    db: &Db,
+    parents: &[PathBuf],
+) -> anyhow::Result<NcmlDataset> {
+    let key = path.to_string_lossy().to_string();
 
-            while let Some(b) = bytes.next().await {
-                yield b;
-            }
-        })
+    let dataset = match super::aggregation_type(path)? {
+        AggregationType::JoinExisting => NcmlDataset::open_nested(path, key, db.clone(), parents)?,
+        AggregationType::Fmrc => FmrcDataset::open_nested(path, &key, db.clone(), parents)?.1,
+    };
+
+    ensure!(
+        dataset.dimension == dimension,
+        "nested aggregation {:?} is along {}, not {}",
+        path,
+        dataset.dimension,
+        dimension
+    );
+
+    Ok(dataset)
+}
+
+/// The modification time of a member. For nested aggregations this is the latest modification
+/// time of the NcML file and all its members.
+pub fn last_modified(path: &Path, parents: &[PathBuf]) -> anyhow::Result<SystemTime> {
+    let modified = std::fs::metadata(path)?.modified()?;
+
+    if !super::is_ncml(path) {
+        return Ok(modified);
     }
+
+    let parents = super::descend(path, parents)?;
+    let aggregation = Aggregation::parse(path)?;
+
+    aggregation.files.iter().try_fold(modified, |latest, f| {
+        Ok::<_, anyhow::Error>(latest.max(last_modified(f, &parents)?))
+    })
 }
 
 #[cfg(test)]
@@ -110,13 +392,25 @@ mod tests {
         assert_eq!(m2.rank, 31.);
     }
 
+    #[test]
+    fn read_coordinates() {
+        let db = test_db();
+        let mut m = NcmlMember::open("../data/ncml/feb.nc4", "time", &db).unwrap();
+        assert!(!m.has_coordinates());
+
+        let units = m.units.clone();
+        m.read_coordinates("time", &units).unwrap();
+        assert!(m.has_coordinates());
+        assert_eq!(m.coordinates[0], m.rank);
+    }
+
     #[test]
     fn db_key_indexed() {
         let db = test_db();
         let m1 = NcmlMember::open("../data/ncml/jan.nc4", "time", &db).unwrap();
         let m2 = NcmlMember::open("../data/ncml/feb.nc4", "time", &db).unwrap();
 
-        assert!(db.contains_key(&m1.idxkey).unwrap());
-        assert!(db.contains_key(&m2.idxkey).unwrap());
+        assert!(db.indexes().contains(&m1.idxkey).unwrap());
+        assert!(db.indexes().contains(&m2.idxkey).unwrap());
     }
 }
@@ -1,25 +1,39 @@
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
-use async_stream::stream;
 use async_trait::async_trait;
-use bytes::{Bytes, BytesMut};
-use futures::{executor::block_on_stream, pin_mut, Stream, StreamExt};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
 use rayon::prelude::*;
 use roxmltree::Node;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
 use walkdir::WalkDir;
 
-use crate::hdf5::HDF5File;
-use dap2::dds::DdsVariableDetails;
-use hidefix::idx;
-use hidefix::idx::DatasetExt;
+use crate::db::Db;
+use crate::hdf5::{dds as hdf5dds, HDF5File};
+use crate::unpack::Packed;
+use dap2::dds::{DdsVariableDetails, VarType};
 
+mod coordinates;
+mod das;
 mod dds;
+mod fmrc;
 mod member;
+mod prefetch;
+mod schema;
+mod units;
+use coordinates::{CoordinateReport, CoordinateVariable, Overlap, Segment};
+pub use fmrc::FmrcDataset;
 use member::NcmlMember;
+use prefetch::Slab;
+use schema::SchemaReport;
+use units::Units;
 
 /// # NCML aggregated datasets
 ///
@@ -27,20 +41,71 @@ use member::NcmlMember;
 ///
 /// ## JoinExisting
 ///
-/// The aggregating dimension must already have a coordinate variable. Only the slowest varying or outer dimension
-/// (first index) may be joined.
+/// The aggregating dimension must already have a coordinate variable. Any dimension may be
+/// joined, e.g. time, depth or the `x` dimension of tiled products. When joining along an inner
+/// dimension the rows of the members are interleaved, see [prefetch].
 ///
-/// No handling of overlapping coordinate variable is done, it is concatenated in order listed.
+/// Overlapping coordinate values are by default concatenated in order, or de-duplicated keeping
+/// the newest or oldest member, see [coordinates]. The aggregated coordinate variable is checked
+/// for duplicates, gaps and non-monotonic values when loaded.
+///
+/// Members that are missing aggregated variables, or where they have a different type or shape
+/// than in the first member, are served with fill values for those variables, see [schema].
+///
+/// If the members use different time units for the coordinate variable (e.g. `days since
+/// 2020-01-01` and `days since 2020-02-01`) the values are converted to the units of the first
+/// member, see [units]. The converted coordinate variable is served as `Float64`.
+///
+/// ## Forecast model run collections
+///
+/// Aggregations of type `forecastModelRunCollection` are served as a 2D `run × time` dataset and
+/// a best estimate time series, see [FmrcDataset].
+///
+/// ## Nested aggregations
+///
+/// Members may be other NcML files, e.g. a yearly aggregation of monthly aggregations. They are
+/// opened the same way as when they are served: `joinExisting` aggregations as they are and
+/// forecast model run collections as their best estimate, and must be aggregated along the same
+/// dimension. Nested aggregations are resolved recursively, an NcML file that (indirectly)
+/// includes itself is an error.
+///
+/// ## Live aggregations
+///
+/// If the `aggregation` element has a `recheckEvery` attribute (e.g. `recheckEvery="15 min"`) the
+/// members are re-scanned periodically, see [NcmlDataset::rescan]. New members that are appended
+/// to the end extend the aggregation.
+///
+/// ## Caching
+///
+/// The members and their coordinate values are stored in the db. As long as the members and their
+/// modification times are unchanged the aggregation is loaded from the db without opening the
+/// members. Otherwise the coordinate values of aggregations that concatenate their members are
+/// read in the background, see [Coordinates].
 pub struct NcmlDataset {
     path: PathBuf,
+    key: String,
     das: dap2::Das,
     dds: dap2::Dds,
     /// Aggregation dimension
     dimension: String,
-    coordinates: CoordinateVariable,
+    /// Units of the coordinate variable (from the first member).
+    units: Units,
+    coordinates: Arc<Coordinates>,
+    /// Fill values of the variables in the first member.
+    fill_values: HashMap<String, f64>,
+    /// Key of the cached aggregation in the db.
+    cache_key: String,
     modified: std::time::SystemTime,
+    /// Interval for re-scanning the members of live aggregations.
+    recheck: Option<Duration>,
+    /// Number of members to read ahead when streaming aggregated variables.
+    prefetch: usize,
+    /// Whether index coordinates are served, see [NcmlDataset::with_index_coordinates].
+    index_coordinates: bool,
+    /// Packed variables that are served unpacked, see [NcmlDataset::with_unpack].
+    packed: Option<Packed>,
     members: Arc<Vec<NcmlMember>>,
-    db: sled::Db,
+    db: Db,
 }
 
 impl fmt::Debug for NcmlDataset {
@@ -50,7 +115,20 @@ impl fmt::Debug for NcmlDataset {
 }
 
 impl NcmlDataset {
-    pub fn open<P>(path: P, key: String, db: sled::Db) -> anyhow::Result<NcmlDataset>
+    pub fn open<P>(path: P, key: String, db: Db) -> anyhow::Result<NcmlDataset>
+    where
+        P: AsRef<Path>,
+    {
+        NcmlDataset::open_nested(path, key, db, &[])
+    }
+
+    /// Open an aggregation nested in the NcML files `parents`, see [NcmlMember::open_nested].
+    fn open_nested<P>(
+        path: P,
+        key: String,
+        db: Db,
+        parents: &[PathBuf],
+    ) -> anyhow::Result<NcmlDataset>
     where
         P: AsRef<Path>,
     {
@@ -58,81 +136,571 @@ impl NcmlDataset {
         let modified = std::fs::metadata(path)?.modified()?;
         info!("Loading {:?}..", path);
 
-        // Parse NCML file.
-        let xml = std::fs::read_to_string(path)?;
-        let xml = roxmltree::Document::parse(&xml)?;
-        let root = xml.root_element();
-
-        let aggregation = root
-            .first_element_child()
-            .ok_or_else(|| anyhow!("no aggregation tag found"))?;
+        let aggregation = Aggregation::parse(path)?;
         ensure!(
-            aggregation.tag_name().name() == "aggregation",
-            "expected aggregation tag"
+            aggregation.kind == AggregationType::JoinExisting,
+            "expected 'joinExisting' aggregation"
         );
 
-        let aggregation_type = aggregation
-            .attribute("type")
-            .ok_or_else(|| anyhow!("aggregation type not specified"))?;
-        ensure!(
-            aggregation_type == "joinExisting",
-            "only 'joinExisting' type aggregation supported"
-        );
+        // The coordinate values of top-level aggregations are read in the background, unless they
+        // are needed to find the overlapping values.
+        let defer = parents.is_empty()
+            && aggregation.overlap == Overlap::Keep
+            && tokio::runtime::Handle::try_current().is_ok();
 
-        // TODO: only available on certain aggregation types
-        let dimension = aggregation
-            .attribute("dimName")
-            .ok_or_else(|| anyhow!("aggregation dimension not specified"))?
-            .to_string();
-        trace!("Coordinate variable: {}", dimension);
+        let (cache_key, members, units) =
+            NcmlDataset::load_members(path, &aggregation, &db, parents, defer)?;
 
-        let files = NcmlDataset::get_member_files(path.parent(), &aggregation)?;
+        NcmlDataset::from_members(
+            path,
+            key,
+            modified,
+            aggregation,
+            cache_key,
+            members,
+            units,
+            db,
+        )
+    }
+
+    /// Load the members from the db if the cached aggregation is still valid, otherwise open them,
+    /// read their coordinate values and store them in the db. If `defer` is set the coordinate
+    /// values are left to [Coordinates]. Returns the key of the cached aggregation, the members
+    /// and the units.
+    ///
+    /// Nested aggregations are opened recursively, `parents` are the NcML files this aggregation
+    /// is nested in.
+    fn load_members(
+        path: &Path,
+        aggregation: &Aggregation,
+        db: &Db,
+        parents: &[PathBuf],
+        defer: bool,
+    ) -> anyhow::Result<(String, Vec<NcmlMember>, Units)> {
+        let parents = descend(path, parents)?;
+        let cache_key = cache_key(path)?;
+        let cache = AggregationCache::load(db, &cache_key).filter(|cache| {
+            cache.is_valid(&aggregation.dimension, &aggregation.files, db, &parents)
+        });
+
+        match cache {
+            Some(mut cache) => {
+                debug!("Using cached aggregation: {}", cache_key);
+
+                for m in &mut cache.members {
+                    m.resolve(&aggregation.dimension, db, &parents)?;
+                }
+
+                Ok((cache_key, cache.members, cache.units))
+            }
+            None => {
+                let (mut members, units) = NcmlDataset::open_members(
+                    &aggregation.files,
+                    &aggregation.dimension,
+                    None,
+                    db,
+                    &parents,
+                )?;
+
+                if !defer {
+                    members
+                        .par_iter_mut()
+                        .try_for_each(|m| m.read_coordinates(&aggregation.dimension, &units))?;
 
+                    AggregationCache::store(
+                        db,
+                        &cache_key,
+                        &aggregation.dimension,
+                        &members,
+                        &units,
+                    )?;
+                }
+
+                Ok((cache_key, members, units))
+            }
+        }
+    }
+
+    /// Open and index the members, sorted by the first value of the aggregation dimension.
+    ///
+    /// The first values are converted to `units`, or to the units of the first file if not
+    /// specified. Returns the members and the units.
+    fn open_members(
+        files: &[PathBuf],
+        dimension: &str,
+        units: Option<&Units>,
+        db: &Db,
+        parents: &[PathBuf],
+    ) -> anyhow::Result<(Vec<NcmlMember>, Units)> {
         let mut members = files
             .par_iter()
-            .map(|p| NcmlMember::open(p, &dimension, &db))
+            .map(|p| NcmlMember::open_nested(p, dimension, db, parents))
             .collect::<Result<Vec<NcmlMember>, _>>()?;
 
+        let units = units
+            .cloned()
+            .or_else(|| members.first().map(|m| m.units.clone()))
+            .unwrap_or_default();
+
+        for m in &mut members {
+            m.convert(&units)?;
+        }
+
         members.sort_by(|a, b| {
             a.rank
                 .partial_cmp(&b.rank)
                 .unwrap_or(std::cmp::Ordering::Equal)
         });
 
+        Ok((members, units))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn from_members(
+        path: &Path,
+        key: String,
+        modified: std::time::SystemTime,
+        aggregation: Aggregation,
+        cache_key: String,
+        mut members: Vec<NcmlMember>,
+        units: Units,
+        db: Db,
+    ) -> anyhow::Result<NcmlDataset> {
         ensure!(!members.is_empty(), "no members in aggregate.");
+        let dimension = aggregation.dimension;
+
+        let schema = SchemaReport::validate(&mut members, &dimension);
+        schema.log(&key);
+
         let members = Arc::new(members);
 
-        let (das, dds) = {
-            // DAS should be the same regardless of files, using first member.
-            trace!("Building DAS..");
-            let ipath = &members[0].path;
-            let hf = HDF5File(hdf5::File::open(ipath)?, key.clone());
-            let das = (&hf).into();
+        // DAS and DDS should be the same regardless of files, using first member.
+        let hf = HDF5File(hdf5::File::open(members[0].file())?, key.clone());
+
+        // Coordinate values converted from other units are not necessarily whole numbers, they
+        // are served as `Float64` whatever the type in the first member.
+        let converted = members.iter().any(|m| m.is_converted(&units));
+        let vartype = if converted {
+            VarType::Float64
+        } else {
+            hdf5dds::hdf5_vartype(&hf.0.dataset(&dimension)?.dtype()?)
+        };
 
-            trace!("Building DDS..");
-            let ipath = &members[0].path;
-            let n = members.iter().map(|m| m.n).sum();
-            let dds = dds::NcmlDdsBuilder::new(hdf5::File::open(ipath)?, key, dimension.clone(), n)
-                .into();
+        let aggregated = if members.iter().all(NcmlMember::has_coordinates) {
+            Some(Aggregated::from(
+                &key,
+                &dimension,
+                &members,
+                aggregation.overlap,
+                vartype,
+            )?)
+        } else {
+            None
+        };
 
-            (das, dds)
+        // Until the coordinate values have been read the members are concatenated (see
+        // [NcmlDataset::open_nested]), and the coordinate variable is not validated.
+        let (n, report) = match &aggregated {
+            Some(a) => (a.variable.values.len(), a.report.attributes()),
+            None => (members.iter().map(|m| m.n).sum(), Vec::new()),
         };
 
-        debug!("Reading coordinate variable..");
-        let coordinates = CoordinateVariable::from(&members, &dimension, &db)?;
+        let fill_values = fill_values(&hf.0)?;
+
+        trace!("Building DAS..");
+        let attributes = report.into_iter().chain(schema.attributes()).collect();
+        let mut das: dap2::Das = das::NcmlDasBuilder::new(&hf, attributes).into();
+
+        trace!("Building DDS..");
+        let mut dds: dap2::Dds = dds::NcmlDdsBuilder::new(
+            hdf5::File::open(members[0].file())?,
+            key.clone(),
+            dimension.clone(),
+            n,
+        )
+        .into();
+
+        if converted {
+            debug!("{}: serving converted {} as Float64", key, dimension);
+            das.set_variable(
+                &dimension,
+                coordinates::float64_attributes(&hf.0.dataset(&dimension)?)?,
+            );
+            dds.set_vartype(&dimension, VarType::Float64);
+        }
+
+        let coordinates = Arc::new(Coordinates {
+            cell: OnceCell::new_with(aggregated),
+            key: key.clone(),
+            cache_key: cache_key.clone(),
+            dimension: dimension.clone(),
+            vartype,
+            units: units.clone(),
+            overlap: aggregation.overlap,
+            members: Arc::clone(&members),
+            db: db.clone(),
+        });
+
+        // Read the coordinate variable in the background, requests will wait for it if it is not
+        // ready.
+        if !coordinates.cell.initialized() {
+            if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                let coordinates = Arc::clone(&coordinates);
+                handle.spawn(async move {
+                    if let Err(e) = coordinates.get().await {
+                        error!(
+                            "Failed to read coordinate variable ({}): {:?}",
+                            coordinates.key, e
+                        );
+                    }
+                });
+            }
+        }
 
         Ok(NcmlDataset {
             path: path.into(),
+            key,
             das,
             dds,
             dimension,
+            units,
             coordinates,
+            fill_values,
+            cache_key,
             modified,
+            recheck: aggregation.recheck,
+            prefetch: crate::config::Ncml::default().prefetch,
+            index_coordinates: false,
+            packed: None,
             members,
             db,
         })
     }
 
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The coordinate variable, if it has been read. Nested aggregations always have it.
+    fn aggregated(&self) -> anyhow::Result<&Aggregated> {
+        self.coordinates
+            .cell
+            .get()
+            .ok_or_else(|| anyhow!("coordinate variable of {:?} is not read yet", self.path))
+    }
+
+    /// Whether the coordinate values of any member are converted to the units of the
+    /// aggregation.
+    fn is_converted(&self) -> bool {
+        self.members.iter().any(|m| m.is_converted(&self.units))
+    }
+
+    /// The values of the aggregated coordinate variable, see [NcmlDataset::aggregated].
+    fn coordinate_values(&self) -> anyhow::Result<&[f64]> {
+        self.aggregated().map(|a| a.variable.values.as_slice())
+    }
+
+    /// Set the number of members to read ahead concurrently when streaming aggregated
+    /// variables, see [prefetch].
+    pub fn with_prefetch(mut self, prefetch: usize) -> NcmlDataset {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// Serve index coordinates for the dimensions without coordinate variables, see
+    /// [crate::hdf5::add_index_coordinates].
+    pub fn with_index_coordinates(mut self, enabled: bool) -> NcmlDataset {
+        if enabled && !self.index_coordinates {
+            crate::hdf5::add_index_coordinates(&mut self.das, &mut self.dds);
+            self.index_coordinates = true;
+        }
+        self
+    }
+
+    /// Serve the packed variables unpacked, see [crate::unpack]. The variables are unpacked the
+    /// same way in all members, as they are in the first member.
+    pub fn with_unpack(mut self, enabled: bool) -> anyhow::Result<NcmlDataset> {
+        if enabled && self.packed.is_none() {
+            let mut packed = Packed::from_file(&hdf5::File::open(self.members[0].file())?)?;
+            packed.remove(&self.dimension);
+            packed.apply(&mut self.das, &mut self.dds);
+            self.packed = Some(packed);
+        }
+        Ok(self)
+    }
+
+    /// The variables that are served unpacked.
+    pub fn packed(&self) -> Option<&Packed> {
+        self.packed.as_ref()
+    }
+
+    /// Whether the NcML file or any of the members have changed on disk since the aggregation was
+    /// opened. New members are not detected, see [NcmlDataset::rescan].
+    pub fn is_modified(&self) -> bool {
+        !std::fs::metadata(&self.path)
+            .and_then(|md| md.modified())
+            .is_ok_and(|modified| modified == self.modified)
+            || self.members.iter().any(NcmlMember::is_modified)
+    }
+
+    /// Remove the indexes of members that have changed on disk from the db.
+    pub fn invalidate(&self) -> anyhow::Result<()> {
+        self.members.iter().try_for_each(|m| m.invalidate(&self.db))
+    }
+
+    /// Re-open the aggregation with the same settings, re-indexing changed members.
+    fn reopen(&self) -> anyhow::Result<NcmlDataset> {
+        self.invalidate()?;
+
+        NcmlDataset::open(&self.path, self.key.clone(), self.db.clone()).and_then(|d| {
+            d.with_prefetch(self.prefetch)
+                .with_index_coordinates(self.index_coordinates)
+                .with_unpack(self.packed.is_some())
+        })
+    }
+
+    /// How often the members of the aggregation should be re-scanned (`recheckEvery`), if the
+    /// aggregation is live.
+    pub fn recheck_every(&self) -> Option<Duration> {
+        self.recheck
+    }
+
+    /// Check whether the aggregation has changed on disk. Returns a new dataset if it has, the
+    /// current dataset is left untouched so that running requests see a consistent snapshot.
+    ///
+    /// Members that are appended to the end of the aggregation extend the existing members, any
+    /// other change causes the aggregation to be re-opened.
+    pub fn rescan(&self) -> anyhow::Result<Option<NcmlDataset>> {
+        let modified = std::fs::metadata(&self.path)?.modified()?;
+        if modified != self.modified {
+            info!("{:?} has changed on disk, re-opening.", self.path);
+            return self.reopen().map(Some);
+        }
+
+        let aggregation = Aggregation::parse(&self.path)?;
+
+        let files: HashSet<_> = aggregation.files.iter().collect();
+        let parents = descend(&self.path, &[])?;
+        let changed = self.members.iter().any(|m| {
+            !files.contains(&m.path)
+                || !member::last_modified(&m.path, &parents)
+                    .is_ok_and(|modified| modified == m.modified)
+        });
+
+        if changed {
+            info!(
+                "Members of {:?} have been changed or removed, re-opening.",
+                self.path
+            );
+            return self.reopen().map(Some);
+        }
+
+        let current: HashSet<_> = self.members.iter().map(|m| &m.path).collect();
+        let files = aggregation
+            .files
+            .iter()
+            .filter(|f| !current.contains(f))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if files.is_empty() {
+            trace!("{:?} is unchanged.", self.path);
+            return Ok(None);
+        }
+
+        let (new, _) = NcmlDataset::open_members(
+            &files,
+            &self.dimension,
+            Some(&self.units),
+            &self.db,
+            &parents,
+        )?;
+
+        let last = self.members.last().map(|m| m.rank).unwrap_or(f64::MIN);
+        if new.iter().any(|m| m.rank <= last) {
+            info!(
+                "New members of {:?} are not appended at the end, re-opening.",
+                self.path
+            );
+            return self.reopen().map(Some);
+        }
+
+        info!("Appending {} new members to {:?}.", new.len(), self.path);
+
+        // Members of a dataset that was opened with the coordinate values read in the background
+        // do not have them.
+        let mut members = self.members.iter().cloned().chain(new).collect::<Vec<_>>();
+        members
+            .par_iter_mut()
+            .try_for_each(|m| m.read_coordinates(&self.dimension, &self.units))?;
+
+        AggregationCache::store(
+            &self.db,
+            &self.cache_key,
+            &self.dimension,
+            &members,
+            &self.units,
+        )?;
+
+        NcmlDataset::from_members(
+            &self.path,
+            self.key.clone(),
+            self.modified,
+            aggregation,
+            self.cache_key.clone(),
+            members,
+            self.units.clone(),
+            self.db.clone(),
+        )
+        .and_then(|d| {
+            d.with_prefetch(self.prefetch)
+                .with_index_coordinates(self.index_coordinates)
+                .with_unpack(self.packed.is_some())
+                .map(Some)
+        })
+    }
+
+    /// Stream a slab of a variable. This is also used to read from the aggregation when it is
+    /// nested in another aggregation. The coordinate variable must have been read, see
+    /// [Coordinates::get].
+    fn stream_variable(
+        &self,
+        variable: &str,
+        vartype: VarType,
+        indices: Vec<u64>,
+        counts: Vec<u64>,
+    ) -> Result<
+        Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>,
+        anyhow::Error,
+    > {
+        // Live aggregations are re-opened by the re-scan when the NcML file changes.
+        if self.recheck.is_none() && self.modified != std::fs::metadata(&self.path)?.modified()? {
+            warn!("{:?} has changed on disk", self.path);
+            return Err(anyhow!("{:?} has changed on disk", self.path));
+        }
+
+        let aggregated = self.aggregated()?;
+
+        let db = self.db.clone();
+        let members = Arc::clone(&self.members);
+        let var = variable.to_string();
+
+        // Position of the aggregation dimension in the variable.
+        let position = self.members[0]
+            .schema
+            .get(variable)
+            .and_then(|s| s.position(&self.dimension));
+
+        Ok(if variable == self.dimension {
+            // Coordinate dimension (aggregation variable).
+            aggregated
+                .variable
+                .stream_xdr(indices.as_slice(), counts.as_slice())?
+                .boxed()
+        } else if let Some(p) = position {
+            // Aggregated variable
+            let fill = self.fill_values.get(&var).copied().unwrap_or(f64::NAN);
+            let fill = dap2::dods::xdr::xdr_encode_f64(vartype, &[fill])?;
+
+            let slabs = if p == 0 {
+                self.member_slabs(aggregated, &var, &fill, p, &indices, &counts)
+            } else {
+                // Joining along an inner dimension: the members are side by side in each row of
+                // the outer dimensions, read one index of the outermost dimension at the time.
+                let rows: u64 = counts[1..p].iter().product();
+                let mut slabs = Vec::new();
+
+                for i in indices[0]..(indices[0] + counts[0]) {
+                    let mut indices = indices.clone();
+                    indices[0] = i;
+                    let mut counts = counts.clone();
+                    counts[0] = 1;
+
+                    let parts = self.member_slabs(aggregated, &var, &fill, p, &indices, &counts);
+
+                    if rows == 1 {
+                        slabs.extend(parts);
+                    } else {
+                        slabs.push(Slab::Interleave {
+                            parts,
+                            rows: rows as usize,
+                        });
+                    }
+                }
+
+                slabs
+            };
+
+            prefetch::stream_slabs(members, var, vartype, db, slabs, self.prefetch).boxed()
+        } else {
+            // Non-aggregated variable, using first member.
+            let slab = Slab::Member {
+                member: 0,
+                indices,
+                counts,
+            };
+
+            prefetch::stream_slabs(members, var, vartype, db, vec![slab], 0).boxed()
+        })
+    }
+
+    /// The slabs of the member segments covering the slab of an aggregated variable along the
+    /// aggregation dimension (at position `p`). Variables that are missing in a member are filled
+    /// with `fill`.
+    fn member_slabs(
+        &self,
+        aggregated: &Aggregated,
+        variable: &str,
+        fill: &[u8],
+        p: usize,
+        indices: &[u64],
+        counts: &[u64],
+    ) -> Vec<Slab> {
+        let mut slabs = Vec::new();
+        let mut member_start = 0;
+
+        for s in &aggregated.segments {
+            // Overlapping values may be left out of the aggregation.
+            let member_end = member_start + s.used.len() as u64;
+
+            if indices[p] < member_end && member_start < indices[p] + counts[p] {
+                let start = max(indices[p], member_start);
+                let end = min(indices[p] + counts[p], member_end);
+
+                let mut mindices = indices.to_vec();
+                mindices[p] = s.used.start as u64 + start - member_start;
+
+                let mut mcounts = counts.to_vec();
+                mcounts[p] = end - start;
+
+                trace!(
+                    "Member at {} to {} (mi = {:?}, mc = {:?})",
+                    member_start,
+                    member_end,
+                    mindices,
+                    mcounts
+                );
+
+                if self.members[s.member].fill.contains(variable) {
+                    let n: u64 = mcounts.iter().product();
+                    slabs.push(Slab::Fill(Bytes::from(fill.repeat(n as usize))));
+                } else {
+                    slabs.push(Slab::Member {
+                        member: s.member,
+                        indices: mindices,
+                        counts: mcounts,
+                    });
+                }
+            } else if indices[p] + counts[p] <= member_start {
+                break;
+            }
+
+            member_start = member_end;
+        }
+
+        slabs
+    }
+
     fn get_member_files(base: Option<&Path>, aggregation: &Node) -> anyhow::Result<Vec<PathBuf>> {
         aggregation
             .children()
@@ -206,6 +774,158 @@ impl NcmlDataset {
     }
 }
 
+/// The supported aggregation types.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregationType {
+    /// `joinExisting`, served as [NcmlDataset].
+    JoinExisting,
+    /// `forecastModelRunCollection`, served as [FmrcDataset].
+    Fmrc,
+}
+
+/// The key of the cached aggregation of the NcML file at `path` in the db.
+pub fn cache_key(path: &Path) -> anyhow::Result<String> {
+    Ok(format!(
+        "ncml:{}",
+        std::fs::canonicalize(path)?.to_string_lossy()
+    ))
+}
+
+/// The type of the aggregation in the NcML file at `path`.
+pub fn aggregation_type(path: &Path) -> anyhow::Result<AggregationType> {
+    Aggregation::parse(path).map(|a| a.kind)
+}
+
+/// The `aggregation` element of an NcML file.
+#[derive(Clone)]
+struct Aggregation {
+    kind: AggregationType,
+    dimension: String,
+    files: Vec<PathBuf>,
+    recheck: Option<Duration>,
+    overlap: Overlap,
+}
+
+impl Aggregation {
+    fn parse(path: &Path) -> anyhow::Result<Aggregation> {
+        // Parse NCML file.
+        let xml = std::fs::read_to_string(path)?;
+        let xml = roxmltree::Document::parse(&xml)?;
+        let root = xml.root_element();
+
+        let aggregation = root
+            .first_element_child()
+            .ok_or_else(|| anyhow!("no aggregation tag found"))?;
+        ensure!(
+            aggregation.tag_name().name() == "aggregation",
+            "expected aggregation tag"
+        );
+
+        let kind = match aggregation
+            .attribute("type")
+            .ok_or_else(|| anyhow!("aggregation type not specified"))?
+        {
+            "joinExisting" => AggregationType::JoinExisting,
+            "forecastModelRunCollection" => AggregationType::Fmrc,
+            t => {
+                return Err(anyhow!(
+                    "only 'joinExisting' and 'forecastModelRunCollection' type aggregation supported, not: {}",
+                    t
+                ))
+            }
+        };
+
+        // TODO: only available on certain aggregation types
+        let dimension = aggregation
+            .attribute("dimName")
+            .ok_or_else(|| anyhow!("aggregation dimension not specified"))?
+            .to_string();
+        trace!("Coordinate variable: {}", dimension);
+
+        let recheck = aggregation
+            .attribute("recheckEvery")
+            .map(parse_duration)
+            .transpose()?;
+
+        let overlap = aggregation
+            .attribute("overlap")
+            .map(str::parse::<Overlap>)
+            .transpose()?
+            .unwrap_or_default();
+
+        let files = NcmlDataset::get_member_files(path.parent(), &aggregation)?;
+
+        Ok(Aggregation {
+            kind,
+            dimension,
+            files,
+            recheck,
+            overlap,
+        })
+    }
+}
+
+/// Whether the file at `path` is an NcML file.
+pub fn is_ncml(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "ncml")
+}
+
+/// The chain of nested NcML files `parents` extended with `path` (canonicalized). Fails if `path`
+/// is already in the chain, i.e. the aggregations are nested in a cycle.
+fn descend(path: &Path, parents: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let path = std::fs::canonicalize(path)?;
+    let mut chain = parents.to_vec();
+
+    ensure!(
+        !chain.contains(&path),
+        "cycle in nested NcML aggregations: {:?} -> {:?}",
+        chain,
+        path
+    );
+
+    chain.push(path);
+    Ok(chain)
+}
+
+/// The fill values of all the variables in a file.
+fn fill_values(file: &hdf5::File) -> anyhow::Result<HashMap<String, f64>> {
+    file.member_names()?
+        .into_iter()
+        .filter_map(|m| file.dataset(&m).ok().map(|d| (m, d)))
+        .map(|(m, d)| {
+            let vartype = hdf5dds::hdf5_vartype(&d.dtype()?);
+            Ok::<_, anyhow::Error>((m, crate::hdf5::fill_value(&d, vartype)))
+        })
+        .collect()
+}
+
+/// Parse a NcML time period, e.g. `15 min` or `1 hour`.
+fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let mut parts = s.split_whitespace();
+
+    let value = parts
+        .next()
+        .ok_or_else(|| anyhow!("empty duration"))?
+        .parse::<f64>()
+        .map_err(|_| anyhow!("invalid duration: {}", s))?;
+
+    let unit = match parts.next().map(|u| u.to_lowercase()) {
+        Some(u) if u.starts_with("sec") => 1.,
+        Some(u) if u.starts_with("min") => 60.,
+        Some(u) if u.starts_with("hour") => 60. * 60.,
+        Some(u) if u.starts_with("day") => 24. * 60. * 60.,
+        _ => return Err(anyhow!("invalid duration unit: {}", s)),
+    };
+
+    ensure!(
+        value > 0. && parts.next().is_none(),
+        "invalid duration: {}",
+        s
+    );
+
+    Ok(Duration::from_secs_f64(value * unit))
+}
+
 #[async_trait]
 impl dap2::Dap2 for NcmlDataset {
     async fn das(&self) -> &dap2::Das {
@@ -238,11 +958,6 @@ impl dap2::DodsXdr for NcmlDataset {
         Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>,
         anyhow::Error,
     > {
-        if self.modified != std::fs::metadata(&self.path)?.modified()? {
-            warn!("{:?} has changed on disk", self.path);
-            return Err(anyhow!("{:?} has changed on disk", self.path));
-        }
-
         debug!(
             "streaming: {} [{:?} / {:?}]",
             variable.name, variable.indices, variable.counts
@@ -251,155 +966,176 @@ impl dap2::DodsXdr for NcmlDataset {
         let indices: Vec<u64> = variable.indices.iter().map(|c| *c as u64).collect();
         let counts: Vec<u64> = variable.counts.iter().map(|c| *c as u64).collect();
 
-        let db = self.db.clone();
+        // Wait for the coordinate variable if it is being read in the background.
+        self.coordinates.get().await?;
 
-        Ok(if variable.name == self.dimension {
-            // Coordinate dimension (aggregation variable).
-            self.coordinates
-                .stream_xdr(indices.as_slice(), counts.as_slice())?
-                .boxed()
-        } else if variable
-            .dimensions
-            .first()
-            .map(|d| d.0 != self.dimension)
-            .unwrap_or(true)
-        {
-            // Non-aggregated variable, using first member.
-            self.members[0]
-                .stream_xdr(&variable.name, db, indices.as_slice(), counts.as_slice())
-                .await?
-                .boxed()
-        } else {
-            // Aggregated variable
-            let members = Arc::clone(&self.members);
-            let var = variable.name.clone();
-
-            (stream! {
-                trace!("streaming aggregated variable");
-                let mut member_start = 0;
-
-                for m in &*members  {
-                    if indices[0] >= member_start && indices[0] < (member_start + m.n as u64) {
-                        let mut mindices = indices.clone();
-                        mindices[0] = indices[0] - member_start;
-
-                        let mut mcounts = counts.clone();
-                        mcounts[0] = min(counts[0], m.n as u64 - mindices[0]);
-
-                        trace!("First file: {} to {} (mi = {:?}, mc = {:?})", member_start, member_start + m.n as u64, mindices, mcounts);
-
-                        let bytes = m.stream_xdr(&var, db.clone(), &mindices, &mcounts).await?;
-                        pin_mut!(bytes);
-                        while let Some(b) = bytes.next().await {
-                            yield b;
-                        }
-                    } else if indices[0] < member_start && (member_start < indices[0] + counts[0]) {
-                        let mut mcounts = counts.clone();
-                        mcounts[0] = min(indices[0] + counts[0] - member_start, m.n as u64);
-
-                        let mut mindices = indices.clone();
-                        mindices[0] = 0;
-
-                        trace!(
-                            "Consecutive file at {} to {} (i = {:?}, c = {:?})",
-                            member_start,
-                            member_start + m.n as u64,
-                            mindices,
-                            mcounts
-                        );
+        self.stream_variable(&variable.name, variable.vartype, indices, counts)
+    }
+}
 
-                        let bytes = m.stream_xdr(&var, db.clone(), &mindices, &mcounts).await?;
-                        pin_mut!(bytes);
-                        while let Some(b) = bytes.next().await {
-                            yield b;
-                        }
-                    } else if indices[0] + counts[0] < member_start {
-                        break;
-                    }
+/// The aggregated coordinate variable. It is available right away when the members are loaded
+/// from the db, otherwise it is read from the members on first use (or in the background when the
+/// dataset is opened) and the members are then persisted in the db.
+struct Coordinates {
+    cell: OnceCell<Aggregated>,
+    /// Key of the dataset.
+    key: String,
+    /// Key of the cached aggregation in the db.
+    cache_key: String,
+    dimension: String,
+    vartype: VarType,
+    units: Units,
+    overlap: Overlap,
+    members: Arc<Vec<NcmlMember>>,
+    db: Db,
+}
 
-                    member_start += m.n as u64;
-                }
-            }).boxed()
-        })
+impl Coordinates {
+    async fn get(self: &Arc<Self>) -> anyhow::Result<&Aggregated> {
+        self.cell
+            .get_or_try_init(|| {
+                let slf = Arc::clone(self);
+
+                async move { tokio::task::spawn_blocking(move || slf.read()).await? }
+            })
+            .await
+    }
+
+    /// Read the coordinate values of the members and store the members in the db.
+    fn read(&self) -> anyhow::Result<Aggregated> {
+        debug!("Reading coordinate variable ({})..", self.key);
+
+        let mut members = self.members.to_vec();
+        members
+            .par_iter_mut()
+            .try_for_each(|m| m.read_coordinates(&self.dimension, &self.units))?;
+
+        AggregationCache::store(
+            &self.db,
+            &self.cache_key,
+            &self.dimension,
+            &members,
+            &self.units,
+        )?;
+
+        Aggregated::from(
+            &self.key,
+            &self.dimension,
+            &members,
+            self.overlap,
+            self.vartype,
+        )
     }
 }
 
-/// The coordinate variable is cached since it is always requested and requires all files to be
-/// opened and read.
-pub struct CoordinateVariable {
-    bytes: Bytes,
-    /// Data type size
-    dsz: usize,
+/// The coordinate variable and the parts of the members it is made of.
+struct Aggregated {
+    variable: CoordinateVariable,
+    /// The parts of the members that make up the aggregation dimension, see [Overlap].
+    segments: Vec<Segment>,
+    report: CoordinateReport,
 }
 
-impl CoordinateVariable {
-    pub fn from(
-        members: &Vec<NcmlMember>,
+impl Aggregated {
+    /// Aggregate the coordinate values of the members, leaving out overlapping values according
+    /// to `overlap`. Problems with the coordinate variable are logged.
+    fn from(
+        key: &str,
         dimension: &str,
-        db: &sled::Db,
-    ) -> anyhow::Result<CoordinateVariable> {
-        ensure!(!members.is_empty(), "no members");
+        members: &[NcmlMember],
+        overlap: Overlap,
+        vartype: VarType,
+    ) -> anyhow::Result<Aggregated> {
+        let segments = overlap.apply(members);
+        let variable = CoordinateVariable::from(members, &segments, vartype)?;
 
-        trace!("Getting member 0: {}", members[0].idxkey);
-        let bts = db.get(&members[0].idxkey)?.unwrap();
-        let idx = bincode::deserialize::<idx::Index>(&bts)?;
+        let report = CoordinateReport::from(&variable.values);
+        report.log(key, dimension);
 
-        let ds = idx
-            .dataset(dimension)
-            .ok_or_else(|| anyhow!("dimension dataset not found."))?;
-        let dsz = ds.dsize() * hidefix::filters::xdr::xdr_factor(ds.dtype());
+        Ok(Aggregated {
+            variable,
+            segments,
+            report,
+        })
+    }
+}
 
-        let n: usize = members.iter().map(|m| m.n).sum();
+/// The members of an aggregation as stored in the db.
+#[derive(Serialize, Deserialize)]
+struct AggregationCache {
+    dimension: String,
+    members: Vec<NcmlMember>,
+    units: Units,
+}
 
-        let mut bytes = BytesMut::with_capacity(n * dsz);
+impl AggregationCache {
+    fn load(db: &Db, key: &str) -> Option<AggregationCache> {
+        match db.sled().get(key) {
+            Ok(Some(bts)) => bincode::deserialize(&bts)
+                .map_err(|e| warn!("Could not deserialize cached aggregation {}: {:?}", key, e))
+                .ok(),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Could not read cached aggregation {}: {:?}", key, e);
+                None
+            }
+        }
+    }
 
-        for m in members {
-            trace!("Getting member: {}", m.idxkey);
-            let bts = db.get(&m.idxkey)?.unwrap();
-            let idx = bincode::deserialize::<idx::Index>(&bts)?;
+    fn store(
+        db: &Db,
+        key: &str,
+        dimension: &str,
+        members: &[NcmlMember],
+        units: &Units,
+    ) -> anyhow::Result<()> {
+        let cache = AggregationCache {
+            dimension: dimension.to_string(),
+            members: members.to_vec(),
+            units: units.clone(),
+        };
 
-            let ds = idx
-                .dataset(dimension)
-                .ok_or_else(|| anyhow!("dimension dataset not found."))?;
-            let reader = ds.as_streamer(&m.path)?;
-            let reader = reader.stream_xdr(&hidefix::extent::Extents::All);
+        trace!("Inserting aggregation into db ({})", key);
+        db.sled().insert(key, bincode::serialize(&cache)?)?;
 
-            pin_mut!(reader);
+        Ok(())
+    }
 
-            block_on_stream(reader)
-                .try_for_each(|b| b.map(|b| bytes.extend_from_slice(b.as_ref())))?;
+    /// The cache is valid if the members are the same files, none of them have been modified,
+    /// and they are all still indexed (or cached, for nested aggregations). The units are taken
+    /// from the first file, so it must have the same units as when the coordinate values were
+    /// converted.
+    fn is_valid(&self, dimension: &str, files: &[PathBuf], db: &Db, parents: &[PathBuf]) -> bool {
+        if self.dimension != dimension || self.members.len() != files.len() {
+            return false;
         }
 
-        trace!(
-            "Coordinate variable: {}, length: {}, data type size: {}",
-            dimension,
-            bytes.len(),
-            dsz
-        );
+        let members: HashMap<_, _> = self.members.iter().map(|m| (&m.path, m)).collect();
 
-        Ok(CoordinateVariable {
-            bytes: bytes.freeze(),
-            dsz,
-        })
-    }
-
-    pub fn stream_xdr(
-        &self,
-        indices: &[u64],
-        counts: &[u64],
-    ) -> Result<impl Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static, anyhow::Error>
-    {
-        ensure!(
-            indices.len() == 1 && counts.len() == 1,
-            "coordinate dimension is always 1 dimension"
-        );
+        if files
+            .first()
+            .and_then(|f| members.get(f))
+            .is_none_or(|m| m.units != self.units)
+        {
+            return false;
+        }
 
-        let start = indices[0] as usize * self.dsz;
-        let end = (indices[0] + counts[0]) as usize * self.dsz;
-        ensure!(end <= self.bytes.len(), "slab out of range");
+        // Nested aggregations are cached in the db, files must have an index that matches them.
+        let indexed = |f: &Path, m: &NcmlMember| {
+            if is_ncml(f) {
+                db.sled().contains_key(&m.idxkey).unwrap_or(false)
+            } else {
+                db.is_indexed(&m.idxkey, f).unwrap_or(false)
+            }
+        };
 
-        let bytes = self.bytes.slice(start..end);
-        Ok(futures::stream::once(async { Ok(bytes) }))
+        files.iter().all(|f| {
+            members.get(f).is_some_and(|m| {
+                member::last_modified(f, parents).is_ok_and(|modified| modified == m.modified)
+                    && m.has_coordinates()
+                    && indexed(f, m)
+            })
+        })
     }
 }
 
@@ -407,6 +1143,28 @@ impl CoordinateVariable {
 mod tests {
     use super::*;
     use crate::data::test_db;
+    use futures::TryStreamExt;
+
+    /// Write a joinExisting aggregation of `members` along `dimension` to `path`.
+    fn write_ncml(path: &Path, dimension: &str, attributes: &str, members: &[&Path]) {
+        std::fs::write(
+            path,
+            format!(
+                r#"<netcdf xmlns="http://www.unidata.ucar.edu/namespaces/netcdf/ncml-2.2">
+                    <aggregation dimName="{}" type="joinExisting" {}>
+                      {}
+                    </aggregation>
+                  </netcdf>"#,
+                dimension,
+                attributes,
+                members
+                    .iter()
+                    .map(|m| format!(r#"<netcdf location="{}"/>"#, m.display()))
+                    .collect::<String>()
+            ),
+        )
+        .unwrap();
+    }
 
     #[tokio::test(flavor = "multi_thread")]
     async fn agg_existing_location() {
@@ -414,7 +1172,11 @@ mod tests {
         let db = test_db();
         let ncml = NcmlDataset::open("../data/ncml/aggExisting.ncml", "aggE".into(), db).unwrap();
 
-        assert_eq!(ncml.coordinates.bytes.len(), 4 * (31 + 28));
+        assert_eq!(
+            ncml.coordinates.get().await.unwrap().variable.values.len(),
+            31 + 28
+        );
+        assert!(!ncml.das.as_str().contains("aggregation_gaps"));
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -423,6 +1185,302 @@ mod tests {
         let db = test_db();
         let ncml = NcmlDataset::open("../data/ncml/scan.ncml", "aggE".into(), db).unwrap();
 
-        assert_eq!(ncml.coordinates.bytes.len(), 4 * (31 + 28));
+        assert_eq!(
+            ncml.coordinates.get().await.unwrap().variable.values.len(),
+            31 + 28
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn agg_existing_cached() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = test_db();
+        let ncml =
+            NcmlDataset::open("../data/ncml/aggExisting.ncml", "aggE".into(), db.clone()).unwrap();
+        ncml.coordinates.get().await.unwrap();
+        assert!(db.sled().contains_key(&ncml.cache_key).unwrap());
+
+        let cached = NcmlDataset::open("../data/ncml/aggExisting.ncml", "aggE".into(), db).unwrap();
+        assert_eq!(cached.members.len(), 2);
+        assert!(cached.coordinates.cell.initialized());
+        assert_eq!(
+            cached.coordinates.get().await.unwrap().variable.values,
+            ncml.coordinates.get().await.unwrap().variable.values
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn agg_existing_overlap() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = test_db();
+
+        let dir = tempfile::tempdir().unwrap();
+        let jan = std::fs::canonicalize("../data/ncml/jan.nc4").unwrap();
+
+        for overlap in ["keep", "newest"] {
+            write_ncml(
+                &dir.path().join(format!("{}.ncml", overlap)),
+                "time",
+                &format!(r#"overlap="{}""#, overlap),
+                &[&jan, &jan],
+            );
+        }
+
+        let keep =
+            NcmlDataset::open(dir.path().join("keep.ncml"), "keep".into(), db.clone()).unwrap();
+        assert_eq!(
+            keep.coordinates.get().await.unwrap().variable.values.len(),
+            2 * 31
+        );
+
+        // The coordinate variable is validated when it is available at load.
+        let keep =
+            NcmlDataset::open(dir.path().join("keep.ncml"), "keep".into(), db.clone()).unwrap();
+        assert!(keep.das.as_str().contains("aggregation_duplicates"));
+
+        let newest =
+            NcmlDataset::open(dir.path().join("newest.ncml"), "newest".into(), db).unwrap();
+        assert_eq!(
+            newest
+                .coordinates
+                .get()
+                .await
+                .unwrap()
+                .variable
+                .values
+                .len(),
+            31
+        );
+        let segments = &newest.aggregated().unwrap().segments;
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].member, 1);
+        assert_eq!(segments[0].used, 0..31);
+        assert!(!newest.das.as_str().contains("aggregation_duplicates"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn agg_existing_inner_dimension() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = test_db();
+
+        let dir = tempfile::tempdir().unwrap();
+        let jan = std::fs::canonicalize("../data/ncml/jan.nc4").unwrap();
+
+        // Join January with itself along longitude.
+        for (name, n) in [("single", 1), ("double", 2)] {
+            write_ncml(
+                &dir.path().join(format!("{}.ncml", name)),
+                "lon",
+                "",
+                &vec![jan.as_path(); n],
+            );
+        }
+
+        let single =
+            NcmlDataset::open(dir.path().join("single.ncml"), "s".into(), db.clone()).unwrap();
+        let double = NcmlDataset::open(dir.path().join("double.ncml"), "d".into(), db).unwrap();
+
+        let n = single
+            .coordinates
+            .get()
+            .await
+            .unwrap()
+            .variable
+            .values
+            .len() as u64;
+        assert_eq!(
+            double
+                .coordinates
+                .get()
+                .await
+                .unwrap()
+                .variable
+                .values
+                .len() as u64,
+            2 * n
+        );
+        assert!(double
+            .dds
+            .all()
+            .to_string()
+            .contains(&format!("[lon = {}]", 2 * n)));
+
+        let read = |d: &NcmlDataset, n: u64| {
+            d.stream_variable("T", VarType::Float32, vec![0, 0, 0], vec![2, 2, n])
+                .unwrap()
+                .try_collect::<Vec<Bytes>>()
+        };
+        let single = read(&single, n).await.unwrap().concat();
+        let double = read(&double, 2 * n).await.unwrap().concat();
+
+        // Every row of the aggregation is the row of January twice.
+        let expected = single
+            .chunks(single.len() / 4)
+            .flat_map(|row| [row, row].concat())
+            .collect::<Vec<u8>>();
+        assert_eq!(double, expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn agg_existing_nested() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = test_db();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let jan = std::fs::canonicalize("../data/ncml/jan.nc4").unwrap();
+        let feb = std::fs::canonicalize("../data/ncml/feb.nc4").unwrap();
+
+        write_ncml(&dir.join("jan.ncml"), "time", "", &[&jan]);
+        write_ncml(
+            &dir.join("year.ncml"),
+            "time",
+            "",
+            &[&dir.join("jan.ncml"), &feb],
+        );
+
+        let year = NcmlDataset::open(dir.join("year.ncml"), "year".into(), db.clone()).unwrap();
+        assert_eq!(
+            year.coordinates.get().await.unwrap().variable.values.len(),
+            31 + 28
+        );
+        assert!(year.members[0].dataset.is_some());
+        assert_eq!(year.members[0].file(), jan);
+
+        // Read across the nested aggregation and the file.
+        let flat =
+            NcmlDataset::open("../data/ncml/aggExisting.ncml", "aggE".into(), db.clone()).unwrap();
+        flat.coordinates.get().await.unwrap();
+        let read = |d: &NcmlDataset| {
+            d.stream_variable("T", VarType::Float32, vec![29, 0, 0], vec![4, 1, 1])
+                .unwrap()
+                .try_collect::<Vec<Bytes>>()
+        };
+        assert_eq!(
+            read(&year).await.unwrap().concat(),
+            read(&flat).await.unwrap().concat()
+        );
+
+        // The nested aggregation is re-opened when loaded from the cache.
+        let cached = NcmlDataset::open(dir.join("year.ncml"), "year".into(), db.clone()).unwrap();
+        assert!(cached.members[0].dataset.is_some());
+        assert_eq!(
+            cached.coordinates.get().await.unwrap().variable.values,
+            year.coordinates.get().await.unwrap().variable.values
+        );
+
+        // An aggregation including itself.
+        write_ncml(&dir.join("a.ncml"), "time", "", &[&dir.join("b.ncml")]);
+        write_ncml(&dir.join("b.ncml"), "time", "", &[&dir.join("a.ncml")]);
+        let err = NcmlDataset::open(dir.join("a.ncml"), "a".into(), db).unwrap_err();
+        assert!(format!("{:?}", err).contains("cycle"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn agg_existing_converted_units() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = test_db();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        // The units of the members, with the same length as the original units.
+        for (month, units) in [
+            ("jan", "days since 2000-01-01Z"),
+            ("feb", "hours since 2000-01-01"),
+        ] {
+            let path = dir.join(format!("{}.nc4", month));
+            std::fs::copy(format!("../data/ncml/{}.nc4", month), &path).unwrap();
+
+            let f = hdf5::File::append(&path).unwrap();
+            f.dataset("time")
+                .unwrap()
+                .attr("units")
+                .unwrap()
+                .write_scalar(&hdf5::types::FixedAscii::<22>::from_ascii(units).unwrap())
+                .unwrap();
+        }
+
+        write_ncml(
+            &dir.join("agg.ncml"),
+            "time",
+            "",
+            &[&dir.join("jan.nc4"), &dir.join("feb.nc4")],
+        );
+
+        let agg = NcmlDataset::open(dir.join("agg.ncml"), "agg".into(), db).unwrap();
+        assert!(agg
+            .dds
+            .all()
+            .to_string()
+            .contains("Float64 time[time = 59]"));
+
+        let values = agg.coordinates.get().await.unwrap().variable.values.clone();
+        assert!(values.iter().any(|v| v.fract() != 0.));
+
+        let bytes = agg
+            .stream_variable("time", VarType::Float64, vec![0], vec![59])
+            .unwrap()
+            .try_collect::<Vec<Bytes>>()
+            .await
+            .unwrap();
+        let served = dap2::dods::xdr::xdr_decode_f64(VarType::Float64, &bytes.concat()).unwrap();
+        assert_eq!(served, values);
+    }
+
+    #[test]
+    fn recheck_every() {
+        assert_eq!(
+            parse_duration("15 min").unwrap(),
+            Duration::from_secs(15 * 60)
+        );
+        assert_eq!(parse_duration("1 hour").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse_duration("30 secs").unwrap(), Duration::from_secs(30));
+        assert!(parse_duration("15").is_err());
+        assert!(parse_duration("-1 min").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn agg_existing_live_append() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let db = test_db();
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::copy("../data/ncml/jan.nc4", dir.path().join("jan.nc4")).unwrap();
+        std::fs::write(
+            dir.path().join("live.ncml"),
+            std::fs::read_to_string("../data/ncml/scan.ncml")
+                .unwrap()
+                .replace(
+                    "type=\"joinExisting\"",
+                    "type=\"joinExisting\" recheckEvery=\"1 min\"",
+                ),
+        )
+        .unwrap();
+
+        let ncml = NcmlDataset::open(dir.path().join("live.ncml"), "live".into(), db).unwrap();
+        assert_eq!(ncml.recheck_every(), Some(Duration::from_secs(60)));
+        assert_eq!(
+            ncml.coordinates.get().await.unwrap().variable.values.len(),
+            31
+        );
+        assert!(ncml.rescan().unwrap().is_none());
+
+        std::fs::copy("../data/ncml/feb.nc4", dir.path().join("feb.nc4")).unwrap();
+        let appended = ncml.rescan().unwrap().unwrap();
+
+        assert_eq!(ncml.members.len(), 1);
+        assert_eq!(appended.members.len(), 2);
+        assert_eq!(
+            appended
+                .coordinates
+                .get()
+                .await
+                .unwrap()
+                .variable
+                .values
+                .len(),
+            31 + 28
+        );
     }
 }
@@ -0,0 +1,230 @@
+//! Concurrent reading of aggregated variables.
+//!
+//! An aggregated variable is read as a sequence of slabs from the members. Each slab is read in a
+//! separate task, and up to `prefetch` slabs following the one currently being streamed are read
+//! concurrently. The bytes are always yielded in order. Every task sends its bytes through a small
+//! bounded channel, so a slab which is read ahead only buffers a few chunks before it waits for
+//! its turn.
+//!
+//! The window is configured with `prefetch` in the `[ncml]` section of `dars.toml`, `0` reads the
+//! members one at a time.
+//!
+//! When joining along an inner dimension the rows of the members have to be interleaved, these
+//! slabs are read completely into memory before they are yielded (see [Slab::Interleave]).
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use async_stream::stream;
+use bytes::{Bytes, BytesMut};
+use futures::{pin_mut, Stream, StreamExt, TryStreamExt};
+use tokio::sync::mpsc;
+
+use super::member::NcmlMember;
+use crate::db::Db;
+use dap2::dds::VarType;
+
+/// Number of chunks a member can read ahead of the consumer.
+const CHANNEL_SIZE: usize = 8;
+
+/// One part of an aggregated variable.
+pub enum Slab {
+    /// Read a slab of the variable from a member.
+    Member {
+        member: usize,
+        indices: Vec<u64>,
+        counts: Vec<u64>,
+    },
+    /// Fill values (already XDR encoded).
+    Fill(Bytes),
+    /// Parts (`Member` or `Fill`) that are side by side along the aggregation dimension, each
+    /// consisting of `rows` rows of equal size. The output is the first row of every part, then
+    /// the second row of every part, and so on.
+    Interleave { parts: Vec<Slab>, rows: usize },
+}
+
+/// Stream the slabs of `variable` in order, reading up to `prefetch` slabs ahead concurrently.
+pub fn stream_slabs(
+    members: Arc<Vec<NcmlMember>>,
+    variable: String,
+    vartype: VarType,
+    db: Db,
+    slabs: Vec<Slab>,
+    prefetch: usize,
+) -> impl Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static {
+    stream! {
+        let mut slabs = slabs.into_iter();
+        let mut pending = VecDeque::with_capacity(prefetch + 1);
+
+        loop {
+            while pending.len() <= prefetch {
+                match slabs.next() {
+                    Some(slab) => pending.push_back(spawn_read(
+                        Arc::clone(&members),
+                        variable.clone(),
+                        vartype,
+                        db.clone(),
+                        slab,
+                    )),
+                    None => break,
+                }
+            }
+
+            let mut rx = match pending.pop_front() {
+                Some(rx) => rx,
+                None => break,
+            };
+
+            while let Some(b) = rx.recv().await {
+                let failed = b.is_err();
+                yield b;
+
+                if failed {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Read a slab in a separate task. The receiver is dropped if the response is cancelled, which
+/// stops the task.
+fn spawn_read(
+    members: Arc<Vec<NcmlMember>>,
+    variable: String,
+    vartype: VarType,
+    db: Db,
+    slab: Slab,
+) -> mpsc::Receiver<Result<Bytes, anyhow::Error>> {
+    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+
+    tokio::spawn(async move {
+        match slab {
+            Slab::Fill(bytes) => {
+                let _ = tx.send(Ok(bytes)).await;
+            }
+            Slab::Member {
+                member,
+                indices,
+                counts,
+            } => {
+                let m = &members[member];
+                trace!(
+                    "Reading {} from {:?} (i = {:?}, c = {:?})",
+                    variable,
+                    m.path,
+                    indices,
+                    counts
+                );
+
+                match m
+                    .stream_xdr(&variable, vartype, db, &indices, &counts)
+                    .await
+                {
+                    Ok(bytes) => {
+                        pin_mut!(bytes);
+                        while let Some(b) = bytes.next().await {
+                            if tx.send(b).await.is_err() {
+                                trace!("Response cancelled, stopping read of {:?}", m.path);
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                    }
+                }
+            }
+            Slab::Interleave { parts, rows } => {
+                let parts = futures::future::try_join_all(
+                    parts
+                        .into_iter()
+                        .map(|part| read_part(&members, &variable, vartype, &db, part)),
+                )
+                .await;
+
+                let _ = tx
+                    .send(parts.and_then(|parts| interleave(&parts, rows)))
+                    .await;
+            }
+        }
+    });
+
+    rx
+}
+
+/// Read a part of an interleaved slab into memory.
+async fn read_part(
+    members: &[NcmlMember],
+    variable: &str,
+    vartype: VarType,
+    db: &Db,
+    part: Slab,
+) -> Result<Bytes, anyhow::Error> {
+    match part {
+        Slab::Fill(bytes) => Ok(bytes),
+        Slab::Member {
+            member,
+            indices,
+            counts,
+        } => {
+            let m = &members[member];
+            trace!(
+                "Reading {} from {:?} (i = {:?}, c = {:?})",
+                variable,
+                m.path,
+                indices,
+                counts
+            );
+
+            let bytes: Vec<Bytes> = m
+                .stream_xdr(variable, vartype, db.clone(), &indices, &counts)
+                .await?
+                .try_collect()
+                .await?;
+
+            Ok(bytes.concat().into())
+        }
+        Slab::Interleave { .. } => Err(anyhow!("interleaved slabs can not be nested")),
+    }
+}
+
+/// Interleave the rows of the parts.
+fn interleave(parts: &[Bytes], rows: usize) -> Result<Bytes, anyhow::Error> {
+    ensure!(
+        rows > 0 && parts.iter().all(|p| p.len() % rows == 0),
+        "parts can not be split in {} rows",
+        rows
+    );
+
+    let mut bytes = BytesMut::with_capacity(parts.iter().map(Bytes::len).sum());
+
+    for r in 0..rows {
+        for part in parts {
+            let sz = part.len() / rows;
+            bytes.extend_from_slice(&part[r * sz..(r + 1) * sz]);
+        }
+    }
+
+    Ok(bytes.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleave_rows() {
+        let a = Bytes::from_static(&[1, 1, 2, 2]);
+        let b = Bytes::from_static(&[3, 4]);
+
+        assert_eq!(
+            interleave(&[a.clone(), b.clone()], 2).unwrap(),
+            Bytes::from_static(&[1, 1, 3, 2, 2, 4])
+        );
+        assert_eq!(
+            interleave(&[a.clone(), b.clone()], 1).unwrap(),
+            [a, b].concat()
+        );
+        assert!(interleave(&[Bytes::from_static(&[1, 2, 3])], 2).is_err());
+    }
+}
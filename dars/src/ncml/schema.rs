@@ -0,0 +1,222 @@
+//! The variables of the members.
+//!
+//! The DDS and DAS of an aggregation are built from the first member. The aggregated variables of
+//! the other members are validated against it when the dataset is loaded: a member which is
+//! missing a variable, or where it has a different type, dimensions or shape, is served with fill
+//! values for that variable. These members are logged and listed in the global attributes
+//! `aggregation_missing_variables` and `aggregation_incompatible_variables`.
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::member::NcmlMember;
+use crate::hdf5::dds as hdf5dds;
+use dap2::das::{AttrValue, Attribute};
+
+/// Type, dimensions and shape of a variable in a member.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schema {
+    pub vartype: String,
+    pub dimensions: Vec<String>,
+    pub shape: Vec<usize>,
+    /// The fill value if the variable has not been allocated in the member, the variable is
+    /// served as fill values.
+    pub unallocated: Option<f64>,
+}
+
+impl Schema {
+    /// The schema of all the variables in a file.
+    pub fn from_file(file: &hdf5::File) -> anyhow::Result<HashMap<String, Schema>> {
+        let phony = hdf5dds::phony_dimensions(file);
+
+        file.member_names()?
+            .into_iter()
+            .filter_map(|m| file.dataset(&m).ok().map(|d| (m, d)))
+            .map(|(m, d)| {
+                let vartype = hdf5dds::hdf5_vartype(&d.dtype()?);
+                let schema = Schema {
+                    vartype: format!("{:?}", vartype),
+                    dimensions: hdf5dds::hdf5_dimensions(&m, &d, &phony),
+                    shape: d.shape(),
+                    unallocated: (!crate::hdf5::is_allocated(&d))
+                        .then(|| crate::hdf5::fill_value(&d, vartype)),
+                };
+
+                Ok::<_, anyhow::Error>((m, schema))
+            })
+            .collect()
+    }
+
+    /// Position of `dimension` in the dimensions of the variable.
+    pub fn position(&self, dimension: &str) -> Option<usize> {
+        self.dimensions.iter().position(|d| d == dimension)
+    }
+
+    /// Describe why a variable in a member can not be aggregated with the same variable in the
+    /// first member (`self`), the aggregation dimension (at position `p`) must have length `n` in
+    /// the member.
+    fn incompatible(&self, other: &Schema, p: usize, n: usize) -> Option<String> {
+        let except = |shape: &[usize]| {
+            shape
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| *i != p)
+                .map(|(_, s)| *s)
+                .collect::<Vec<_>>()
+        };
+
+        if self.vartype != other.vartype {
+            Some(format!("type {} != {}", other.vartype, self.vartype))
+        } else if self.dimensions != other.dimensions {
+            Some(format!(
+                "dimensions {:?} != {:?}",
+                other.dimensions, self.dimensions
+            ))
+        } else if except(&self.shape) != except(&other.shape) || other.shape.get(p) != Some(&n) {
+            Some(format!("shape {:?} != {:?}", other.shape, self.shape))
+        } else {
+            None
+        }
+    }
+}
+
+/// Members that are missing aggregated variables, or where they are incompatible.
+#[derive(Debug, Default)]
+pub struct SchemaReport {
+    pub missing: Vec<(PathBuf, String)>,
+    pub incompatible: Vec<(PathBuf, String, String)>,
+}
+
+impl SchemaReport {
+    /// Validate the aggregated variables of the members against the first member, and mark the
+    /// variables that must be served as fill values.
+    pub fn validate(members: &mut [NcmlMember], dimension: &str) -> SchemaReport {
+        let mut report = SchemaReport::default();
+
+        let Some((first, rest)) = members.split_first_mut() else {
+            return report;
+        };
+        first.fill.clear();
+
+        let aggregated = first
+            .schema
+            .iter()
+            .filter(|(name, _)| *name != dimension)
+            .filter_map(|(name, s)| s.position(dimension).map(|p| (name, s, p)))
+            .collect::<Vec<_>>();
+
+        for m in rest {
+            m.fill.clear();
+
+            for (name, schema, p) in &aggregated {
+                match m.schema.get(*name) {
+                    None => {
+                        report.missing.push((m.path.clone(), name.to_string()));
+                        m.fill.insert(name.to_string());
+                    }
+                    Some(other) => {
+                        if let Some(reason) = schema.incompatible(other, *p, m.n) {
+                            report
+                                .incompatible
+                                .push((m.path.clone(), name.to_string(), reason));
+                            m.fill.insert(name.to_string());
+                        }
+                    }
+                }
+            }
+        }
+
+        report
+    }
+
+    pub fn log(&self, key: &str) {
+        for (path, variable) in &self.missing {
+            warn!(
+                "{}: member {:?} is missing {}, serving fill values.",
+                key, path, variable
+            );
+        }
+
+        for (path, variable, reason) in &self.incompatible {
+            warn!(
+                "{}: {} in member {:?} is incompatible ({}), serving fill values.",
+                key, variable, path, reason
+            );
+        }
+    }
+
+    pub fn attributes(&self) -> Vec<Attribute> {
+        let missing = self
+            .missing
+            .iter()
+            .map(|(path, variable)| format!("{}: {}", path.to_string_lossy(), variable))
+            .collect::<Vec<_>>();
+
+        let incompatible = self
+            .incompatible
+            .iter()
+            .map(|(path, variable, reason)| {
+                format!("{}: {} ({})", path.to_string_lossy(), variable, reason)
+            })
+            .collect::<Vec<_>>();
+
+        [
+            ("aggregation_missing_variables", missing),
+            ("aggregation_incompatible_variables", incompatible),
+        ]
+        .into_iter()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(name, v)| Attribute {
+            name: name.to_string(),
+            value: AttrValue::Str(v.join("; ")),
+        })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::test_db;
+
+    #[test]
+    fn missing_and_incompatible() {
+        let db = test_db();
+        let mut members = vec![
+            NcmlMember::open("../data/ncml/jan.nc4", "time", &db).unwrap(),
+            NcmlMember::open("../data/ncml/feb.nc4", "time", &db).unwrap(),
+        ];
+
+        let report = SchemaReport::validate(&mut members, "time");
+        assert!(report.missing.is_empty());
+        assert!(report.incompatible.is_empty());
+        assert!(report.attributes().is_empty());
+
+        let (name, schema) = members[0]
+            .schema
+            .iter()
+            .find(|(name, s)| {
+                *name != "time" && s.dimensions.first().map(String::as_str) == Some("time")
+            })
+            .map(|(name, s)| (name.clone(), s.clone()))
+            .unwrap();
+
+        members[1].schema.remove(&name);
+        let report = SchemaReport::validate(&mut members, "time");
+        assert_eq!(report.missing.len(), 1);
+        assert!(members[1].fill.contains(&name));
+
+        members[1].schema.insert(
+            name.clone(),
+            Schema {
+                vartype: "Int64".into(),
+                ..schema
+            },
+        );
+        let report = SchemaReport::validate(&mut members, "time");
+        assert_eq!(report.incompatible.len(), 1);
+        assert!(members[1].fill.contains(&name));
+        assert_eq!(report.attributes().len(), 1);
+    }
+}
@@ -0,0 +1,185 @@
+//! CF time units of the aggregation coordinate variable.
+//!
+//! Members may use different units for the same coordinate (e.g. `days since 2020-01-01` and
+//! `days since 2020-02-01`). The coordinate values are converted to the units of the first member
+//! so that the aggregated coordinate variable is consistent.
+//!
+//! Only the `standard`, `gregorian` and `proleptic_gregorian` calendars are supported when
+//! converting, and only time units of fixed length (seconds to days).
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+/// The `units` and `calendar` attributes of a coordinate variable.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Units {
+    pub units: Option<String>,
+    pub calendar: Option<String>,
+}
+
+impl Units {
+    pub fn from_dataset(ds: &hdf5::Dataset) -> Units {
+        let attr = |name: &str| {
+            ds.attr(name)
+                .ok()
+                .map(|a| crate::hdf5::das::h5attr_to_das(name, a).value)
+                .and_then(|v| match v {
+                    dap2::das::AttrValue::Str(s) => Some(s.trim().to_string()),
+                    _ => None,
+                })
+        };
+
+        Units {
+            units: attr("units"),
+            calendar: attr("calendar"),
+        }
+    }
+
+    /// Whether [Units::convert] changes values given in these units to the units `to`.
+    pub fn converts_to(&self, to: &Units) -> bool {
+        matches!((&self.units, &to.units), (Some(f), Some(t)) if f != t)
+    }
+
+    /// Convert `values` given in these units to the units `to`, in place.
+    ///
+    /// Values are left unchanged if the units are the same or if either of them are unknown.
+    pub fn convert(&self, values: &mut [f64], to: &Units) -> anyhow::Result<()> {
+        let (from_units, to_units) = match (&self.units, &to.units) {
+            (Some(f), Some(t)) if f != t => (f, t),
+            (Some(_), Some(_)) => return Ok(()),
+            (f, t) => {
+                if f != t {
+                    warn!("Missing units, not converting: {:?} to {:?}", f, t);
+                }
+                return Ok(());
+            }
+        };
+
+        for calendar in [&self.calendar, &to.calendar].into_iter().flatten() {
+            ensure!(
+                matches!(
+                    calendar.to_lowercase().as_str(),
+                    "standard" | "gregorian" | "proleptic_gregorian"
+                ),
+                "unsupported calendar for unit conversion: {}",
+                calendar
+            );
+        }
+
+        let from = TimeUnits::parse(from_units)?;
+        let to = TimeUnits::parse(to_units)?;
+        debug!("Converting from '{}' to '{}'", from_units, to_units);
+
+        let offset = (from.epoch - to.epoch).num_milliseconds() as f64 / 1000.;
+
+        for v in values.iter_mut() {
+            *v = (*v * from.seconds + offset) / to.seconds;
+        }
+
+        Ok(())
+    }
+}
+
+/// CF time units: `<unit> since <reference time>`.
+#[derive(Debug, PartialEq)]
+struct TimeUnits {
+    /// Length of unit in seconds.
+    seconds: f64,
+    epoch: NaiveDateTime,
+}
+
+impl TimeUnits {
+    fn parse(units: &str) -> anyhow::Result<TimeUnits> {
+        let (unit, epoch) = units
+            .split_once(" since ")
+            .ok_or_else(|| anyhow!("not time units: {}", units))?;
+
+        let seconds = match unit.trim().to_lowercase().as_str() {
+            "seconds" | "second" | "secs" | "sec" | "s" => 1.,
+            "minutes" | "minute" | "mins" | "min" => 60.,
+            "hours" | "hour" | "hrs" | "hr" | "h" => 60. * 60.,
+            "days" | "day" | "d" => 24. * 60. * 60.,
+            u => return Err(anyhow!("unsupported time unit: {}", u)),
+        };
+
+        let epoch = epoch
+            .trim()
+            .trim_end_matches(" UTC")
+            .trim_end_matches(" GMT")
+            .trim_end_matches('Z');
+
+        let epoch = [
+            "%Y-%m-%d %H:%M:%S%.f",
+            "%Y-%m-%dT%H:%M:%S%.f",
+            "%Y-%m-%d %H:%M",
+            "%Y-%m-%dT%H:%M",
+        ]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(epoch, f).ok())
+        .or_else(|| {
+            chrono::NaiveDate::parse_from_str(epoch, "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| anyhow!("could not parse reference time: {}", epoch))?;
+
+        Ok(TimeUnits { seconds, epoch })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn units(u: &str) -> Units {
+        Units {
+            units: Some(u.to_string()),
+            calendar: None,
+        }
+    }
+
+    #[test]
+    fn parse_units() {
+        let t = TimeUnits::parse("hour since 0000-01-01 00:00:00").unwrap();
+        assert_eq!(t.seconds, 3600.);
+
+        let t = TimeUnits::parse("seconds since 1970-01-01T00:00:00Z").unwrap();
+        assert_eq!(t.epoch.and_utc().timestamp(), 0);
+
+        assert!(TimeUnits::parse("degrees_north").is_err());
+        assert!(TimeUnits::parse("months since 2020-01-01").is_err());
+    }
+
+    #[test]
+    fn convert_days() {
+        let mut v = vec![0., 1., 27.];
+        units("days since 2020-02-01")
+            .convert(&mut v, &units("days since 2020-01-01"))
+            .unwrap();
+        assert_eq!(v, [31., 32., 58.]);
+
+        let mut v = vec![24.];
+        units("hours since 2020-01-02")
+            .convert(&mut v, &units("days since 2020-01-01"))
+            .unwrap();
+        assert_eq!(v, [2.]);
+    }
+
+    #[test]
+    fn convert_unsupported_calendar() {
+        let mut v = vec![0.];
+        let from = Units {
+            units: Some("days since 2020-02-01".into()),
+            calendar: Some("noleap".into()),
+        };
+        assert!(from
+            .convert(&mut v, &units("days since 2020-01-01"))
+            .is_err());
+
+        // Same units do not need conversion.
+        assert!(from
+            .convert(&mut v, &units("days since 2020-02-01"))
+            .is_ok());
+        assert!(!from.converts_to(&units("days since 2020-02-01")));
+        assert!(!from.converts_to(&Units::default()));
+    }
+}
@@ -0,0 +1,424 @@
+//! Unpacking of CF packed variables on the server, for clients that can not unpack them (see
+//! [crate::config::Dds::unpack]).
+//!
+//! Variables with a `scale_factor` or `add_offset` attribute are served with the type of these
+//! attributes (`Float32` or `Float64`) and the values `packed * scale_factor + add_offset`. The
+//! fill value and missing values are served as `NaN`. Packed values of variables with
+//! `_Unsigned = "true"` are read as unsigned before they are unpacked. The valid range
+//! (`valid_min`, `valid_max` and `valid_range`) is unpacked too if it is given in the packed
+//! type. The DDS and DAS of the dataset are adjusted when the dataset is opened, and the packed
+//! values are read from the files and unpacked while they are streamed (see
+//! [crate::data::DatasetType]).
+use std::collections::HashMap;
+use std::pin::Pin;
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+
+use crate::hdf5::das::h5attr_to_das;
+use crate::hdf5::dds::hdf5_vartype;
+use dap2::das::{AttrValue, Attribute};
+use dap2::dds::{DdsVariableDetails, VarType};
+use dap2::dods::xdr::{xdr_decode_f64, xdr_encode_f64};
+
+/// The packed variables of a dataset.
+#[derive(Debug, Default, Clone)]
+pub struct Packed(HashMap<String, Packing>);
+
+/// How a variable is packed.
+#[derive(Debug, Clone)]
+pub struct Packing {
+    /// Type of the packed values in the file.
+    vartype: VarType,
+    /// Type of the unpacked values.
+    unpacked: VarType,
+    scale: f64,
+    offset: f64,
+    /// Added to negative packed values of `_Unsigned` variables: 2^bits of the packed type.
+    unsigned: Option<f64>,
+    /// Packed values that are served as `NaN` (the fill value and `missing_value`).
+    missing: Vec<f64>,
+    /// Attributes of the unpacked variable.
+    attributes: Vec<Attribute>,
+}
+
+impl Packed {
+    /// The packed variables in `file`.
+    pub fn from_file(file: &hdf5::File) -> anyhow::Result<Packed> {
+        let mut packed = HashMap::new();
+
+        for m in file.member_names()? {
+            let Ok(d) = file.dataset(&m) else {
+                continue;
+            };
+
+            if let Some(packing) = Packing::from_dataset(&d)? {
+                packed.insert(m, packing);
+            }
+        }
+
+        Ok(Packed(packed))
+    }
+
+    /// Do not unpack `variable`, e.g. the aggregation dimension of NcML aggregations which is
+    /// served from the coordinate values.
+    pub fn remove(&mut self, variable: &str) {
+        self.0.remove(variable);
+    }
+
+    pub fn get(&self, variable: &str) -> Option<&Packing> {
+        self.0.get(variable)
+    }
+
+    /// Change the types of the packed variables in `dds` to the unpacked types, and replace their
+    /// attributes in `das`.
+    pub fn apply(&self, das: &mut dap2::Das, dds: &mut dap2::Dds) {
+        for (name, packing) in &self.0 {
+            dds.set_vartype(name, packing.unpacked);
+            das.set_variable(name, packing.attributes.clone());
+        }
+    }
+}
+
+impl Packing {
+    fn from_dataset(dataset: &hdf5::Dataset) -> anyhow::Result<Option<Packing>> {
+        let scale = dataset.attr("scale_factor").ok();
+        let offset = dataset.attr("add_offset").ok();
+
+        // The unpacked type is the type of the attributes.
+        let unpacked = match scale.as_ref().or(offset.as_ref()) {
+            Some(a) => match hdf5_vartype(&a.dtype()?) {
+                VarType::Float64 => VarType::Float64,
+                _ => VarType::Float32,
+            },
+            None => return Ok(None),
+        };
+
+        let vartype = hdf5_vartype(&dataset.dtype()?);
+        if matches!(
+            vartype,
+            VarType::String(_) | VarType::Structure(_) | VarType::Unimplemented
+        ) {
+            return Ok(None);
+        }
+
+        let value = |a: Option<hdf5::Attribute>| -> anyhow::Result<Option<f64>> {
+            Ok(match a {
+                Some(a) => a.read_raw::<f64>()?.first().copied(),
+                None => None,
+            })
+        };
+
+        let scale = value(scale)?.unwrap_or(1.);
+        let offset = value(offset)?.unwrap_or(0.);
+
+        // The default fill value is used when there is no `_FillValue`.
+        let mut missing = vec![crate::hdf5::fill_value(dataset, vartype)];
+        if let Ok(a) = dataset.attr("missing_value") {
+            missing.extend(a.read_raw::<f64>()?);
+        }
+
+        let unsigned = dataset
+            .attr("_Unsigned")
+            .ok()
+            .map(|a| h5attr_to_das("_Unsigned", a).value)
+            .is_some_and(|v| matches!(v, AttrValue::Str(s) if s.eq_ignore_ascii_case("true")));
+        let unsigned = match vartype {
+            _ if !unsigned => None,
+            VarType::Int16 => Some(2_f64.powi(16)),
+            VarType::Int32 => Some(2_f64.powi(32)),
+            VarType::Int64 => Some(2_f64.powi(64)),
+            _ => None,
+        };
+
+        let mut packing = Packing {
+            vartype,
+            unpacked,
+            scale,
+            offset,
+            unsigned,
+            missing,
+            attributes: Vec::new(),
+        };
+
+        let attribute = |v: Vec<f64>| match (unpacked, v.len()) {
+            (VarType::Float64, 1) => AttrValue::Double(v[0]),
+            (VarType::Float64, _) => AttrValue::Doubles(v),
+            (_, 1) => AttrValue::Float(v[0] as f32),
+            _ => AttrValue::Floats(v.iter().map(|v| *v as f32).collect()),
+        };
+
+        let dtype = dataset.dtype()?;
+
+        for name in dataset.attr_names()? {
+            let Ok(a) = dataset.attr(&name) else {
+                continue;
+            };
+
+            let value = match name.as_str() {
+                "scale_factor" | "add_offset" | "_Unsigned" => continue,
+                "_FillValue" | "missing_value" => attribute(vec![f64::NAN]),
+                // The valid range is in the packed type if it is given in it, otherwise it is
+                // already unpacked.
+                "valid_min" | "valid_max" | "valid_range" if a.dtype()? == dtype => {
+                    let mut v = a.read_raw::<f64>()?;
+                    v.iter_mut().for_each(|v| *v = packing.unpack_value(*v));
+                    v.sort_by(|a, b| a.total_cmp(b));
+                    attribute(v)
+                }
+                "valid_min" | "valid_max" | "valid_range"
+                    if !matches!(
+                        hdf5_vartype(&a.dtype()?),
+                        VarType::String(_) | VarType::Structure(_) | VarType::Unimplemented
+                    ) =>
+                {
+                    attribute(a.read_raw::<f64>()?)
+                }
+                _ => h5attr_to_das(&name, a).value,
+            };
+
+            packing.attributes.push(Attribute { name, value });
+        }
+
+        Ok(Some(packing))
+    }
+
+    /// The variable to read from the files: the same slab with the packed type.
+    pub fn source(&self, variable: &DdsVariableDetails) -> DdsVariableDetails {
+        let mut variable = variable.clone();
+        variable.vartype = self.vartype;
+        variable
+    }
+
+    /// Unpack a packed value, which is not missing.
+    fn unpack_value(&self, packed: f64) -> f64 {
+        let packed = match self.unsigned {
+            Some(modulus) if packed < 0. => packed + modulus,
+            _ => packed,
+        };
+
+        packed * self.scale + self.offset
+    }
+
+    fn unpack(&self, packed: &[f64]) -> Vec<f64> {
+        packed
+            .iter()
+            .map(|v| {
+                if self.missing.contains(v) {
+                    f64::NAN
+                } else {
+                    self.unpack_value(*v)
+                }
+            })
+            .collect()
+    }
+
+    /// Unpack the XDR encoded packed values of `stream`, read from the [Packing::source].
+    pub fn stream_xdr(
+        &self,
+        stream: Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>>,
+    ) -> Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + 'static>> {
+        let packing = self.clone();
+        let size = self.vartype.xdr_size();
+
+        // Values may be split between the chunks of the stream.
+        let mut rest: Vec<u8> = Vec::new();
+
+        stream
+            .map(move |b| {
+                rest.extend_from_slice(&b?);
+                let n = rest.len() - rest.len() % size;
+
+                let values = xdr_decode_f64(packing.vartype, &rest[..n])?;
+                rest.drain(..n);
+
+                Ok(Bytes::from(xdr_encode_f64(
+                    packing.unpacked,
+                    &packing.unpack(&values),
+                )?))
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unpack_dataset() {
+        use crate::data::{test_db, DatasetType};
+        use crate::hdf5::Hdf5Dataset;
+        use dap2::dds::ConstrainedVariable;
+        use dap2::{Dap2, DodsXdr};
+        use futures::TryStreamExt;
+
+        let db = test_db();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("packed.h5");
+
+        {
+            let f = hdf5::File::create(&path).unwrap();
+            let d = f.new_dataset::<i16>().shape(4).create("packed").unwrap();
+            d.write_raw(&[0_i16, 2, -1, 4]).unwrap();
+
+            d.new_attr::<f32>()
+                .create("scale_factor")
+                .unwrap()
+                .write_scalar(&0.5_f32)
+                .unwrap();
+            d.new_attr::<f32>()
+                .create("add_offset")
+                .unwrap()
+                .write_scalar(&10_f32)
+                .unwrap();
+            d.new_attr::<i16>()
+                .create("_FillValue")
+                .unwrap()
+                .write_scalar(&-1_i16)
+                .unwrap();
+        }
+
+        let hd = Hdf5Dataset::open(&path, "packed".into(), &db)
+            .unwrap()
+            .with_unpack(true)
+            .unwrap();
+
+        assert!(hd.dds.all().to_string().contains("Float32 packed["));
+
+        let das = hd.das.to_string();
+        assert!(!das.contains("scale_factor"));
+        assert!(!das.contains("add_offset"));
+        assert!(das.contains("Float32 _FillValue NaN;"));
+
+        let hd = DatasetType::HDF5(hd);
+        let c = dap2::Constraint::parse("packed").unwrap();
+        let packed = match hd.dds().await.dds(&c).unwrap().variables.remove(0) {
+            ConstrainedVariable::Variable(v) => v,
+            _ => panic!("expected variable"),
+        };
+
+        let bytes: Vec<Bytes> = hd
+            .variable_xdr(&packed)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let values = xdr_decode_f64(VarType::Float32, &bytes.concat()).unwrap();
+
+        assert_eq!(values[..2], [10., 11.]);
+        assert!(values[2].is_nan());
+        assert_eq!(values[3], 12.);
+    }
+
+    #[test]
+    fn default_fill_value() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("packed.h5");
+
+        let f = hdf5::File::create(&path).unwrap();
+        let d = f.new_dataset::<i16>().shape(3).create("packed").unwrap();
+        d.write_raw(&[0_i16, -32767, 2]).unwrap();
+        d.new_attr::<f32>()
+            .create("scale_factor")
+            .unwrap()
+            .write_scalar(&0.5_f32)
+            .unwrap();
+
+        // Without `_FillValue` the netCDF default is missing, not the HDF5 default of 0.
+        let packed = Packed::from_file(&f).unwrap();
+        let packing = packed.get("packed").unwrap();
+        assert_eq!(packing.missing, [-32767.]);
+
+        let values = packing.unpack(&[0., -32767., 2.]);
+        assert_eq!(values[0], 0.);
+        assert!(values[1].is_nan());
+        assert_eq!(values[2], 1.);
+    }
+
+    #[test]
+    fn unsigned_and_valid_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("packed.h5");
+
+        let f = hdf5::File::create(&path).unwrap();
+        let d = f.new_dataset::<i16>().shape(3).create("packed").unwrap();
+        d.write_raw(&[0_i16, -1, 2]).unwrap();
+        d.new_attr::<f32>()
+            .create("scale_factor")
+            .unwrap()
+            .write_scalar(&0.5_f32)
+            .unwrap();
+        d.new_attr::<hdf5::types::FixedAscii<4>>()
+            .create("_Unsigned")
+            .unwrap()
+            .write_scalar(&hdf5::types::FixedAscii::<4>::from_ascii("true").unwrap())
+            .unwrap();
+        d.new_attr::<i16>()
+            .shape(2)
+            .create("valid_range")
+            .unwrap()
+            .write_raw(&[0_i16, -2])
+            .unwrap();
+        d.new_attr::<f32>()
+            .create("valid_max")
+            .unwrap()
+            .write_scalar(&100_f32)
+            .unwrap();
+
+        let packed = Packed::from_file(&f).unwrap();
+        let packing = packed.get("packed").unwrap();
+
+        let values = packing.unpack(&[0., -1., 2.]);
+        assert_eq!(values, [0., 32767.5, 1.]);
+
+        let attribute = |name: &str| {
+            packing
+                .attributes
+                .iter()
+                .find(|a| a.name == name)
+                .map(|a| a.to_string())
+        };
+
+        // The valid range in the packed type is unpacked, the one in the unpacked type is kept.
+        assert_eq!(
+            attribute("valid_range").unwrap(),
+            "Float32 valid_range +0.0E0, +3.3E4;"
+        );
+        assert_eq!(attribute("valid_max").unwrap(), "Float32 valid_max +1.0E2;");
+        assert!(attribute("_Unsigned").is_none());
+    }
+
+    #[tokio::test]
+    async fn unpack_stream() {
+        use futures::TryStreamExt;
+
+        let packing = Packing {
+            vartype: VarType::Int16,
+            unpacked: VarType::Float32,
+            scale: 0.5,
+            offset: 10.,
+            unsigned: None,
+            missing: vec![-1.],
+            attributes: Vec::new(),
+        };
+
+        let packed = xdr_encode_f64(VarType::Int16, &[0., 2., -1., 4.]).unwrap();
+
+        // Split in the middle of a value.
+        let stream = futures::stream::iter([
+            Ok(Bytes::copy_from_slice(&packed[..6])),
+            Ok(Bytes::copy_from_slice(&packed[6..])),
+        ])
+        .boxed();
+
+        let bytes: Vec<Bytes> = packing.stream_xdr(stream).try_collect().await.unwrap();
+        let values = xdr_decode_f64(VarType::Float32, &bytes.concat()).unwrap();
+
+        assert_eq!(values[..2], [10., 11.]);
+        assert!(values[2].is_nan());
+        assert_eq!(values[3], 12.);
+    }
+}
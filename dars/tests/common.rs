@@ -1,6 +1,5 @@
 use dars::{config, data};
 use std::convert::TryInto;
-use std::sync::Arc;
 use warp::Filter;
 
 pub const TDS_UNI: &'static str = "https://remotetest.unidata.ucar.edu/thredds/dodsC/testdods/";
@@ -19,17 +18,15 @@ pub fn test_log() {
 pub async fn dars_test() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
 {
     let config = config::Config::default();
-    let db = sled::Config::default().temporary(true).open().unwrap();
+    let db = dars::db::Db::temporary();
     let test_data = match std::env::var("CARGO_MANIFEST_DIR") {
         Ok(path) => std::path::Path::new(&path).join("..").join("data"),
         Err(_) => std::path::Path::new("../data").to_owned(),
     };
     let root_url = config.root_url.clone();
-    let data = Arc::new(
-        data::Datasets::new_with_datadir(root_url, test_data, db)
-            .await
-            .unwrap(),
-    );
+    let data = data::Datasets::new_with_datadir(root_url, test_data, db, &config.ncml)
+        .await
+        .unwrap();
     data::filters::datasets(data.clone()).with(warp::log::custom(data::request_log))
 }
 